@@ -2,7 +2,7 @@ use crate::{project::Project, tree::TreeBehavior};
 use egui::{Button, Visuals};
 use enum2pos::EnumIndex;
 use enum2str::EnumStr;
-use rpc::{Command, RpcResult};
+use rpc::{Command, Compression, Credential, RpcResult};
 use serde::{Deserialize, Serialize};
 use std::collections::HashSet;
 use ui::contract::{
@@ -36,9 +36,15 @@ pub struct App {
     last_result: Option<RpcResult>,
     theme: Theme,
     connection_strategy: BackendConnectionStrategy,
+    scheme: WebsocketScheme,
+    compress: bool,
 
     recents: HashSet<RecentEntry>,
 
+    // Credentials are secrets, so they are not persisted with the rest of the app state
+    #[serde(skip)]
+    credential: String,
+
     #[serde(skip)]
     selected_tab: usize,
 
@@ -50,17 +56,72 @@ pub struct App {
 
     #[serde(skip)]
     show_side_panel: bool,
+
+    // The backend that served the page, connected to on the first frame. It is never
+    // saved, so a page served by another backend doesn't try to reach this one.
+    #[serde(skip)]
+    origin_backend: Option<String>,
+
+    // Browsers can't send UDP, so the wasm build only takes backends entered by hand
+    #[cfg(not(target_arch = "wasm32"))]
+    #[serde(skip)]
+    discovery: crate::discovery::Discovery,
 }
 
 impl App {
     const URL_BAR_WIDTH: f32 = 100.0;
 
+    #[cfg(not(target_arch = "wasm32"))]
+    const DISCOVERY_POLL_INTERVAL: std::time::Duration = std::time::Duration::from_millis(250);
+
     pub fn new(cc: &eframe::CreationContext<'_>) -> Self {
-        if let Some(storage) = cc.storage {
-            return eframe::get_value(storage, eframe::APP_KEY).unwrap_or_default();
+        let app: Self = cc
+            .storage
+            .and_then(|storage| eframe::get_value(storage, eframe::APP_KEY))
+            .unwrap_or_default();
+
+        #[cfg(target_arch = "wasm32")]
+        let app = app.with_origin_backend(&cc.integration_info.web_info.location);
+
+        app
+    }
+
+    /// A page served by the backend's `--web` mode is marked with the path the backend
+    /// listens on, which is connected to unless another backend was entered before
+    #[cfg(target_arch = "wasm32")]
+    fn with_origin_backend(mut self, location: &eframe::Location) -> Self {
+        let Some(path) = backend_marker() else {
+            return self;
+        };
+        if !self.url.is_empty() {
+            return self;
         }
+        let scheme = if location.protocol == "https:" {
+            WebsocketScheme::Wss
+        } else {
+            WebsocketScheme::Ws
+        };
+        self.origin_backend = Some(format!("{scheme}{}{path}", location.host));
+        self
+    }
 
-        Default::default()
+    fn connect(&mut self, context: &egui::Context) {
+        let url = format!("{}{}", self.scheme, &self.url);
+        self.connect_to(&url, context);
+    }
+
+    fn connect_to(&mut self, url: &str, context: &egui::Context) {
+        let context = context.clone();
+        let wakeup = move || context.request_repaint(); // wake up UI thread on new message
+        let compression = if self.compress {
+            Compression::Lz4
+        } else {
+            Compression::None
+        };
+        let rpc = &mut self.project.behavior.rpc;
+        rpc.set_compression(compression);
+        rpc.set_credential(Credential::from_text(&self.credential));
+        rpc.connect(url, wakeup);
     }
 
     pub fn rpc(&self) -> &crate::rpc::Rpc {
@@ -126,6 +187,9 @@ impl App {
 
             self.backend_strategy_ui(ui, context);
 
+            #[cfg(not(target_arch = "wasm32"))]
+            self.discovered_backends_ui(ui, context);
+
             let label = if self.project.behavior.rpc.has_connected() {
                 "Backend Available ✅"
             } else {
@@ -149,6 +213,14 @@ impl App {
         ui.horizontal(|ui| {
             ui.horizontal(|ui| {
                 ui.label("URL:");
+                egui::ComboBox::from_id_source("websocket_scheme")
+                    .selected_text(self.scheme.to_string())
+                    .width(60.0)
+                    .show_ui(ui, |ui| {
+                        ui.selectable_value(&mut self.scheme, WebsocketScheme::Ws, "ws://");
+                        ui.selectable_value(&mut self.scheme, WebsocketScheme::Wss, "wss://")
+                            .on_hover_text("Connect with TLS");
+                    });
                 egui::TextEdit::singleline(&mut self.url)
                     .desired_width(Self::URL_BAR_WIDTH)
                     .show(ui);
@@ -158,24 +230,72 @@ impl App {
                 );
                 let enter_key_pressed = ui.input(|i| i.key_pressed(egui::Key::Enter));
 
+                ui.checkbox(&mut self.compress, "Compress")
+                    .on_hover_text("Compress large messages if the backend supports it");
+
                 if enter_key_pressed || connect_button.clicked() {
-                    let context = context.clone();
-                    let wakeup = move || context.request_repaint(); // wake up UI thread on new message
-                    self.project
-                        .behavior
-                        .rpc
-                        .connect(&format!("ws://{}", &self.url), wakeup);
+                    self.connect(context);
                 }
             });
 
-            let connected = self.project.behavior.rpc.has_connected();
-            let status = if connected {
-                "Connected"
-            } else {
-                "Not Connected"
+            let rpc = self.rpc();
+            let status = match (rpc.has_connected(), rpc.is_responsive(), rpc.latency()) {
+                (false, ..) => "Not Connected".to_string(),
+                (true, false, _) => "Not Responding".to_string(),
+                (true, true, Some(latency)) => format!("Connected ({} ms)", latency.as_millis()),
+                (true, true, None) => "Connected".to_string(),
             };
             ui.label(status);
         });
+
+        ui.horizontal(|ui| {
+            ui.label("Credential:");
+            egui::TextEdit::singleline(&mut self.credential)
+                .password(true)
+                .hint_text("Token or signed credential")
+                .show(ui);
+        });
+
+        if let Some(error) = self.rpc().authentication_error() {
+            ui.colored_label(ui.visuals().error_fg_color, error.to_string());
+        }
+    }
+
+    #[cfg(not(target_arch = "wasm32"))]
+    fn discovered_backends_ui(&mut self, ui: &mut egui::Ui, context: &egui::Context) {
+        self.discovery.poll();
+
+        ui.horizontal(|ui| {
+            ui.label("On this network:");
+            if ui.button("Scan").clicked() {
+                self.discovery.scan();
+            }
+        });
+
+        let mut selected = None;
+        for backend in self.discovery.backends() {
+            let label = format!("{} ({}) {}", backend.name, backend.version, backend.address);
+            if ui.button(label).clicked() {
+                selected = Some(backend.clone());
+            }
+        }
+        if self.discovery.backends().is_empty() {
+            ui.label("No backends found");
+        }
+
+        if let Some(backend) = selected {
+            self.scheme = if backend.tls {
+                WebsocketScheme::Wss
+            } else {
+                WebsocketScheme::Ws
+            };
+            self.url = backend.address.to_string();
+            self.connection_strategy = BackendConnectionStrategy::Remote;
+            self.connect(context);
+        }
+
+        // Answers arrive without input, so they are checked for while the window is open
+        context.request_repaint_after(Self::DISCOVERY_POLL_INTERVAL);
     }
 
     fn editor_tab_ui(
@@ -302,6 +422,12 @@ impl App {
 
     fn toggle_connection_window(&mut self) {
         self.show_connection_window = !self.show_connection_window;
+
+        // Backends are looked for each time the window opens
+        #[cfg(not(target_arch = "wasm32"))]
+        if self.show_connection_window {
+            self.discovery.scan();
+        }
     }
 
     #[cfg(not(target_arch = "wasm32"))]
@@ -356,6 +482,25 @@ impl eframe::App for App {
     }
 
     fn update(&mut self, context: &egui::Context, frame: &mut eframe::Frame) {
+        // The internal backend runs on its own thread, waking the UI thread when it answers
+        #[cfg(not(target_arch = "wasm32"))]
+        if self.connection_strategy == BackendConnectionStrategy::Internal
+            && !self.rpc_mut().client_available()
+        {
+            let context = context.clone();
+            self.rpc_mut()
+                .connect_internal(move || context.request_repaint());
+        }
+
+        if let Some(url) = self.origin_backend.take() {
+            self.connect_to(&url, context);
+        }
+
+        // Heartbeats are sent from the update loop, so it has to run even without input
+        if self.rpc().has_connected() {
+            context.request_repaint_after(self.rpc().heartbeat_interval());
+        }
+
         egui::TopBottomPanel::top("server").show(context, |ui| {
             self.menu_bar_ui(ui, frame, context);
         });
@@ -458,6 +603,31 @@ impl Default for BackendConnectionStrategy {
     }
 }
 
+/// Names the `<meta>` tag the `--web` server adds to the page it serves,
+/// holding the path its websocket backend listens on
+pub(crate) const BACKEND_MARKER: &str = "editor-backend";
+
+// Pages served any other way, such as by `trunk serve`, have no backend to connect to
+#[cfg(target_arch = "wasm32")]
+fn backend_marker() -> Option<String> {
+    web_sys::window()?
+        .document()?
+        .query_selector(&format!("meta[name={BACKEND_MARKER}]"))
+        .ok()??
+        .get_attribute("content")
+}
+
+#[derive(Serialize, Deserialize, Default, PartialEq, Eq, Copy, Clone, EnumStr)]
+pub enum WebsocketScheme {
+    #[default]
+    #[enum2str("ws://")]
+    Ws,
+
+    /// Requires the backend to be started with TLS enabled
+    #[enum2str("wss://")]
+    Wss,
+}
+
 #[derive(Default, EnumStr)]
 pub enum FileSystemTag {
     #[default]
@@ -0,0 +1,236 @@
+//! Finding backends on the local network. A frontend broadcasts a probe to
+//! `DISCOVERY_PORT`, and every discoverable server there answers it with an
+//! `Announcement` of where clients reach it.
+
+use serde::{Deserialize, Serialize};
+use std::{
+    io::ErrorKind,
+    net::{Ipv4Addr, SocketAddr, UdpSocket},
+};
+
+/// The UDP port discoverable servers listen for probes on
+pub const DISCOVERY_PORT: u16 = 9003;
+
+/// What frontends send to find servers, so stray datagrams go unanswered
+pub const PROBE: &[u8] = b"editor-discovery-probe";
+
+/// Announcements are a few hundred bytes, larger datagrams aren't ours
+const MAX_DATAGRAM_SIZE: usize = 1024;
+
+/// How a server describes itself to frontends looking for one
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Announcement {
+    pub name: String,
+    pub version: String,
+
+    /// The websocket port, on the address the announcement came from
+    pub port: u16,
+
+    /// Whether clients must connect with `wss://`
+    pub tls: bool,
+}
+
+/// A server that answered a probe
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Backend {
+    pub name: String,
+    pub version: String,
+    pub address: SocketAddr,
+    pub tls: bool,
+}
+
+/// Looks for servers on the local network without blocking, so it can be polled every frame
+#[derive(Default)]
+pub struct Discovery {
+    // Bound on the first scan
+    socket: Option<UdpSocket>,
+    backends: Vec<Backend>,
+}
+
+impl Discovery {
+    /// Forgets the servers found so far and probes for them again.
+    /// Servers on this machine are probed directly, as broadcasts may not loop back.
+    pub fn scan(&mut self) {
+        self.backends.clear();
+        if self.socket.is_none() {
+            match bind() {
+                Ok(socket) => self.socket = Some(socket),
+                Err(error) => {
+                    log::error!("Failed to open a socket to discover backends: {error}");
+                    return;
+                }
+            }
+        }
+
+        let Some(socket) = &self.socket else {
+            return;
+        };
+        for ip in [Ipv4Addr::BROADCAST, Ipv4Addr::LOCALHOST] {
+            if let Err(error) = socket.send_to(PROBE, (ip, DISCOVERY_PORT)) {
+                log::warn!("Failed to probe {ip} for backends: {error}");
+            }
+        }
+    }
+
+    /// Collects the answers received since the last poll
+    pub fn poll(&mut self) {
+        let Some(socket) = &self.socket else {
+            return;
+        };
+
+        let mut buffer = [0; MAX_DATAGRAM_SIZE];
+        loop {
+            let (length, source) = match socket.recv_from(&mut buffer) {
+                Ok(received) => received,
+                Err(error) if error.kind() == ErrorKind::WouldBlock => break,
+                Err(error) => {
+                    log::warn!("Failed to receive a discovery answer: {error}");
+                    break;
+                }
+            };
+            let Ok(announcement) = serde_json::from_slice::<Announcement>(&buffer[..length]) else {
+                continue;
+            };
+
+            let backend = Backend {
+                name: announcement.name,
+                version: announcement.version,
+                address: SocketAddr::new(source.ip(), announcement.port),
+                tls: announcement.tls,
+            };
+            add(&mut self.backends, backend);
+        }
+    }
+
+    /// Every server that has answered since the last scan
+    pub fn backends(&self) -> &[Backend] {
+        &self.backends
+    }
+}
+
+// Servers are told apart by their address, except that a server on this machine answers the
+// direct probe from the loopback address and the broadcast from its address on the network.
+// The latter is kept, as it is also where other machines reach the server.
+fn add(backends: &mut Vec<Backend>, backend: Backend) {
+    let same_server = |known: &Backend| {
+        known.name == backend.name && known.address.port() == backend.address.port()
+    };
+    if backend.address.ip().is_loopback() {
+        if !backends.iter().any(same_server) {
+            backends.push(backend);
+        }
+        return;
+    }
+
+    if backends
+        .iter()
+        .any(|known| known.address == backend.address)
+    {
+        return;
+    }
+    match backends
+        .iter_mut()
+        .find(|known| known.address.ip().is_loopback() && same_server(known))
+    {
+        Some(known) => *known = backend,
+        None => backends.push(backend),
+    }
+}
+
+fn bind() -> std::io::Result<UdpSocket> {
+    let socket = UdpSocket::bind((Ipv4Addr::UNSPECIFIED, 0))?;
+    socket.set_broadcast(true)?;
+    socket.set_nonblocking(true)?;
+    Ok(socket)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{add, bind, Announcement, Backend, Discovery};
+    use std::{
+        net::{Ipv4Addr, SocketAddr, UdpSocket},
+        time::{Duration, Instant},
+    };
+
+    fn backend(name: &str, address: &str) -> Backend {
+        Backend {
+            name: name.to_string(),
+            version: "1.0.0".to_string(),
+            address: address.parse().unwrap(),
+            tls: false,
+        }
+    }
+
+    #[test]
+    fn test_add() {
+        let mut backends = Vec::new();
+        add(&mut backends, backend("editor", "192.168.1.2:9000"));
+
+        // The same server answering the direct probe
+        add(&mut backends, backend("editor", "127.0.0.1:9000"));
+        assert_eq!(backends, [backend("editor", "192.168.1.2:9000")]);
+
+        // Machines may well share a name and a port
+        add(&mut backends, backend("editor", "192.168.1.3:9000"));
+        add(&mut backends, backend("editor", "192.168.1.3:9000"));
+        assert_eq!(
+            backends,
+            [
+                backend("editor", "192.168.1.2:9000"),
+                backend("editor", "192.168.1.3:9000")
+            ]
+        );
+    }
+
+    #[test]
+    fn test_add_loopback_first() {
+        let mut backends = vec![backend("editor", "127.0.0.1:9000")];
+        add(&mut backends, backend("other", "127.0.0.1:9001"));
+        add(&mut backends, backend("editor", "192.168.1.2:9000"));
+        assert_eq!(
+            backends,
+            [
+                backend("editor", "192.168.1.2:9000"),
+                backend("other", "127.0.0.1:9001")
+            ]
+        );
+    }
+
+    #[test]
+    fn test_poll() {
+        let socket = bind().unwrap();
+        let port = socket.local_addr().unwrap().port();
+        let mut discovery = Discovery {
+            socket: Some(socket),
+            backends: Vec::new(),
+        };
+
+        let server = UdpSocket::bind((Ipv4Addr::LOCALHOST, 0)).unwrap();
+        let announcement = Announcement {
+            name: "editor".to_string(),
+            version: "1.0.0".to_string(),
+            port: 9000,
+            tls: true,
+        };
+        let target = (Ipv4Addr::LOCALHOST, port);
+        server.send_to(b"not an announcement", target).unwrap();
+        server
+            .send_to(&serde_json::to_vec(&announcement).unwrap(), target)
+            .unwrap();
+
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while discovery.backends().is_empty() && Instant::now() < deadline {
+            discovery.poll();
+            std::thread::sleep(Duration::from_millis(10));
+        }
+        assert_eq!(
+            discovery.backends(),
+            [Backend {
+                name: "editor".to_string(),
+                version: "1.0.0".to_string(),
+                address: SocketAddr::new(Ipv4Addr::LOCALHOST.into(), 9000),
+                tls: true,
+            }]
+        );
+    }
+}
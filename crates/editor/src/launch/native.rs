@@ -1,25 +1,127 @@
 mod cli;
-mod server;
+mod config;
+pub mod server;
 
 #[cfg(feature = "bundled")]
 mod bundle;
 
-use self::cli::{Command, Options};
+use self::{
+    cli::{Command, Options},
+    config::Config,
+};
+use std::fmt::Display;
 use structopt::StructOpt;
 
 pub async fn launch() -> Result<(), eframe::Error> {
-    env_logger::init();
-    let Options { command } = Options::from_args();
-    start_editor(command).await
+    let Options {
+        config,
+        log_level,
+        command,
+    } = Options::from_args();
+
+    // Only the subcommands with settings read the config, so a broken one can't stop the others
+    let config = if reads_config(&command) {
+        Config::load(config.as_deref()).map(Some)
+    } else {
+        Ok(None)
+    };
+    let log_level = match &config {
+        Ok(Some(config)) => log_level.or_else(|| config.logging.level.clone()),
+        _ => log_level,
+    };
+    // RUST_LOG still takes precedence over both
+    env_logger::Builder::from_env(
+        env_logger::Env::default().default_filter_or(log_level.as_deref().unwrap_or("error")),
+    )
+    .init();
+
+    match config {
+        Ok(config) => start_editor(command, config.unwrap_or_default()).await,
+        Err(error) => fail(error),
+    }
 }
 
-async fn start_editor(command: Command) -> Result<(), eframe::Error> {
+fn reads_config(command: &Command) -> bool {
     match command {
-        Command::Server { port } => server::listen(port).await,
+        Command::Server(_) | Command::Audit { .. } => true,
+        #[cfg(feature = "bundled")]
+        Command::Browser(_) => true,
+        Command::Desktop | Command::Schema { .. } | Command::Credential { .. } => false,
+    }
+}
+
+// Scripts and service managers can only tell a subcommand failed by its exit status
+fn fail(error: impl Display) -> ! {
+    log::error!("{error}");
+    std::process::exit(1)
+}
+
+async fn start_editor(command: Command, config: Config) -> Result<(), eframe::Error> {
+    match command {
+        Command::Server(args) => {
+            let server = match args.or(config.server).resolve() {
+                Ok(server) => server,
+                Err(error) => fail(error),
+            };
+            match server.start().await {
+                Ok(handle) => {
+                    shutdown_signal().await;
+                    log::info!("Shutting down, waiting for running commands to finish");
+                    handle.shutdown().await;
+                }
+                Err(error) => fail(error),
+            }
+        }
+        Command::Credential {
+            signing_key,
+            subject,
+            valid_for,
+        } => {
+            let expires_at = rpc::unix_time() + valid_for;
+            let credential = rpc::sign_credential(&signing_key, &subject, expires_at);
+            println!("{}", credential.to_text());
+        }
+        Command::Schema { output } => {
+            let schema = crate::schema::contract_schema();
+            match output {
+                Some(path) => {
+                    if let Err(error) = std::fs::write(&path, schema) {
+                        log::error!("Failed to write the schema to {}: {error}", path.display());
+                    }
+                }
+                None => println!("{schema}"),
+            }
+        }
+        Command::Audit {
+            file,
+            since,
+            until,
+            client,
+        } => {
+            let Some(path) = file.or(config.server.audit_log) else {
+                fail("No audit log given, pass --file or set the server's audit_log");
+            };
+            let records = match server::read_audit_log(&path, since, until, client.as_deref()) {
+                Ok(records) => records,
+                Err(error) => fail(format!(
+                    "Failed to read the audit log {}: {error}",
+                    path.display()
+                )),
+            };
+            for record in records {
+                match serde_json::to_string(&record) {
+                    Ok(line) => println!("{line}"),
+                    Err(error) => log::error!("Failed to print an audit record: {error}"),
+                }
+            }
+        }
         Command::Desktop => return render_native_ui(),
 
         #[cfg(feature = "bundled")]
-        Command::Browser { address, port } => bundle::launch_browser_ui(&address, port).await,
+        Command::Browser(args) => match args.or(config.browser).resolve() {
+            Ok(address) => bundle::launch_browser_ui(address).await,
+            Err(error) => fail(error),
+        },
     };
     Ok(())
 }
@@ -32,3 +134,34 @@ fn render_native_ui() -> Result<(), eframe::Error> {
         Box::new(|cc| Box::new(crate::app::App::new(cc))),
     )
 }
+
+/// Resolves on Ctrl+C, or on SIGTERM where there is one
+async fn shutdown_signal() {
+    let interrupt = async {
+        if let Err(error) = tokio::signal::ctrl_c().await {
+            log::error!("Failed to listen for Ctrl+C: {error}");
+            std::future::pending::<()>().await;
+        }
+    };
+
+    #[cfg(unix)]
+    let terminate = async {
+        use tokio::signal::unix::{signal, SignalKind};
+        match signal(SignalKind::terminate()) {
+            Ok(mut terminate) => {
+                terminate.recv().await;
+            }
+            Err(error) => {
+                log::error!("Failed to listen for SIGTERM: {error}");
+                std::future::pending::<()>().await;
+            }
+        }
+    };
+    #[cfg(not(unix))]
+    let terminate = std::future::pending::<()>();
+
+    tokio::select! {
+        _ = interrupt => {}
+        _ = terminate => {}
+    }
+}
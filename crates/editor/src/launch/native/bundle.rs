@@ -1,18 +1,13 @@
-use std::net::SocketAddr;
+use std::{
+    borrow::Cow,
+    net::{Ipv4Addr, Ipv6Addr, SocketAddr},
+};
 use warp::{
     http::{Response, StatusCode},
     Filter,
 };
 
-pub(crate) async fn launch_browser_ui(address: &str, port: u16) {
-    let address = if address == "localhost" {
-        "127.0.0.1"
-    } else {
-        address
-    }
-    .to_string();
-    let socket_address = SocketAddr::new(address.parse().expect("Invalid IP address"), port);
-
+pub(crate) async fn launch_browser_ui(socket_address: SocketAddr) {
     let root_route =
         warp::path::end().and_then(|| async { serve_asset("index.html".to_string()).await });
 
@@ -22,7 +17,15 @@ pub(crate) async fn launch_browser_ui(address: &str, port: u16) {
 
     let routes = root_route.or(assets_route);
 
-    if let Err(error) = webbrowser::open(&format!("http://{address}:{port}#dev")) {
+    // A browser can't open an unspecified address, but the same port on loopback reaches it
+    let mut browser_address = socket_address;
+    if browser_address.ip().is_unspecified() {
+        browser_address.set_ip(match browser_address {
+            SocketAddr::V4(_) => Ipv4Addr::LOCALHOST.into(),
+            SocketAddr::V6(_) => Ipv6Addr::LOCALHOST.into(),
+        });
+    }
+    if let Err(error) = webbrowser::open(&format!("http://{browser_address}#dev")) {
         log::error!("Failed to open browser: {error}");
     }
     warp::serve(routes).run(socket_address).await;
@@ -33,6 +36,11 @@ pub(crate) async fn launch_browser_ui(address: &str, port: u16) {
 #[folder = "dist/"]
 struct Site;
 
+/// The embedded file at `path`, for serving the frontend next to the backend
+pub(crate) fn asset(path: &str) -> Option<Cow<'static, [u8]>> {
+    Site::get(path).map(|file| file.data)
+}
+
 async fn serve_asset(path: String) -> Result<impl warp::Reply, warp::Rejection> {
     let asset = Site::get(&path).or_else(|| Site::get("index.html"));
 
@@ -1,8 +1,26 @@
+use serde::Deserialize;
+use std::path::PathBuf;
 use structopt::StructOpt;
 
 #[derive(Debug, StructOpt)]
 #[structopt(name = "My app name", about = "🛠 my app description 🛠")]
 pub struct Options {
+    /// A TOML file with settings for the `server` and `browser` subcommands
+    #[structopt(
+        long,
+        global = true,
+        about = "A TOML file with [server], [browser] and [logging] settings. Flags take precedence, then EDITOR_* environment variables"
+    )]
+    pub config: Option<PathBuf>,
+
+    /// How much to log
+    #[structopt(
+        long,
+        global = true,
+        about = "The least severe messages to log: error, warn, info, debug or trace. RUST_LOG takes precedence [default: error]"
+    )]
+    pub log_level: Option<String>,
+
     #[structopt(subcommand)]
     pub command: Command,
 }
@@ -16,36 +34,248 @@ pub enum Command {
     /// Launches the application in a web browser.
     #[cfg(feature = "bundled")]
     #[structopt(about = "Launch a browser client")]
-    Browser {
-        /// The address to serve
+    Browser(BrowserArgs),
+
+    /// Starts the server to allow remote client connections.
+    #[structopt(about = "Launch a server to accept connections from remote clients")]
+    Server(ServerArgs),
+
+    /// Prints the JSON Schema of the RPC and widget message contracts.
+    #[structopt(about = "Export the JSON Schema of the RPC and widget message contracts")]
+    Schema {
+        /// Where to write the schema instead of stdout
         #[structopt(
             short,
             long,
-            default_value = "localhost",
-            about = "The address to serve"
+            about = "The file to write the schema to, instead of printing it"
         )]
-        address: String,
+        output: Option<PathBuf>,
+    },
+
+    /// Creates a signed credential for connecting to a server started with `--signing-key`.
+    #[structopt(about = "Create a signed credential for authenticating with a server")]
+    Credential {
+        /// The key the server verifies credentials with
+        #[structopt(long, about = "The server's signing key")]
+        signing_key: String,
+
+        /// Who the credential identifies
+        #[structopt(long, about = "The identity the credential grants")]
+        subject: String,
 
-        /// The port for the server to listen on
+        /// How long the credential is valid for
         #[structopt(
-            short,
             long,
-            default_value = "9002",
-            about = "The port the server will listen on"
+            default_value = "86400",
+            about = "How many seconds the credential is valid for"
         )]
-        port: u16,
+        valid_for: u64,
     },
 
-    /// Starts the server to allow remote client connections.
-    #[structopt(about = "Launch a server to accept connections from remote clients")]
-    Server {
-        /// The port for the server to listen on
+    /// Prints the commands a server recorded in its audit log, as JSON lines.
+    #[structopt(about = "Query a server's audit log by time range and client")]
+    Audit {
+        /// The log to read, including the logs rotated out of it
+        #[structopt(
+            long,
+            about = "The audit log to read [default: the server's audit_log setting]"
+        )]
+        file: Option<PathBuf>,
+
+        /// The earliest time to include
         #[structopt(
-            short,
             long,
-            default_value = "9000",
-            about = "The port the server will listen on"
+            about = "Only commands run at or after this unix time, in seconds"
         )]
-        port: u16,
+        since: Option<u64>,
+
+        /// The time to stop at
+        #[structopt(long, about = "Only commands run before this unix time, in seconds")]
+        until: Option<u64>,
+
+        /// The client to include
+        #[structopt(long, about = "Only commands sent by the client with this id")]
+        client: Option<String>,
     },
 }
+
+/// Settings for the `browser` subcommand. Unset ones fall back to the environment,
+/// then to the config file, then to their defaults.
+#[derive(Debug, Default, Clone, StructOpt, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct BrowserArgs {
+    /// The address to serve
+    #[structopt(short, long, about = "The address to serve [default: localhost]")]
+    pub address: Option<String>,
+
+    /// The port for the server to listen on
+    #[structopt(
+        short,
+        long,
+        about = "The port the server will listen on [default: 9002]"
+    )]
+    pub port: Option<u16>,
+}
+
+/// Settings for the `server` subcommand. Unset ones fall back to the environment,
+/// then to the config file, then to their defaults.
+#[derive(Debug, Default, Clone, StructOpt, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ServerArgs {
+    /// The addresses to listen on
+    #[structopt(
+        short,
+        long = "address",
+        about = "An IPv4 or IPv6 address to listen on, with or without a port, can be repeated [default: 0.0.0.0]"
+    )]
+    pub addresses: Vec<String>,
+
+    /// The port for the server to listen on
+    #[structopt(
+        short,
+        long,
+        about = "The port the server will listen on, for addresses without one [default: 9000]"
+    )]
+    pub port: Option<u16>,
+
+    /// Pre-shared tokens clients may authenticate with
+    #[structopt(
+        long = "token",
+        about = "A pre-shared token clients may authenticate with, can be repeated"
+    )]
+    pub tokens: Vec<String>,
+
+    /// The key signed credentials are verified with
+    #[structopt(
+        long,
+        about = "A key for verifying signed credentials created with the credential subcommand"
+    )]
+    pub signing_key: Option<String>,
+
+    /// A PEM certificate chain to serve `wss://` with
+    #[structopt(
+        long,
+        requires = "tls_key",
+        about = "A PEM encoded certificate chain, to accept wss:// connections"
+    )]
+    pub tls_certificate: Option<PathBuf>,
+
+    /// The PEM private key for the certificate
+    #[structopt(
+        long,
+        requires = "tls_certificate",
+        about = "The PEM encoded private key of the certificate"
+    )]
+    pub tls_key: Option<PathBuf>,
+
+    /// Serve `wss://` with a certificate generated at startup
+    #[structopt(
+        long,
+        conflicts_with = "tls_certificate",
+        parse(from_flag = flag),
+        about = "Accept wss:// connections using a generated self-signed certificate, for development"
+    )]
+    pub tls_self_signed: Flag,
+
+    /// How often clients are pinged
+    #[structopt(
+        long,
+        about = "How many seconds between the pings sent to each client [default: 5]"
+    )]
+    pub heartbeat_interval: Option<u64>,
+
+    /// How long a silent client is kept
+    #[structopt(
+        long,
+        about = "How many seconds a client may send nothing before it is disconnected [default: 15]"
+    )]
+    pub liveness_timeout: Option<u64>,
+
+    /// The largest frame a client may send
+    #[structopt(
+        long,
+        about = "The largest websocket frame, in bytes, a client may send [default: 1048576]"
+    )]
+    pub max_frame_size: Option<usize>,
+
+    /// The largest message a client may send
+    #[structopt(
+        long,
+        about = "The largest message, in bytes, a client may send once decompressed or reassembled from chunks [default: 67108864]"
+    )]
+    pub max_payload_size: Option<u64>,
+
+    /// How many messages a client may send each second
+    #[structopt(
+        long,
+        about = "How many messages per second each client may send on average, 0 for no limit [default: 100]"
+    )]
+    pub rate_limit: Option<u32>,
+
+    /// How many messages a client may send at once
+    #[structopt(
+        long,
+        about = "How many messages each client may send in a burst before being rate limited [default: 200]"
+    )]
+    pub rate_burst: Option<u32>,
+
+    /// A port for clients without a websocket stack
+    #[structopt(
+        long,
+        about = "Also accept clients sending length prefixed frames over TCP on this port, wrapped in TLS when it is configured"
+    )]
+    pub tcp_port: Option<u16>,
+
+    /// A socket for local clients without a websocket stack
+    #[structopt(
+        long,
+        about = "Also accept clients sending length prefixed frames over this Unix domain socket, in plaintext and only from the same user"
+    )]
+    pub unix_socket: Option<PathBuf>,
+
+    /// Where to record every command run
+    #[structopt(
+        long,
+        about = "Append a JSON line to this file for every command run, rotating it when full"
+    )]
+    pub audit_log: Option<PathBuf>,
+
+    /// How large the audit log may grow before it is rotated
+    #[structopt(
+        long,
+        about = "How many bytes the audit log may grow to before it is rotated [default: 10485760]"
+    )]
+    pub audit_log_max_size: Option<u64>,
+
+    /// Let frontends on the local network find the server
+    #[structopt(
+        long,
+        parse(from_flag = flag),
+        about = "Answer frontends looking for backends on the local network, over UDP port 9003"
+    )]
+    pub discoverable: Flag,
+
+    /// What discovering frontends list the server as
+    #[structopt(
+        long,
+        about = "The name frontends list the server as when discoverable [default: the host name]"
+    )]
+    pub name: Option<String>,
+
+    /// Serve the web frontend on the same port
+    #[cfg(feature = "bundled")]
+    #[structopt(
+        long,
+        parse(from_flag = flag),
+        about = "Also serve the web frontend on the websocket port, which then connects to the backend on /ws"
+    )]
+    pub web: Flag,
+}
+
+/// A switch that is unset rather than off when its flag is not given, so the environment
+/// and the config file can still turn it on or off
+pub type Flag = Option<bool>;
+
+fn flag(present: bool) -> Flag {
+    present.then_some(true)
+}
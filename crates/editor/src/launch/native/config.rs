@@ -0,0 +1,435 @@
+use super::{
+    cli::{BrowserArgs, ServerArgs},
+    server::{AuditConfig, Limits, Liveness, Server, ServerOptions, TlsConfig},
+};
+use enum2str::EnumStr;
+use rpc::{AuthConfig, RateLimit, DEFAULT_RATE_LIMIT};
+use serde::Deserialize;
+use std::{
+    net::{IpAddr, Ipv4Addr, SocketAddr},
+    path::{Path, PathBuf},
+    str::FromStr,
+    time::Duration,
+};
+
+/// Every environment variable read for settings starts with this
+const ENV_PREFIX: &str = "EDITOR_";
+
+const DEFAULT_SERVER_ADDRESS: IpAddr = IpAddr::V4(Ipv4Addr::UNSPECIFIED);
+const DEFAULT_SERVER_PORT: u16 = 9000;
+#[cfg(feature = "bundled")]
+const DEFAULT_BROWSER_ADDRESS: &str = "localhost";
+#[cfg(feature = "bundled")]
+const DEFAULT_BROWSER_PORT: u16 = 9002;
+
+/// Settings read from a TOML file, overridden by `EDITOR_*` environment variables
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub(crate) struct Config {
+    pub(crate) logging: Logging,
+    pub(crate) server: ServerArgs,
+    pub(crate) browser: BrowserArgs,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub(crate) struct Logging {
+    /// An `env_logger` filter, such as `info` or `editor_core=debug`
+    pub(crate) level: Option<String>,
+}
+
+#[derive(Debug, EnumStr)]
+pub(crate) enum ConfigError {
+    #[enum2str("Failed to read the config file '{path}'. Error: {error}")]
+    Read { path: String, error: String },
+
+    #[enum2str("The config file '{path}' is invalid. Error: {error}")]
+    Parse { path: String, error: String },
+
+    #[enum2str("The environment variable {name} has an invalid value '{value}'.")]
+    Environment { name: String, value: String },
+
+    #[enum2str("'{address}' is not an IP address, with or without a port.")]
+    Address { address: String },
+
+    #[enum2str("A TLS certificate and key must be given together.")]
+    IncompleteTls,
+
+    #[enum2str("The heartbeat interval must be at least a second.")]
+    HeartbeatInterval,
+
+    #[enum2str("The liveness timeout of {timeout}s must be longer than the heartbeat interval of {interval}s.")]
+    LivenessTimeout { timeout: u64, interval: u64 },
+}
+
+impl Config {
+    /// Reads the file at `path`, or the one `EDITOR_CONFIG` names, then applies the environment
+    pub(crate) fn load(path: Option<&Path>) -> Result<Self, ConfigError> {
+        let path = match path {
+            Some(path) => Some(path.to_path_buf()),
+            None => env::<PathBuf>("CONFIG")?,
+        };
+        let config = match path {
+            Some(path) => Self::read(&path)?,
+            None => Self::default(),
+        };
+
+        Ok(Self {
+            logging: Logging {
+                level: env("LOG_LEVEL")?.or(config.logging.level),
+            },
+            server: server_env()?.or(config.server),
+            browser: browser_env()?.or(config.browser),
+        })
+    }
+
+    fn read(path: &Path) -> Result<Self, ConfigError> {
+        let text = std::fs::read_to_string(path).map_err(|error| ConfigError::Read {
+            path: path.display().to_string(),
+            error: error.to_string(),
+        })?;
+        toml::from_str(&text).map_err(|error| ConfigError::Parse {
+            path: path.display().to_string(),
+            error: error.to_string(),
+        })
+    }
+}
+
+impl ServerArgs {
+    /// Keeps the settings given here, taking unset ones from `fallback`
+    pub(crate) fn or(self, fallback: Self) -> Self {
+        Self {
+            addresses: or_list(self.addresses, fallback.addresses),
+            port: self.port.or(fallback.port),
+            tokens: or_list(self.tokens, fallback.tokens),
+            signing_key: self.signing_key.or(fallback.signing_key),
+            tls_certificate: self.tls_certificate.or(fallback.tls_certificate),
+            tls_key: self.tls_key.or(fallback.tls_key),
+            tls_self_signed: self.tls_self_signed.or(fallback.tls_self_signed),
+            heartbeat_interval: self.heartbeat_interval.or(fallback.heartbeat_interval),
+            liveness_timeout: self.liveness_timeout.or(fallback.liveness_timeout),
+            max_frame_size: self.max_frame_size.or(fallback.max_frame_size),
+            max_payload_size: self.max_payload_size.or(fallback.max_payload_size),
+            rate_limit: self.rate_limit.or(fallback.rate_limit),
+            rate_burst: self.rate_burst.or(fallback.rate_burst),
+            tcp_port: self.tcp_port.or(fallback.tcp_port),
+            unix_socket: self.unix_socket.or(fallback.unix_socket),
+            discoverable: self.discoverable.or(fallback.discoverable),
+            name: self.name.or(fallback.name),
+            audit_log: self.audit_log.or(fallback.audit_log),
+            audit_log_max_size: self.audit_log_max_size.or(fallback.audit_log_max_size),
+            #[cfg(feature = "bundled")]
+            web: self.web.or(fallback.web),
+        }
+    }
+
+    /// A server listening on every address, unset settings defaulted
+    pub(crate) fn resolve(self) -> Result<Server, ConfigError> {
+        let port = self.port.unwrap_or(DEFAULT_SERVER_PORT);
+        let mut addresses = self
+            .addresses
+            .iter()
+            .map(|address| socket_address(address, port))
+            .collect::<Result<Vec<_>, _>>()?
+            .into_iter();
+        let first = addresses
+            .next()
+            .unwrap_or(SocketAddr::new(DEFAULT_SERVER_ADDRESS, port));
+        let server = addresses.fold(Server::bind(first), Server::also_bind);
+        #[cfg(feature = "bundled")]
+        let server = if self.web.unwrap_or(false) {
+            server.with_assets(super::bundle::asset)
+        } else {
+            server
+        };
+
+        let tls = match (self.tls_certificate, self.tls_key) {
+            (Some(certificate), Some(key)) => Some(TlsConfig::Files { certificate, key }),
+            (Some(_), None) | (None, Some(_)) => return Err(ConfigError::IncompleteTls),
+            _ if self.tls_self_signed.unwrap_or(false) => Some(TlsConfig::SelfSigned {
+                hostnames: vec!["localhost".to_string()],
+            }),
+            _ => None,
+        };
+
+        let liveness = Liveness::default();
+        let liveness = Liveness {
+            heartbeat_interval: self
+                .heartbeat_interval
+                .map_or(liveness.heartbeat_interval, Duration::from_secs),
+            timeout: self
+                .liveness_timeout
+                .map_or(liveness.timeout, Duration::from_secs),
+        };
+        // Pings are sent on an interval timer, and a client is only dropped after missing one
+        if liveness.heartbeat_interval.is_zero() {
+            return Err(ConfigError::HeartbeatInterval);
+        }
+        if liveness.timeout <= liveness.heartbeat_interval {
+            return Err(ConfigError::LivenessTimeout {
+                timeout: liveness.timeout.as_secs(),
+                interval: liveness.heartbeat_interval.as_secs(),
+            });
+        }
+
+        let limits = Limits::default();
+        let rate_limit = self.rate_limit.unwrap_or(DEFAULT_RATE_LIMIT.per_second);
+        let options = ServerOptions {
+            auth: AuthConfig {
+                tokens: self.tokens,
+                signing_key: self.signing_key,
+            },
+            tls,
+            liveness,
+            limits: Limits {
+                max_frame_size: self.max_frame_size.unwrap_or(limits.max_frame_size),
+                max_payload_size: self.max_payload_size.unwrap_or(limits.max_payload_size),
+                rate_limit: (rate_limit > 0).then_some(RateLimit {
+                    per_second: rate_limit,
+                    burst: self.rate_burst.unwrap_or(DEFAULT_RATE_LIMIT.burst).max(1),
+                }),
+            },
+            tcp_port: self.tcp_port,
+            unix_socket: self.unix_socket,
+            discovery_name: self
+                .discoverable
+                .unwrap_or(false)
+                .then(|| self.name.unwrap_or_else(host_name)),
+            audit_log: self.audit_log.map(|path| {
+                let audit = AuditConfig::new(path);
+                AuditConfig {
+                    max_size: self.audit_log_max_size.unwrap_or(audit.max_size),
+                    ..audit
+                }
+            }),
+        };
+        Ok(server.with_options(options))
+    }
+}
+
+impl BrowserArgs {
+    /// Keeps the settings given here, taking unset ones from `fallback`
+    pub(crate) fn or(self, fallback: Self) -> Self {
+        Self {
+            address: self.address.or(fallback.address),
+            port: self.port.or(fallback.port),
+        }
+    }
+
+    /// The address to serve the bundle on, unset settings defaulted
+    #[cfg(feature = "bundled")]
+    pub(crate) fn resolve(self) -> Result<SocketAddr, ConfigError> {
+        let address = self.address.as_deref().unwrap_or(DEFAULT_BROWSER_ADDRESS);
+        socket_address(address, self.port.unwrap_or(DEFAULT_BROWSER_PORT))
+    }
+}
+
+fn server_env() -> Result<ServerArgs, ConfigError> {
+    Ok(ServerArgs {
+        addresses: env_list("SERVER_ADDRESSES"),
+        port: env("SERVER_PORT")?,
+        tokens: env_list("SERVER_TOKENS"),
+        signing_key: env("SERVER_SIGNING_KEY")?,
+        tls_certificate: env("SERVER_TLS_CERTIFICATE")?,
+        tls_key: env("SERVER_TLS_KEY")?,
+        tls_self_signed: env("SERVER_TLS_SELF_SIGNED")?,
+        heartbeat_interval: env("SERVER_HEARTBEAT_INTERVAL")?,
+        liveness_timeout: env("SERVER_LIVENESS_TIMEOUT")?,
+        max_frame_size: env("SERVER_MAX_FRAME_SIZE")?,
+        max_payload_size: env("SERVER_MAX_PAYLOAD_SIZE")?,
+        rate_limit: env("SERVER_RATE_LIMIT")?,
+        rate_burst: env("SERVER_RATE_BURST")?,
+        tcp_port: env("SERVER_TCP_PORT")?,
+        unix_socket: env("SERVER_UNIX_SOCKET")?,
+        discoverable: env("SERVER_DISCOVERABLE")?,
+        name: env("SERVER_NAME")?,
+        audit_log: env("SERVER_AUDIT_LOG")?,
+        audit_log_max_size: env("SERVER_AUDIT_LOG_MAX_SIZE")?,
+        #[cfg(feature = "bundled")]
+        web: env("SERVER_WEB")?,
+    })
+}
+
+fn browser_env() -> Result<BrowserArgs, ConfigError> {
+    Ok(BrowserArgs {
+        address: env("BROWSER_ADDRESS")?,
+        port: env("BROWSER_PORT")?,
+    })
+}
+
+// Unset and empty variables both leave the setting to the config file
+fn env<T: FromStr>(name: &str) -> Result<Option<T>, ConfigError> {
+    let name = format!("{ENV_PREFIX}{name}");
+    match std::env::var(&name) {
+        Ok(value) if !value.trim().is_empty() => value
+            .trim()
+            .parse()
+            .map(Some)
+            .map_err(|_| ConfigError::Environment { name, value }),
+        _ => Ok(None),
+    }
+}
+
+// Lists are comma separated
+fn env_list(name: &str) -> Vec<String> {
+    std::env::var(format!("{ENV_PREFIX}{name}"))
+        .unwrap_or_default()
+        .split(',')
+        .map(str::trim)
+        .filter(|item| !item.is_empty())
+        .map(str::to_string)
+        .collect()
+}
+
+fn or_list(list: Vec<String>, fallback: Vec<String>) -> Vec<String> {
+    if list.is_empty() {
+        fallback
+    } else {
+        list
+    }
+}
+
+// Servers are told apart by the machine they run on unless named
+fn host_name() -> String {
+    gethostname::gethostname()
+        .into_string()
+        .ok()
+        .map(|name| name.trim().to_string())
+        .filter(|name| !name.is_empty())
+        .unwrap_or_else(|| env!("CARGO_PKG_NAME").to_string())
+}
+
+/// Parses `127.0.0.1`, `::1`, `[::1]:9000` or `localhost`, using `port` when none is given
+fn socket_address(address: &str, port: u16) -> Result<SocketAddr, ConfigError> {
+    if address == "localhost" {
+        return Ok(SocketAddr::new(Ipv4Addr::LOCALHOST.into(), port));
+    }
+    if let Ok(address) = address.parse::<SocketAddr>() {
+        return Ok(address);
+    }
+    address
+        .trim_start_matches('[')
+        .trim_end_matches(']')
+        .parse::<IpAddr>()
+        .map(|ip| SocketAddr::new(ip, port))
+        .map_err(|_| ConfigError::Address {
+            address: address.to_string(),
+        })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{host_name, socket_address, Config, ConfigError};
+    use crate::launch::native::cli::ServerArgs;
+    use std::net::SocketAddr;
+    use structopt::StructOpt;
+
+    #[test]
+    fn test_flags() {
+        // A flag that isn't given leaves the switch to the environment and the config file
+        let flags = ServerArgs::from_iter(["server", "--discoverable"]);
+        assert_eq!(flags.discoverable, Some(true));
+        assert_eq!(flags.tls_self_signed, None);
+
+        let fallback = ServerArgs {
+            tls_self_signed: Some(true),
+            discoverable: Some(false),
+            ..Default::default()
+        };
+        let server = flags.or(fallback);
+        assert_eq!(server.discoverable, Some(true));
+        assert_eq!(server.tls_self_signed, Some(true));
+    }
+
+    #[test]
+    fn test_precedence() {
+        let path = std::env::temp_dir().join(format!("editor-config-{}.toml", std::process::id()));
+        std::fs::write(
+            &path,
+            "[server]\nport = 1\nname = \"file\"\ntls_self_signed = true\ndiscoverable = true\n",
+        )
+        .unwrap();
+        std::env::set_var("EDITOR_SERVER_PORT", "2");
+        std::env::set_var("EDITOR_SERVER_TLS_SELF_SIGNED", "false");
+        let config = Config::load(Some(&path));
+        std::env::remove_var("EDITOR_SERVER_PORT");
+        std::env::remove_var("EDITOR_SERVER_TLS_SELF_SIGNED");
+        std::fs::remove_file(&path).unwrap();
+
+        // The environment overrides the file, even to turn a switch off
+        let server = config.unwrap().server;
+        assert_eq!(server.port, Some(2));
+        assert_eq!(server.name.as_deref(), Some("file"));
+        assert_eq!(server.tls_self_signed, Some(false));
+        assert_eq!(server.discoverable, Some(true));
+
+        // Flags override both
+        let flags = ServerArgs {
+            port: Some(3),
+            ..Default::default()
+        };
+        let server = flags.or(server);
+        assert_eq!(server.port, Some(3));
+        assert_eq!(server.name.as_deref(), Some("file"));
+    }
+
+    #[test]
+    fn test_invalid_file() {
+        let path = std::env::temp_dir().join(format!("editor-invalid-{}.toml", std::process::id()));
+        std::fs::write(&path, "[server]\nunknown = 1\n").unwrap();
+        let config = Config::load(Some(&path));
+        std::fs::remove_file(&path).unwrap();
+        assert!(matches!(config, Err(ConfigError::Parse { .. })));
+
+        let missing = std::env::temp_dir().join("editor-missing.toml");
+        assert!(matches!(
+            Config::load(Some(&missing)),
+            Err(ConfigError::Read { .. })
+        ));
+    }
+
+    #[test]
+    fn test_socket_address() {
+        let address = |address: &str| socket_address(address, 9000).ok();
+        let parsed = |address: &str| address.parse::<SocketAddr>().ok();
+        assert_eq!(address("localhost"), parsed("127.0.0.1:9000"));
+        assert_eq!(address("127.0.0.1"), parsed("127.0.0.1:9000"));
+        assert_eq!(address("10.0.0.1:80"), parsed("10.0.0.1:80"));
+        assert_eq!(address("::1"), parsed("[::1]:9000"));
+        assert_eq!(address("[::1]"), parsed("[::1]:9000"));
+        assert_eq!(address("[::1]:80"), parsed("[::1]:80"));
+        assert!(matches!(
+            socket_address("example.com", 9000),
+            Err(ConfigError::Address { .. })
+        ));
+    }
+
+    #[test]
+    fn test_resolve() {
+        let server = ServerArgs {
+            tls_certificate: Some("certificate.pem".into()),
+            ..Default::default()
+        };
+        assert!(matches!(server.resolve(), Err(ConfigError::IncompleteTls)));
+
+        let server = ServerArgs {
+            heartbeat_interval: Some(10),
+            liveness_timeout: Some(10),
+            ..Default::default()
+        };
+        assert!(matches!(
+            server.resolve(),
+            Err(ConfigError::LivenessTimeout {
+                timeout: 10,
+                interval: 10
+            })
+        ));
+        assert!(ServerArgs::default().resolve().is_ok());
+    }
+
+    #[test]
+    fn test_host_name() {
+        let expected = gethostname::gethostname();
+        assert_eq!(host_name(), expected.to_string_lossy().trim());
+    }
+}
@@ -1,93 +1,593 @@
-use futures_util::{
-    stream::{SplitSink, SplitStream},
-    SinkExt, StreamExt, TryStreamExt,
+mod audit;
+mod builder;
+mod framing;
+mod gateway;
+mod http;
+mod jsonrpc;
+mod metrics;
+mod tls;
+
+pub use self::{
+    audit::{
+        read_audit_log, AuditConfig, AuditRecord, DEFAULT_AUDIT_LOG_MAX_FILES,
+        DEFAULT_AUDIT_LOG_MAX_SIZE,
+    },
+    builder::*,
+    tls::TlsConfig,
+};
+
+pub use rpc::Limits;
+
+use self::{audit::AuditLog, gateway::Gateway, http::Assets, metrics::Metrics};
+
+use futures_util::{Sink, SinkExt, Stream, StreamExt};
+use log::{error, info, warn};
+use rpc::{
+    AuthConfig, Backend, BackendConnection, Execution, Job, Observer, Outgoing, Response,
+    RpcExecutor, RpcResult, DEFAULT_HEARTBEAT_INTERVAL, DEFAULT_LIVENESS_TIMEOUT,
+};
+use std::{
+    fmt::Display,
+    path::PathBuf,
+    sync::{Arc, Mutex, MutexGuard},
+    time::Duration,
 };
-use log::{error, info};
-use rpc::{Id, Message, Response, RpcExecutor};
-use std::{sync::Arc, time::Duration};
 use tokio::{
-    net::{TcpListener, TcpStream},
-    sync::{RwLock, RwLockWriteGuard},
+    io::{AsyncRead, AsyncWrite},
+    sync::{mpsc, watch},
+    task::JoinHandle,
+    time::Instant,
 };
-use tokio_tungstenite::{tungstenite::Message as WebsocketMessage, WebSocketStream};
+use tokio_tungstenite::{
+    tungstenite::{
+        protocol::{frame::coding::CloseCode, CloseFrame, Role, WebSocketConfig},
+        Message as WebsocketMessage,
+    },
+    WebSocketStream,
+};
+
+/// How the server authenticates clients and secures connections
+#[derive(Default, Debug, Clone)]
+pub struct ServerOptions {
+    pub auth: AuthConfig,
+
+    /// Serves `wss://` instead of `ws://` when set
+    pub tls: Option<TlsConfig>,
+
+    pub liveness: Liveness,
+
+    pub limits: Limits,
+
+    /// Also accepts clients sending length prefixed frames over TCP on this port,
+    /// wrapped in TLS when it is configured
+    pub tcp_port: Option<u16>,
+
+    /// Also accepts clients sending length prefixed frames over this Unix domain socket.
+    /// They are never wrapped in TLS, as only the user running the server may connect.
+    pub unix_socket: Option<PathBuf>,
+
+    /// Answers frontends looking for backends on the local network with this name
+    pub discovery_name: Option<String>,
+
+    /// Records every command run or refused when set
+    pub audit_log: Option<AuditConfig>,
+}
 
-pub(crate) async fn listen(port: u16) {
-    let address = format!("0.0.0.0:{port}");
+/// How the server notices clients that went away without closing their connection
+#[derive(Debug, Clone, Copy)]
+pub struct Liveness {
+    /// How often each client is sent a websocket ping
+    pub heartbeat_interval: Duration,
 
-    let try_socket = TcpListener::bind(&address).await;
-    let listener = try_socket.expect("Failed to bind");
-    info!("Listening on: {address}");
+    /// How long a client may go without sending anything, pongs included, before it is dropped
+    pub timeout: Duration,
+}
 
-    while let Ok((stream, _)) = listener.accept().await {
-        tokio::spawn(accept_connection(stream));
+impl Default for Liveness {
+    fn default() -> Self {
+        Self {
+            heartbeat_interval: DEFAULT_HEARTBEAT_INTERVAL,
+            timeout: DEFAULT_LIVENESS_TIMEOUT,
+        }
     }
 }
 
-async fn accept_connection(stream: TcpStream) {
-    let address = stream
-        .peer_addr()
-        .expect("connected streams should have a peer address");
-    info!("Peer address: {address}");
+/// Server-wide state shared by every connection
+pub(crate) struct ServerState {
+    // Dispatches the messages of every client, however it connected
+    backend: Arc<Backend>,
 
-    let ws_stream = tokio_tungstenite::accept_async(stream)
-        .await
-        .expect("Error during the websocket handshake occurred");
+    liveness: Liveness,
 
-    info!("New WebSocket connection: {address}");
+    // Served over HTTP on the websocket port when set
+    assets: Option<Assets>,
 
-    let server = Arc::new(RwLock::new(RpcExecutor));
+    metrics: Arc<Metrics>,
+    audit: Option<Arc<AuditLog>>,
 
-    let (mut write, mut read) = ws_stream.split();
+    // Commands POSTed over HTTP, which run outside of any connection
+    gateway: Gateway,
+
+    // Set once, when the server starts shutting down
+    shutdown: watch::Sender<bool>,
+}
+
+impl ServerState {
+    pub(crate) fn new(
+        executor: RpcExecutor,
+        auth: AuthConfig,
+        liveness: Liveness,
+        limits: Limits,
+        assets: Option<Assets>,
+        audit: Option<AuditLog>,
+    ) -> Self {
+        let metrics = Arc::new(Metrics::default());
+        let audit = audit.map(Arc::new);
+        let recorder = Recorder {
+            metrics: metrics.clone(),
+            audit: audit.clone(),
+        };
+        let backend = Backend::new(executor)
+            .with_auth(auth)
+            .with_limits(limits)
+            .with_observer(recorder);
+        Self {
+            backend: Arc::new(backend),
+            liveness,
+            assets,
+            metrics,
+            audit,
+            gateway: Gateway::default(),
+            shutdown: watch::channel(false).0,
+        }
+    }
+
+    /// Stops every listener and connection
+    pub(crate) fn shut_down(&self) {
+        self.shutdown.send_replace(true);
+    }
+
+    /// Resolves once the server starts shutting down, or right away if it already has
+    pub(crate) async fn stopping(&self) {
+        let mut shutdown = self.shutdown.subscribe();
+        while !*shutdown.borrow_and_update() {
+            if shutdown.changed().await.is_err() {
+                return;
+            }
+        }
+    }
+}
+
+pub(crate) type SharedServerState = Arc<ServerState>;
+
+/// Feeds what the backend does into the metrics and the audit log
+struct Recorder {
+    metrics: Arc<Metrics>,
+    audit: Option<Arc<AuditLog>>,
+}
+
+impl Recorder {
+    fn audit(&self, execution: &Execution<'_>) {
+        if let Some(audit) = &self.audit {
+            audit.record(&AuditRecord::new(
+                execution.peer,
+                execution.client_id,
+                execution.command,
+                execution.duration,
+                execution.result,
+            ));
+        }
+    }
+}
+
+impl Observer for Recorder {
+    fn executed(&self, execution: &Execution<'_>) {
+        self.audit(execution);
+        self.metrics.executed(execution.command, execution.duration);
+    }
+
+    // Only executed commands count towards the latencies in the metrics
+    fn handled(&self, execution: &Execution<'_>) {
+        self.audit(execution);
+    }
+
+    fn answered(&self, result: &RpcResult) {
+        self.metrics.answered(result);
+    }
+}
+
+/// Responses are queued here and written to the websocket by a dedicated task,
+/// so commands executing in the background can reply without owning the socket
+type Outbox = mpsc::UnboundedSender<WebsocketMessage>;
+
+/// How long a shutdown waits for the commands a client started before cancelling them
+const DRAIN_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Why a connection stopped reading
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Disconnect {
+    /// The client closed the connection, or it failed
+    Closed,
+
+    /// The client sent nothing, not even a pong, for longer than the liveness timeout
+    TimedOut,
+
+    /// The server is shutting down
+    Shutdown,
+}
+
+/// A client of the backend, along with what its websocket needs besides dispatching
+#[derive(Clone)]
+struct Connection {
+    dispatch: BackendConnection,
+    state: SharedServerState,
+
+    // How many spawned commands have yet to queue their response
+    running: Arc<watch::Sender<usize>>,
+
+    // Pings and close frames, queued behind the responses the backend sends
+    outbox: Outbox,
+}
+
+async fn accept_connection<S>(stream: S, peer: String, state: SharedServerState)
+where
+    S: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+{
+    info!("Peer address: {peer}");
+    http::serve(stream, peer, state).await;
+}
+
+/// Serves a client whose HTTP connection was upgraded to a websocket
+async fn accept_websocket<S>(stream: S, peer: String, state: SharedServerState)
+where
+    S: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+{
+    let max_message_size = max_message_size(state.backend.limits());
+    let config = WebSocketConfig {
+        max_message_size: Some(max_message_size),
+        max_frame_size: Some(max_message_size),
+        ..Default::default()
+    };
+    let ws_stream = WebSocketStream::from_raw_socket(stream, Role::Server, Some(config)).await;
+
+    info!("New WebSocket connection: {peer}");
+    let (write, read) = ws_stream.split();
+    serve_connection(read, write, &peer, state).await;
+}
+
+/// Serves a client sending length prefixed frames over a plain byte stream
+async fn accept_stream_connection<S>(stream: S, peer: String, state: SharedServerState)
+where
+    S: AsyncRead + AsyncWrite + Send + 'static,
+{
+    info!("New stream connection: {peer}");
+    let (read, write) = tokio::io::split(stream);
+    let read = Box::pin(framing::frames(
+        read,
+        max_message_size(state.backend.limits()),
+    ));
+    let write = Box::pin(framing::frame_sink(write));
+    serve_connection(read, write, &peer, state).await;
+}
+
+// Frames up to the payload limit are read so oversized ones can still be answered
+// with `Error::FrameTooLarge`, anything bigger closes the connection
+fn max_message_size(limits: &Limits) -> usize {
+    usize::try_from(limits.max_payload_size)
+        .unwrap_or(usize::MAX)
+        .max(limits.max_frame_size)
+}
+
+async fn serve_connection<R, W, E>(mut read: R, mut write: W, peer: &str, state: SharedServerState)
+where
+    R: Stream<Item = Result<WebsocketMessage, E>> + Unpin,
+    E: Display,
+    W: Sink<WebsocketMessage> + Unpin + Send + 'static,
+    W::Error: Display,
+{
+    state.metrics.connection_opened();
+
+    let (outbox, mut outgoing) = mpsc::unbounded_channel::<WebsocketMessage>();
+    let writer_peer = peer.to_string();
+    let writer_state = state.clone();
+    let writer = tokio::spawn(async move {
+        while let Some(message) = outgoing.recv().await {
+            writer_state.metrics.sent(message.len());
+
+            // Nothing may follow a close frame
+            let closing = matches!(message, WebsocketMessage::Close(_));
+            if let Err(error) = write.send(message).await {
+                // Whatever is still queued would fail the same way
+                error!("Failed to send to {writer_peer}: {error}");
+                return;
+            }
+            if closing {
+                break;
+            }
+        }
+        let _ = write.close().await;
+    });
+
+    let responses = outbox.clone();
+    let dispatch = state.backend.connect(peer, move |outgoing| {
+        let message = match outgoing {
+            Outgoing::Binary(frame) => WebsocketMessage::Binary(frame),
+            Outgoing::Text(text) => WebsocketMessage::Text(text),
+        };
+        if let Err(error) = responses.send(message) {
+            error!("Failed to queue response: {error}")
+        }
+    });
+    let connection = Connection {
+        dispatch,
+        state: state.clone(),
+        running: Arc::new(watch::channel(0).0),
+        outbox,
+    };
+
+    let disconnect = receive_rpc_messages(&connection, &mut read).await;
+
+    if disconnect == Disconnect::Shutdown {
+        drain_commands(&connection).await;
+    }
+    if disconnect != Disconnect::Closed {
+        queue_close(&connection, disconnect);
+    }
+    connection.dispatch.close();
+
+    // The writer finishes once every handle on the outbox is gone
+    drop(connection);
+    let _ = writer.await;
+}
+
+async fn receive_rpc_messages<R, E>(connection: &Connection, read: &mut R) -> Disconnect
+where
+    R: Stream<Item = Result<WebsocketMessage, E>> + Unpin,
+    E: Display,
+{
+    let Liveness {
+        heartbeat_interval,
+        timeout,
+    } = connection.state.liveness;
+    let client_id = connection.dispatch.client_id();
+    let mut pings = tokio::time::interval(heartbeat_interval);
+    let mut last_received = Instant::now();
+    let stopping = connection.state.stopping();
+    tokio::pin!(stopping);
 
     loop {
-        receive_rpc_messages(&mut read, &server, &mut write).await;
-        tokio::time::sleep(Duration::from_millis(1_000)).await;
+        tokio::select! {
+            message = read.next() => match message {
+                Some(Ok(WebsocketMessage::Close(_))) | None => return Disconnect::Closed,
+                Some(Err(error)) => {
+                    error!("Failed to read from '{client_id}': {error}");
+                    return Disconnect::Closed;
+                }
+                Some(Ok(message)) => {
+                    last_received = Instant::now();
+                    receive_frame(connection, message);
+                }
+            },
+            _ = pings.tick() => queue_ping(connection),
+            _ = tokio::time::sleep_until(last_received + timeout) => {
+                info!("Client '{client_id}' sent nothing for {timeout:?}, closing the connection");
+                return Disconnect::TimedOut;
+            }
+            _ = &mut stopping => {
+                info!("Closing the connection of '{client_id}' for shutdown");
+                return Disconnect::Shutdown;
+            }
+        }
+    }
+}
+
+// Lets the commands the client already sent finish and queue their
+// responses, so a shutdown doesn't lose work the client is waiting for
+async fn drain_commands(connection: &Connection) {
+    let mut running = connection.running.subscribe();
+    if tokio::time::timeout(DRAIN_TIMEOUT, running.wait_for(|running| *running == 0))
+        .await
+        .is_err()
+    {
+        warn!(
+            "Cancelling the commands of '{}' still running after {DRAIN_TIMEOUT:?}",
+            connection.dispatch.client_id()
+        );
     }
 }
 
-async fn receive_rpc_messages(
-    read: &mut SplitStream<WebSocketStream<TcpStream>>,
-    server: &Arc<RwLock<RpcExecutor>>,
-    write: &mut SplitSink<WebSocketStream<TcpStream>, WebsocketMessage>,
-) {
-    if let Ok(Some(WebsocketMessage::Binary(bytes))) = read.try_next().await {
-        receive_bytes(server, bytes, write).await;
+// Queued behind every response, so the client receives those before the close
+fn queue_close(connection: &Connection, disconnect: Disconnect) {
+    let (code, reason) = match disconnect {
+        Disconnect::TimedOut => (CloseCode::Policy, "No heartbeat received"),
+        _ => (CloseCode::Away, "The server is shutting down"),
+    };
+    let frame = CloseFrame {
+        code,
+        reason: reason.into(),
+    };
+    if let Err(error) = connection.outbox.send(WebsocketMessage::Close(Some(frame))) {
+        error!("Failed to queue close frame: {error}")
     }
 }
 
-async fn receive_bytes(
-    server: &Arc<RwLock<RpcExecutor>>,
-    bytes: Vec<u8>,
-    write: &mut SplitSink<WebSocketStream<TcpStream>, WebsocketMessage>,
-) {
-    let handle = server.write().await;
-    if let Ok(Message { id, command }) = bincode::deserialize::<Message>(&bytes) {
-        let response = execute_command(id, handle, command);
-        send_response(response, write).await;
+fn receive_frame(connection: &Connection, message: WebsocketMessage) {
+    connection.state.metrics.received(message.len());
+    match message {
+        WebsocketMessage::Binary(frame) => {
+            if let Some(job) = connection.dispatch.receive_frame(&frame) {
+                spawn_command(connection.clone(), job);
+            }
+        }
+        WebsocketMessage::Text(text) => {
+            connection.dispatch.use_json_rpc();
+            match connection.dispatch.check_frame_size(text.len()) {
+                Ok(()) => jsonrpc::receive_text(connection, &text),
+                Err(error) => jsonrpc::reject_text(connection, error),
+            }
+        }
+
+        // Pongs, and pings which tungstenite answers on its own, only count as signs of life
+        _ => {}
     }
 }
 
-fn execute_command(
-    id: Id,
-    mut handle: RwLockWriteGuard<'_, RpcExecutor>,
-    command: rpc::Command,
-) -> Response {
-    info!("[RPC ->]: {command:#?}");
-    let result = handle.execute(&id, command);
-    Response {
-        id: id.to_string(),
-        result,
+// Commands run on the blocking pool so the connection
+// can keep reading cancellation requests while they execute
+fn spawn_command(connection: Connection, job: Job) {
+    let task = start_command(&connection, job);
+    connection.running.send_modify(|running| *running += 1);
+    tokio::spawn(async move {
+        match task.await {
+            Ok(response) => connection.dispatch.respond(response),
+            Err(error) => error!("Failed to execute command: {error}"),
+        }
+        connection.running.send_modify(|running| *running -= 1);
+    });
+}
+
+fn start_command(connection: &Connection, job: Job) -> JoinHandle<Response> {
+    let dispatch = connection.dispatch.clone();
+    tokio::task::spawn_blocking(move || dispatch.execute(job))
+}
+
+fn queue_ping(connection: &Connection) {
+    if let Err(error) = connection.outbox.send(WebsocketMessage::Ping(Vec::new())) {
+        error!("Failed to queue ping: {error}")
     }
 }
 
-async fn send_response(
-    response: Response,
-    write: &mut SplitSink<WebSocketStream<TcpStream>, WebsocketMessage>,
-) {
-    info!("[RPC <-]: {response:#?}");
-    if let Ok(response_bytes) = bincode::serialize(&response) {
-        if let Err(error) = write.send(WebsocketMessage::Binary(response_bytes)).await {
-            error!("Failed to send response: {error}")
+fn lock<T>(mutex: &Mutex<T>) -> MutexGuard<'_, T> {
+    mutex
+        .lock()
+        .unwrap_or_else(|poisoned| poisoned.into_inner())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Liveness, Server, ServerOptions};
+    use futures_util::{SinkExt, StreamExt};
+    use rpc::{
+        CallContext, Codec, Command, Message, PayloadJson, Response, RpcExecutor, RpcMessage,
+        RpcResult, RpcService,
+    };
+    use std::{net::SocketAddr, time::Duration};
+    use tokio::net::TcpStream;
+    use tokio_tungstenite::{
+        connect_async,
+        tungstenite::{protocol::frame::coding::CloseCode, Message as WebsocketMessage},
+        MaybeTlsStream, WebSocketStream,
+    };
+
+    pub(super) const TIMEOUT: Duration = Duration::from_secs(5);
+
+    pub(super) type Client = WebSocketStream<MaybeTlsStream<TcpStream>>;
+
+    pub(super) async fn connect(address: SocketAddr) -> Client {
+        connect_async(format!("ws://{address}")).await.unwrap().0
+    }
+
+    pub(super) async fn send(client: &mut Client, id: &str, command: Command) {
+        let bytes = bincode::serialize(&Message::new(id.to_string(), command)).unwrap();
+        let frame = Codec::default().encode(&bytes);
+        client.send(WebsocketMessage::Binary(frame)).await.unwrap();
+    }
+
+    // The next message that isn't a ping or a pong, if the connection is still open
+    pub(super) async fn next(client: &mut Client) -> Option<WebsocketMessage> {
+        loop {
+            let message = tokio::time::timeout(TIMEOUT, client.next())
+                .await
+                .expect("Timed out waiting for the server");
+            match message? {
+                Ok(WebsocketMessage::Ping(_) | WebsocketMessage::Pong(_)) => {}
+                Ok(message) => return Some(message),
+                Err(_) => return None,
+            }
         }
     }
+
+    pub(super) async fn response(client: &mut Client) -> Response {
+        match next(client).await {
+            Some(WebsocketMessage::Binary(frame)) => {
+                bincode::deserialize(&Codec::decode(&frame).unwrap()).unwrap()
+            }
+            message => panic!("Expected a response, got {message:?}"),
+        }
+    }
+
+    async fn close_code(client: &mut Client) -> Option<CloseCode> {
+        match next(client).await {
+            Some(WebsocketMessage::Close(frame)) => frame.map(|frame| frame.code),
+            message => panic!("Expected a close frame, got {message:?}"),
+        }
+    }
+
+    struct Sleeper;
+
+    impl RpcService for Sleeper {
+        fn name(&self) -> &'static str {
+            "sleeper"
+        }
+
+        fn call(
+            &mut self,
+            _context: &mut CallContext<'_>,
+            payload: &str,
+        ) -> Result<PayloadJson, String> {
+            std::thread::sleep(Duration::from_millis(300));
+            Ok(payload.to_string())
+        }
+    }
+
+    #[tokio::test]
+    async fn test_drain_on_shutdown() {
+        let server = Server::bind("127.0.0.1:0".parse().unwrap())
+            .with_executor(RpcExecutor::default().with_service(Sleeper))
+            .start()
+            .await
+            .unwrap();
+        let mut client = connect(server.address()).await;
+        response(&mut client).await;
+
+        let command = Command::Service {
+            service: "sleeper".to_string(),
+            call_id: "call".to_string(),
+            payload: "{}".to_string(),
+        };
+        send(&mut client, "sleep", command).await;
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        let shutdown = tokio::spawn(server.shutdown());
+
+        // The command the client was waiting for is answered before the connection closes
+        let answer = response(&mut client).await;
+        assert_eq!(answer.id, "sleep");
+        assert!(matches!(
+            answer.result,
+            RpcResult::Success(RpcMessage::Service { .. })
+        ));
+        assert_eq!(close_code(&mut client).await, Some(CloseCode::Away));
+        shutdown.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_liveness_timeout() {
+        let options = ServerOptions {
+            liveness: Liveness {
+                heartbeat_interval: Duration::from_secs(3600),
+                timeout: Duration::from_millis(200),
+            },
+            ..Default::default()
+        };
+        let server = Server::bind("127.0.0.1:0".parse().unwrap())
+            .with_options(options)
+            .start()
+            .await
+            .unwrap();
+        let mut client = connect(server.address()).await;
+        response(&mut client).await;
+
+        assert_eq!(close_code(&mut client).await, Some(CloseCode::Policy));
+        server.shutdown().await;
+    }
 }
@@ -0,0 +1,323 @@
+use log::{error, warn};
+use rpc::{Id, RpcResult};
+use serde::{Deserialize, Serialize};
+use std::{
+    fs::{File, OpenOptions},
+    io::{self, BufRead, BufReader, Write},
+    path::{Path, PathBuf},
+    sync::Mutex,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+pub const DEFAULT_AUDIT_LOG_MAX_SIZE: u64 = 10 * 1024 * 1024;
+pub const DEFAULT_AUDIT_LOG_MAX_FILES: usize = 5;
+
+/// Where the server records every command it runs or refuses, one JSON object per line
+#[derive(Debug, Clone)]
+pub struct AuditConfig {
+    pub path: PathBuf,
+
+    /// Once the log would grow past this many bytes it is renamed to `{path}.1`,
+    /// older logs moving to `{path}.2` and so on, and a new one is started
+    pub max_size: u64,
+
+    /// How many rotated logs are kept besides the current one
+    pub max_files: usize,
+}
+
+impl AuditConfig {
+    pub fn new(path: PathBuf) -> Self {
+        Self {
+            path,
+            max_size: DEFAULT_AUDIT_LOG_MAX_SIZE,
+            max_files: DEFAULT_AUDIT_LOG_MAX_FILES,
+        }
+    }
+}
+
+/// One command run or refused by the server
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct AuditRecord {
+    /// Milliseconds since the unix epoch, when the command finished or was refused
+    pub timestamp: u64,
+    pub peer: String,
+    pub client_id: Id,
+    pub command: String,
+    pub duration_micros: u64,
+
+    /// The variant of the message or error answered
+    pub result: String,
+
+    /// What went wrong, if the command failed
+    pub error: Option<String>,
+}
+
+impl AuditRecord {
+    pub(crate) fn new(
+        peer: &str,
+        client_id: &str,
+        command: &str,
+        duration: Duration,
+        result: &RpcResult,
+    ) -> Self {
+        let (result, error) = match result {
+            RpcResult::Success(message) => (message.name(), None),
+            RpcResult::Error(error) => (error.name(), Some(error.to_string())),
+        };
+        Self {
+            timestamp: unix_time_millis(),
+            peer: peer.to_string(),
+            client_id: client_id.to_string(),
+            command: command.to_string(),
+            duration_micros: duration.as_micros() as u64,
+            result: result.to_string(),
+            error,
+        }
+    }
+}
+
+/// An append-only audit log, shared by every connection
+pub(crate) struct AuditLog {
+    config: AuditConfig,
+    file: Mutex<AuditFile>,
+}
+
+struct AuditFile {
+    file: File,
+    size: u64,
+}
+
+impl AuditLog {
+    pub(crate) fn open(config: AuditConfig) -> io::Result<Self> {
+        let file = AuditFile::open(&config.path)?;
+        Ok(Self {
+            config,
+            file: Mutex::new(file),
+        })
+    }
+
+    /// Appends `record`, rotating the log first if it is full.
+    /// Failures are logged, as a command that already ran can't be taken back.
+    pub(crate) fn record(&self, record: &AuditRecord) {
+        let mut line = match serde_json::to_vec(record) {
+            Ok(line) => line,
+            Err(error) => {
+                error!("Failed to serialize an audit record: {error}");
+                return;
+            }
+        };
+        line.push(b'\n');
+
+        let mut file = super::lock(&self.file);
+        if file.size > 0 && file.size + line.len() as u64 > self.config.max_size {
+            match self.rotate() {
+                Ok(rotated) => *file = rotated,
+                Err(error) => error!(
+                    "Failed to rotate the audit log {}: {error}",
+                    self.config.path.display()
+                ),
+            }
+        }
+        match file.file.write_all(&line) {
+            Ok(()) => file.size += line.len() as u64,
+            Err(error) => error!(
+                "Failed to write to the audit log {}: {error}",
+                self.config.path.display()
+            ),
+        }
+    }
+
+    fn rotate(&self) -> io::Result<AuditFile> {
+        let path = &self.config.path;
+        if self.config.max_files == 0 {
+            std::fs::remove_file(path)?;
+        } else {
+            // The oldest log is overwritten by the one before it
+            for index in (1..self.config.max_files).rev() {
+                let from = rotated_path(path, index);
+                if from.exists() {
+                    std::fs::rename(&from, rotated_path(path, index + 1))?;
+                }
+            }
+            std::fs::rename(path, rotated_path(path, 1))?;
+        }
+        AuditFile::open(path)
+    }
+}
+
+impl AuditFile {
+    fn open(path: &Path) -> io::Result<Self> {
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+        let size = file.metadata()?.len();
+        Ok(Self { file, size })
+    }
+}
+
+/// The records in the log at `path` and its rotated logs, oldest first, that were written
+/// between `since` and `until`, in seconds since the unix epoch, and by `client_id`.
+/// The log itself may be missing if rotated logs are left, as when a server rotated
+/// it and stopped before writing again.
+pub fn read_audit_log(
+    path: &Path,
+    since: Option<u64>,
+    until: Option<u64>,
+    client_id: Option<&str>,
+) -> io::Result<Vec<AuditRecord>> {
+    let mut paths = (1..)
+        .map(|index| rotated_path(path, index))
+        .take_while(|path| path.exists())
+        .collect::<Vec<_>>();
+    paths.reverse();
+    if !paths.is_empty() && !path.exists() {
+        warn!(
+            "The audit log {} is missing, reading its rotated logs",
+            path.display()
+        );
+    } else {
+        paths.push(path.to_path_buf());
+    }
+
+    let since = since.map(|since| since.saturating_mul(1000));
+    let until = until.map(|until| until.saturating_mul(1000));
+    let mut records = Vec::new();
+    for path in paths {
+        for line in BufReader::new(File::open(&path)?).lines() {
+            let line = line?;
+            // A line cut short by a crash shouldn't hide the rest of the log
+            let Ok(record) = serde_json::from_str::<AuditRecord>(&line) else {
+                continue;
+            };
+            let matches = since.map_or(true, |since| record.timestamp >= since)
+                && until.map_or(true, |until| record.timestamp < until)
+                && client_id.map_or(true, |client_id| record.client_id == client_id);
+            if matches {
+                records.push(record);
+            }
+        }
+    }
+    Ok(records)
+}
+
+fn rotated_path(path: &Path, index: usize) -> PathBuf {
+    let mut rotated = path.as_os_str().to_os_string();
+    rotated.push(format!(".{index}"));
+    PathBuf::from(rotated)
+}
+
+fn unix_time_millis() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |time| time.as_millis() as u64)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{read_audit_log, rotated_path, AuditConfig, AuditLog, AuditRecord};
+    use rpc::{RpcMessage, RpcResult};
+    use std::{path::PathBuf, time::Duration};
+
+    // A log path of its own for each test, cleaned up along with its rotated logs
+    struct TempLog(PathBuf);
+
+    impl TempLog {
+        fn new(name: &str) -> Self {
+            let path = std::env::temp_dir()
+                .join(format!("editor-audit-{name}-{}.log", std::process::id()));
+            let log = Self(path);
+            log.remove();
+            log
+        }
+
+        fn remove(&self) {
+            let _ = std::fs::remove_file(&self.0);
+            for index in 1..10 {
+                let _ = std::fs::remove_file(rotated_path(&self.0, index));
+            }
+        }
+    }
+
+    impl Drop for TempLog {
+        fn drop(&mut self) {
+            self.remove();
+        }
+    }
+
+    fn record(client_id: &str, timestamp: u64) -> AuditRecord {
+        AuditRecord {
+            timestamp,
+            ..AuditRecord::new(
+                "127.0.0.1:1",
+                client_id,
+                "Example",
+                Duration::ZERO,
+                &RpcResult::value(RpcMessage::Empty),
+            )
+        }
+    }
+
+    fn clients(records: &[AuditRecord]) -> Vec<&str> {
+        records
+            .iter()
+            .map(|record| record.client_id.as_str())
+            .collect()
+    }
+
+    #[test]
+    fn test_rotation() {
+        let log = TempLog::new("rotation");
+        let line_size = serde_json::to_vec(&record("a", 0)).unwrap().len() as u64 + 1;
+        let config = AuditConfig {
+            max_size: line_size * 2,
+            max_files: 2,
+            ..AuditConfig::new(log.0.clone())
+        };
+        let audit = AuditLog::open(config).unwrap();
+        for client_id in ["a", "b", "c", "d", "e", "f", "g"] {
+            audit.record(&record(client_id, 0));
+        }
+
+        // Two records per file, and the oldest file is dropped
+        assert!(!rotated_path(&log.0, 3).exists());
+        let records = read_audit_log(&log.0, None, None, None).unwrap();
+        assert_eq!(clients(&records), ["c", "d", "e", "f", "g"]);
+    }
+
+    #[test]
+    fn test_read_filters() {
+        let log = TempLog::new("filters");
+        let audit = AuditLog::open(AuditConfig::new(log.0.clone())).unwrap();
+        audit.record(&record("a", 1_000));
+        audit.record(&record("b", 2_000));
+        audit.record(&record("a", 3_000));
+        std::fs::write(
+            &log.0,
+            std::fs::read_to_string(&log.0).unwrap() + "{\"cut short",
+        )
+        .unwrap();
+
+        let read = |since, until, client_id| {
+            let records = read_audit_log(&log.0, since, until, client_id).unwrap();
+            records
+                .iter()
+                .map(|record| record.timestamp)
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(read(None, None, None), [1_000, 2_000, 3_000]);
+        assert_eq!(read(Some(2), None, None), [2_000, 3_000]);
+        assert_eq!(read(None, Some(3), None), [1_000, 2_000]);
+        assert_eq!(read(None, None, Some("a")), [1_000, 3_000]);
+        assert_eq!(read(Some(2), Some(3), Some("a")), Vec::<u64>::new());
+    }
+
+    #[test]
+    fn test_read_rotated_only() {
+        let log = TempLog::new("rotated");
+        assert!(read_audit_log(&log.0, None, None, None).is_err());
+
+        let rotated = rotated_path(&log.0, 1);
+        let line = serde_json::to_string(&record("a", 0)).unwrap();
+        std::fs::write(&rotated, line + "\n").unwrap();
+        let records = read_audit_log(&log.0, None, None, None).unwrap();
+        assert_eq!(clients(&records), ["a"]);
+    }
+}
@@ -0,0 +1,752 @@
+use super::{
+    accept_connection, accept_stream_connection, audit::AuditLog, http::Assets, tls, ServerOptions,
+    ServerState, SharedServerState,
+};
+use crate::discovery::{Announcement, DISCOVERY_PORT, PROBE};
+use enum2str::EnumStr;
+use log::{error, info, warn};
+use rpc::{Id, RpcExecutor};
+use std::{
+    borrow::Cow,
+    fmt::Display,
+    future::Future,
+    io,
+    net::{IpAddr, Ipv4Addr, SocketAddr},
+    sync::Arc,
+    time::Duration,
+};
+use tokio::{
+    io::{AsyncRead, AsyncWrite},
+    net::{TcpListener, UdpSocket},
+    task::{JoinHandle, JoinSet},
+};
+use tokio_rustls::TlsAcceptor;
+
+#[cfg(unix)]
+use std::path::{Path, PathBuf};
+#[cfg(unix)]
+use tokio::net::UnixListener;
+
+/// How long a listener waits before accepting again after a failed accept,
+/// such as when the process ran out of file descriptors
+const ACCEPT_RETRY_DELAY: Duration = Duration::from_millis(100);
+
+#[derive(Debug, Clone, PartialEq, Eq, EnumStr)]
+pub enum ServerError {
+    #[enum2str("Failed to bind {address}. Error: {error}")]
+    Bind { address: String, error: String },
+
+    #[enum2str("Failed to configure TLS. Error: {error}")]
+    Tls { error: String },
+
+    #[enum2str("Failed to open the audit log '{path}'. Error: {error}")]
+    AuditLog { path: String, error: String },
+}
+
+/// Runs the backend inside the calling process, on the current tokio runtime
+///
+/// ```no_run
+/// # async fn run() -> Result<(), editor_core::launch::server::ServerError> {
+/// use editor_core::launch::server::Server;
+///
+/// let handle = Server::bind(([127, 0, 0, 1], 0).into()).start().await?;
+/// println!("Listening on {}", handle.address());
+/// handle.shutdown().await;
+/// # Ok(())
+/// # }
+/// ```
+pub struct Server {
+    addresses: Vec<SocketAddr>,
+    executor: Option<RpcExecutor>,
+    options: ServerOptions,
+    assets: Option<Assets>,
+}
+
+impl Server {
+    /// Serves websocket clients on `address`. Port 0 picks a free port,
+    /// which `ServerHandle::address` reports once started.
+    pub fn bind(address: SocketAddr) -> Self {
+        Self {
+            addresses: vec![address],
+            executor: None,
+            options: ServerOptions::default(),
+            assets: None,
+        }
+    }
+
+    /// Also serves websocket clients on `address`, such as an IPv6 one next to an IPv4 one.
+    /// Every address shares the same sessions and rooms.
+    pub fn also_bind(mut self, address: SocketAddr) -> Self {
+        self.addresses.push(address);
+        self
+    }
+
+    /// Runs commands with `executor` instead of one serving the contract schema
+    pub fn with_executor(mut self, executor: RpcExecutor) -> Self {
+        self.executor = Some(executor);
+        self
+    }
+
+    pub fn with_options(mut self, options: ServerOptions) -> Self {
+        self.options = options;
+        self
+    }
+
+    /// Also serves static files, such as the web frontend, over HTTP on the websocket
+    /// addresses. `assets` is given a path relative to the site root, like `index.html`.
+    /// The index is marked so the frontend served this way connects to `/ws` of the same origin.
+    pub fn with_assets(
+        mut self,
+        assets: impl Fn(&str) -> Option<Cow<'static, [u8]>> + Send + Sync + 'static,
+    ) -> Self {
+        self.assets = Some(Arc::new(assets));
+        self
+    }
+
+    /// Binds every listener, then serves clients in the background until the handle
+    /// is shut down or dropped. Nothing is served if any listener fails to bind.
+    pub async fn start(self) -> Result<ServerHandle, ServerError> {
+        let ServerOptions {
+            auth,
+            tls,
+            liveness,
+            limits,
+            tcp_port,
+            unix_socket,
+            discovery_name,
+            audit_log,
+        } = self.options;
+
+        let acceptor = tls
+            .as_ref()
+            .map(tls::acceptor)
+            .transpose()
+            .map_err(|error| ServerError::Tls {
+                error: error.to_string(),
+            })?;
+
+        let scheme = if acceptor.is_some() { "wss" } else { "ws" };
+        let mut websocket_listeners = Vec::new();
+        for address in &self.addresses {
+            let (listener, address) = bind_tcp(*address).await?;
+            info!("Listening on: {scheme}://{address}");
+            if self.assets.is_some() {
+                let scheme = if acceptor.is_some() { "https" } else { "http" };
+                info!("Serving the web frontend on: {scheme}://{address}");
+            }
+            websocket_listeners.push((listener, address));
+        }
+        if auth.is_enabled() {
+            info!("Clients must authenticate before sending commands");
+        }
+
+        // Stream clients are accepted on the same interfaces as websocket ones
+        let mut tcp_listeners = Vec::new();
+        if let Some(port) = tcp_port {
+            let mut ips = self
+                .addresses
+                .iter()
+                .map(SocketAddr::ip)
+                .collect::<Vec<_>>();
+            ips.sort();
+            ips.dedup();
+            for ip in ips {
+                let (listener, address) = bind_tcp(SocketAddr::new(ip, port)).await?;
+                info!(
+                    "Listening for {} TCP clients on: {address}",
+                    stream_kind(&acceptor)
+                );
+                tcp_listeners.push((listener, address));
+            }
+        }
+
+        let discovery = match discovery_name {
+            Some(name) => {
+                let announcement = Announcement {
+                    name,
+                    version: env!("CARGO_PKG_VERSION").to_string(),
+                    port: announced_port(&websocket_listeners),
+                    tls: acceptor.is_some(),
+                };
+                Some((bind_discovery().await?, announcement))
+            }
+            None => None,
+        };
+
+        #[cfg(unix)]
+        let unix_listener = match unix_socket {
+            Some(path) => {
+                let listener = bind_unix(&path)?;
+                info!(
+                    "Listening for plaintext local clients on: {}",
+                    path.display()
+                );
+                Some(listener)
+            }
+            None => None,
+        };
+        #[cfg(not(unix))]
+        if let Some(path) = unix_socket {
+            error!(
+                "Unix domain sockets are not supported on this platform, not listening on {}",
+                path.display()
+            );
+        }
+
+        let audit = match audit_log {
+            Some(config) => {
+                let path = config.path.display().to_string();
+                let audit = AuditLog::open(config).map_err(|error| ServerError::AuditLog {
+                    path: path.clone(),
+                    error: error.to_string(),
+                })?;
+                info!("Recording commands in: {path}");
+                Some(audit)
+            }
+            None => None,
+        };
+
+        let executor = self.executor.unwrap_or_else(|| {
+            RpcExecutor::default().with_schema(crate::schema::contract_schema())
+        });
+        let state = SharedServerState::new(ServerState::new(
+            executor,
+            auth,
+            liveness,
+            limits,
+            self.assets,
+            audit,
+        ));
+
+        let addresses = websocket_listeners
+            .iter()
+            .map(|(_, address)| *address)
+            .collect();
+        let tcp_addresses = tcp_listeners.iter().map(|(_, address)| *address).collect();
+
+        let mut listeners = Vec::new();
+        for (listener, _) in websocket_listeners {
+            listeners.push(serve_websocket(listener, acceptor.clone(), state.clone()));
+        }
+        for (listener, _) in tcp_listeners {
+            listeners.push(serve_tcp(listener, acceptor.clone(), state.clone()));
+        }
+        #[cfg(unix)]
+        if let Some((listener, path)) = unix_listener {
+            listeners.push(serve_unix(listener, path, state.clone()));
+        }
+        if let Some((socket, announcement)) = discovery {
+            listeners.push(serve_discovery(socket, announcement, state.clone()));
+        }
+        listeners.push(drain_gateway(state.clone()));
+
+        Ok(ServerHandle {
+            addresses,
+            tcp_addresses,
+            state,
+            listeners,
+        })
+    }
+}
+
+// Commands POSTed over HTTP belong to no connection, so they are drained on their own
+fn drain_gateway(state: SharedServerState) -> JoinHandle<()> {
+    tokio::spawn(async move {
+        state.stopping().await;
+        state.gateway.drain().await;
+    })
+}
+
+fn serve_websocket(
+    listener: TcpListener,
+    acceptor: Option<TlsAcceptor>,
+    state: SharedServerState,
+) -> JoinHandle<()> {
+    tokio::spawn(async move {
+        let listener = &listener;
+        let acceptor = &acceptor;
+        let connection_state = state.clone();
+        accept_until_shutdown(&state, move || {
+            let state = connection_state.clone();
+            async move {
+                let (stream, address) = listener.accept().await?;
+                let peer = address.to_string();
+                let acceptor = acceptor.clone();
+                Ok(async move {
+                    match acceptor {
+                        Some(acceptor) => match acceptor.accept(stream).await {
+                            Ok(stream) => accept_connection(stream, peer, state).await,
+                            Err(error) => error!("TLS handshake with {peer} failed: {error}"),
+                        },
+                        None => accept_connection(stream, peer, state).await,
+                    }
+                })
+            }
+        })
+        .await
+    })
+}
+
+fn serve_tcp(
+    listener: TcpListener,
+    acceptor: Option<TlsAcceptor>,
+    state: SharedServerState,
+) -> JoinHandle<()> {
+    tokio::spawn(async move {
+        let listener = &listener;
+        let acceptor = &acceptor;
+        let connection_state = state.clone();
+        accept_until_shutdown(&state, move || {
+            let state = connection_state.clone();
+            async move {
+                let (stream, address) = listener.accept().await?;
+                if let Err(error) = stream.set_nodelay(true) {
+                    error!("Failed to disable Nagle's algorithm for {address}: {error}");
+                }
+                Ok(accept_stream(
+                    stream,
+                    address.to_string(),
+                    acceptor.clone(),
+                    state,
+                ))
+            }
+        })
+        .await
+    })
+}
+
+// Local clients are served in plaintext, as `bind_unix` keeps other users off the socket
+#[cfg(unix)]
+fn serve_unix(listener: UnixListener, path: PathBuf, state: SharedServerState) -> JoinHandle<()> {
+    tokio::spawn(async move {
+        let listener = &listener;
+        let peer = format!("unix:{}", path.display());
+        let peer = &peer;
+        let connection_state = state.clone();
+        accept_until_shutdown(&state, move || {
+            let state = connection_state.clone();
+            async move {
+                let (stream, _) = listener.accept().await?;
+                Ok(accept_stream_connection(stream, peer.to_string(), state))
+            }
+        })
+        .await;
+
+        // Clients finding the socket after shutdown would otherwise be refused
+        if let Err(error) = std::fs::remove_file(&path) {
+            error!("Failed to remove the socket {}: {error}", path.display());
+        }
+    })
+}
+
+// A server with TLS configured never serves a TCP client in plaintext
+async fn accept_stream<S>(
+    stream: S,
+    peer: String,
+    acceptor: Option<TlsAcceptor>,
+    state: SharedServerState,
+) where
+    S: AsyncRead + AsyncWrite + Send + Unpin + 'static,
+{
+    match acceptor {
+        Some(acceptor) => match acceptor.accept(stream).await {
+            Ok(stream) => accept_stream_connection(stream, peer, state).await,
+            Err(error) => error!("TLS handshake with {peer} failed: {error}"),
+        },
+        None => accept_stream_connection(stream, peer, state).await,
+    }
+}
+
+// Probes are answered until the server shuts down, there is no connection to wait for
+fn serve_discovery(
+    socket: UdpSocket,
+    announcement: Announcement,
+    state: SharedServerState,
+) -> JoinHandle<()> {
+    tokio::spawn(async move {
+        let answer = match serde_json::to_vec(&announcement) {
+            Ok(answer) => answer,
+            Err(error) => {
+                error!("Failed to serialize the discovery announcement: {error}");
+                return;
+            }
+        };
+        let stopping = state.stopping();
+        tokio::pin!(stopping);
+
+        let mut buffer = [0; 64];
+        loop {
+            tokio::select! {
+                received = socket.recv_from(&mut buffer) => match received {
+                    Ok((length, peer)) if &buffer[..length] == PROBE => {
+                        if let Err(error) = socket.send_to(&answer, peer).await {
+                            error!("Failed to answer the discovery probe from {peer}: {error}");
+                        }
+                    }
+                    Ok(_) => {}
+                    Err(error) => {
+                        error!("Failed to receive a discovery probe: {error}");
+                        tokio::time::sleep(ACCEPT_RETRY_DELAY).await;
+                    }
+                },
+                _ = &mut stopping => break,
+            }
+        }
+    })
+}
+
+/// A snapshot of one connected client
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SessionInfo {
+    pub client_id: Id,
+    pub peer_address: String,
+    pub connected_for: Duration,
+
+    /// Who the client authenticated as, if it has
+    pub subject: Option<String>,
+}
+
+/// Controls a started server, which shuts down when the handle is dropped
+pub struct ServerHandle {
+    addresses: Vec<SocketAddr>,
+    tcp_addresses: Vec<SocketAddr>,
+    state: SharedServerState,
+    listeners: Vec<JoinHandle<()>>,
+}
+
+impl ServerHandle {
+    /// The address websocket clients connect to, the first one bound if there are several
+    pub fn address(&self) -> SocketAddr {
+        self.addresses[0]
+    }
+
+    /// Every address websocket clients connect to
+    pub fn addresses(&self) -> &[SocketAddr] {
+        &self.addresses
+    }
+
+    /// The address length prefixed TCP clients connect to, if the server listens for them
+    pub fn tcp_address(&self) -> Option<SocketAddr> {
+        self.tcp_addresses.first().copied()
+    }
+
+    /// Every address length prefixed TCP clients connect to
+    pub fn tcp_addresses(&self) -> &[SocketAddr] {
+        &self.tcp_addresses
+    }
+
+    /// How many clients are connected
+    pub fn connections(&self) -> usize {
+        self.state.backend.connections()
+    }
+
+    pub fn sessions(&self) -> Vec<SessionInfo> {
+        self.state
+            .backend
+            .sessions()
+            .iter()
+            .map(|session| {
+                let session = super::lock(session);
+                SessionInfo {
+                    client_id: session.id().to_string(),
+                    peer_address: session.peer_address().to_string(),
+                    connected_for: session.connected_for(),
+                    subject: session.subject().map(str::to_string),
+                }
+            })
+            .collect()
+    }
+
+    /// Waits until the server has shut down and every connection has closed
+    pub async fn stopped(&mut self) {
+        for listener in self.listeners.drain(..) {
+            if let Err(error) = listener.await {
+                error!("A listener stopped unexpectedly: {error}");
+            }
+        }
+    }
+
+    /// Stops accepting clients, lets the commands they sent finish, sends each a close frame
+    /// and waits until every connection has closed
+    pub async fn shutdown(mut self) {
+        self.state.shut_down();
+        self.stopped().await;
+    }
+}
+
+impl Drop for ServerHandle {
+    fn drop(&mut self) {
+        self.state.shut_down();
+    }
+}
+
+/// Serves every connection `accept` yields until the server shuts down,
+/// then waits for the open ones to close
+async fn accept_until_shutdown<A, F, C>(state: &ServerState, mut accept: A)
+where
+    A: FnMut() -> F,
+    F: Future<Output = io::Result<C>>,
+    C: Future<Output = ()> + Send + 'static,
+{
+    let mut connections = JoinSet::new();
+    let stopping = state.stopping();
+    tokio::pin!(stopping);
+
+    loop {
+        tokio::select! {
+            accepted = accept() => match accepted {
+                Ok(connection) => {
+                    connections.spawn(connection);
+                }
+                Err(error) => {
+                    error!("Failed to accept a connection: {error}");
+                    tokio::time::sleep(ACCEPT_RETRY_DELAY).await;
+                }
+            },
+            // Reaps finished connections so the set doesn't grow for the server's lifetime
+            Some(_) = connections.join_next(), if !connections.is_empty() => {}
+            _ = &mut stopping => break,
+        }
+    }
+
+    while connections.join_next().await.is_some() {}
+}
+
+// Returns the address actually bound, which differs from `address` when it asks for port 0
+async fn bind_tcp(address: SocketAddr) -> Result<(TcpListener, SocketAddr), ServerError> {
+    let listener = TcpListener::bind(address)
+        .await
+        .map_err(|error| bind_error(address, error))?;
+    let local_address = listener
+        .local_addr()
+        .map_err(|error| bind_error(address, error))?;
+    Ok((listener, local_address))
+}
+
+// Frontends connect to the address the announcement came from, which is on the local network
+// unless they probed this machine directly, so a listener reachable from there is announced
+fn announced_port<T>(listeners: &[(T, SocketAddr)]) -> u16 {
+    let reachable = listeners.iter().find(|(_, address)| match address.ip() {
+        IpAddr::V4(ip) => !ip.is_loopback(),
+        // Discovery is over IPv4, which IPv6 wildcard sockets usually accept as well
+        IpAddr::V6(ip) => ip.is_unspecified(),
+    });
+    match reachable {
+        Some((_, address)) => address.port(),
+        None => {
+            let (_, address) = &listeners[0];
+            warn!("Only frontends on this machine can reach {address}, announcing it anyway");
+            address.port()
+        }
+    }
+}
+
+async fn bind_discovery() -> Result<UdpSocket, ServerError> {
+    let address = SocketAddr::new(Ipv4Addr::UNSPECIFIED.into(), DISCOVERY_PORT);
+    let socket = UdpSocket::bind(address)
+        .await
+        .map_err(|error| bind_error(address, error))?;
+    info!("Answering discovery probes on: udp://{address}");
+    Ok(socket)
+}
+
+#[cfg(unix)]
+fn bind_unix(path: &Path) -> Result<(UnixListener, PathBuf), ServerError> {
+    // A socket left behind by a previous run would make binding fail
+    if let Ok(metadata) = std::fs::symlink_metadata(path) {
+        use std::os::unix::fs::FileTypeExt;
+        if metadata.file_type().is_socket() {
+            let _ = std::fs::remove_file(path);
+        }
+    }
+
+    let listener = UnixListener::bind(path).map_err(|error| bind_error(path.display(), error))?;
+
+    // Only the user running the server may connect, as the socket is not wrapped in TLS
+    {
+        use std::os::unix::fs::PermissionsExt;
+        std::fs::set_permissions(path, std::fs::Permissions::from_mode(0o600))
+            .map_err(|error| bind_error(path.display(), error))?;
+    }
+    Ok((listener, path.to_path_buf()))
+}
+
+// Stream clients must know whether to start a TLS handshake, as nothing is negotiated
+fn stream_kind(acceptor: &Option<TlsAcceptor>) -> &'static str {
+    if acceptor.is_some() {
+        "TLS"
+    } else {
+        "plaintext"
+    }
+}
+
+fn bind_error(address: impl Display, error: io::Error) -> ServerError {
+    ServerError::Bind {
+        address: address.to_string(),
+        error: error.to_string(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        super::{
+            tests::{connect, next, response, TIMEOUT},
+            ServerOptions, TlsConfig,
+        },
+        announced_port, Server, ServerError,
+    };
+    use rpc::{RpcMessage, RpcResult};
+    use std::net::SocketAddr;
+    use tokio::time::Instant;
+
+    fn local() -> SocketAddr {
+        "127.0.0.1:0".parse().unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_bind_error() {
+        let taken = std::net::TcpListener::bind(local()).unwrap();
+        let address = taken.local_addr().unwrap();
+
+        let result = Server::bind(local()).also_bind(address).start().await;
+        assert!(
+            matches!(
+                result.as_ref().err(),
+                Some(ServerError::Bind { address: failed, .. }) if *failed == address.to_string()
+            ),
+            "{:?}",
+            result.err()
+        );
+    }
+
+    #[tokio::test]
+    async fn test_addresses() {
+        let options = ServerOptions {
+            tcp_port: Some(0),
+            ..Default::default()
+        };
+        let server = Server::bind(local())
+            .also_bind(local())
+            .with_options(options)
+            .start()
+            .await
+            .unwrap();
+
+        let addresses = server.addresses();
+        assert_eq!(addresses.len(), 2);
+        assert_eq!(server.address(), addresses[0]);
+        assert!(addresses.iter().all(|address| address.port() != 0));
+        assert_ne!(addresses[0].port(), addresses[1].port());
+
+        // Both websocket addresses are on the same interface, which gets one TCP listener
+        assert_eq!(server.tcp_addresses().len(), 1);
+        assert_eq!(server.tcp_address(), Some(server.tcp_addresses()[0]));
+        server.shutdown().await;
+    }
+
+    #[tokio::test]
+    async fn test_sessions() {
+        let server = Server::bind(local()).start().await.unwrap();
+        assert!(server.sessions().is_empty());
+
+        let mut client = connect(server.address()).await;
+        let client_id = match response(&mut client).await.result {
+            RpcResult::Success(RpcMessage::ClientId { id }) => id,
+            result => panic!("Expected a client id, got {result:?}"),
+        };
+        let sessions = server.sessions();
+        assert_eq!(sessions.len(), 1);
+        assert_eq!(sessions[0].client_id, client_id);
+        assert_eq!(sessions[0].subject, None);
+        assert_eq!(server.connections(), 1);
+
+        client.close(None).await.unwrap();
+        let deadline = Instant::now() + TIMEOUT;
+        while server.connections() > 0 {
+            assert!(Instant::now() < deadline, "The session was never closed");
+            tokio::time::sleep(std::time::Duration::from_millis(10)).await;
+        }
+        assert!(server.sessions().is_empty());
+        server.shutdown().await;
+    }
+
+    #[tokio::test]
+    async fn test_shutdown() {
+        let server = Server::bind(local()).start().await.unwrap();
+        let address = server.address();
+        let mut client = connect(address).await;
+        response(&mut client).await;
+
+        server.shutdown().await;
+        assert!(matches!(
+            next(&mut client).await,
+            Some(tokio_tungstenite::tungstenite::Message::Close(_))
+        ));
+        assert!(tokio::net::TcpStream::connect(address).await.is_err());
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn test_unix_socket_plaintext() {
+        use rpc::{read_frame, Codec, Response};
+        use std::os::unix::{fs::PermissionsExt, net::UnixStream};
+
+        let path = std::env::temp_dir().join(format!("server-test-{}.sock", std::process::id()));
+        let options = ServerOptions {
+            tls: Some(TlsConfig::SelfSigned {
+                hostnames: vec!["localhost".to_string()],
+            }),
+            unix_socket: Some(path.clone()),
+            ..Default::default()
+        };
+        let server = Server::bind(local())
+            .with_options(options)
+            .start()
+            .await
+            .unwrap();
+        let mode = std::fs::metadata(&path).unwrap().permissions().mode();
+        assert_eq!(mode & 0o777, 0o600);
+
+        // Local clients skip the TLS handshake the websocket listener requires
+        let socket = path.clone();
+        let response = tokio::task::spawn_blocking(move || {
+            let mut stream = UnixStream::connect(socket).unwrap();
+            let frame = read_frame(&mut stream, usize::MAX).unwrap().unwrap();
+            bincode::deserialize::<Response>(&Codec::decode(&frame).unwrap()).unwrap()
+        })
+        .await
+        .unwrap();
+        assert!(matches!(
+            response.result,
+            RpcResult::Success(RpcMessage::ClientId { .. })
+        ));
+        server.shutdown().await;
+        assert!(!path.exists());
+    }
+
+    #[test]
+    fn test_announced_port() {
+        let listeners = |addresses: &[&str]| {
+            addresses
+                .iter()
+                .map(|address| ((), address.parse::<SocketAddr>().unwrap()))
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(
+            announced_port(&listeners(&["127.0.0.1:9000", "192.168.1.2:9001"])),
+            9001
+        );
+        assert_eq!(
+            announced_port(&listeners(&["[::1]:9000", "[::]:9001", "0.0.0.0:9002"])),
+            9001
+        );
+        assert_eq!(
+            announced_port(&listeners(&["[::1]:9000", "0.0.0.0:9002"])),
+            9002
+        );
+
+        // Still answers probes sent to this machine
+        assert_eq!(announced_port(&listeners(&["127.0.0.1:9000"])), 9000);
+    }
+}
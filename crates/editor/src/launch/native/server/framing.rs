@@ -0,0 +1,69 @@
+use futures_util::{sink, stream, Sink, Stream};
+use std::io;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio_tungstenite::tungstenite::Message as WebsocketMessage;
+
+/// Reads the length prefixed frames `rpc::StreamTransport` sends as binary messages,
+/// so plain TCP and Unix domain socket clients are served like websocket ones
+pub(super) fn frames<R>(
+    reader: R,
+    max_frame_size: usize,
+) -> impl Stream<Item = io::Result<WebsocketMessage>>
+where
+    R: AsyncRead + Unpin,
+{
+    stream::unfold(reader, move |mut reader| async move {
+        match read_frame(&mut reader, max_frame_size).await {
+            Ok(Some(frame)) => Some((Ok(WebsocketMessage::Binary(frame)), reader)),
+            Ok(None) => None,
+            Err(error) => Some((Err(error), reader)),
+        }
+    })
+}
+
+/// Writes binary messages as length prefixed frames. Byte streams have no pings
+/// and carry no JSON-RPC, so any other message is dropped.
+pub(super) fn frame_sink<W>(writer: W) -> impl Sink<WebsocketMessage, Error = io::Error>
+where
+    W: AsyncWrite + Unpin,
+{
+    sink::unfold(writer, |mut writer, message: WebsocketMessage| async move {
+        if let WebsocketMessage::Binary(frame) = message {
+            let length = u32::try_from(frame.len()).map_err(|_| {
+                io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    "The frame is too large for its length prefix",
+                )
+            })?;
+            writer.write_u32(length).await?;
+            writer.write_all(&frame).await?;
+            writer.flush().await?;
+        }
+        Ok::<_, io::Error>(writer)
+    })
+}
+
+async fn read_frame<R>(reader: &mut R, max_frame_size: usize) -> io::Result<Option<Vec<u8>>>
+where
+    R: AsyncRead + Unpin,
+{
+    let length = match reader.read_u32().await {
+        Ok(length) => length as usize,
+        Err(error) if error.kind() == io::ErrorKind::UnexpectedEof => return Ok(None),
+        Err(error) => return Err(error),
+    };
+    if length > max_frame_size {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("A frame of {length} bytes is larger than the limit of {max_frame_size} bytes"),
+        ));
+    }
+
+    // The buffer grows as bytes arrive, so a prefix alone can't claim a large allocation
+    let mut frame = Vec::new();
+    reader.take(length as u64).read_to_end(&mut frame).await?;
+    if frame.len() < length {
+        return Err(io::ErrorKind::UnexpectedEof.into());
+    }
+    Ok(Some(frame))
+}
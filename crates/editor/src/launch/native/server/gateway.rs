@@ -0,0 +1,485 @@
+use super::{
+    audit::AuditRecord,
+    http::{authenticate, status},
+    lock, SharedServerState, DRAIN_TIMEOUT,
+};
+use hyper::{body::HttpBody, header, Body, Request, Response, StatusCode};
+use log::{error, info, warn};
+use rpc::{
+    Authentication, BackendConnection, Command, Handled, Id, Message, RpcResult, TokenBucket,
+    UNREAD,
+};
+use serde::Serialize;
+use std::{
+    collections::HashMap,
+    net::SocketAddr,
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+use tokio::sync::watch;
+use uuid::Uuid;
+
+/// Names the retries of a command, which are answered with its first result
+const IDEMPOTENCY_KEY: &str = "idempotency-key";
+
+/// What the gateway keeps across requests, since each of them gets a connection of its own
+pub(super) struct Gateway {
+    // Rate limits apply per peer address, as a client may send each request on a new connection
+    buckets: Mutex<HashMap<String, (TokenBucket, Instant)>>,
+
+    // The connections of the commands yet to finish, whether or not their client still waits
+    running: watch::Sender<HashMap<Id, BackendConnection>>,
+}
+
+impl Default for Gateway {
+    fn default() -> Self {
+        Self {
+            buckets: Mutex::default(),
+            running: watch::channel(HashMap::new()).0,
+        }
+    }
+}
+
+impl Gateway {
+    /// Lets the running commands finish, cancelling those still running after the drain timeout
+    pub(super) async fn drain(&self) {
+        let mut running = self.running.subscribe();
+        if tokio::time::timeout(
+            DRAIN_TIMEOUT,
+            running.wait_for(|running| running.is_empty()),
+        )
+        .await
+        .is_err()
+        {
+            warn!("Cancelling the HTTP commands still running after {DRAIN_TIMEOUT:?}");
+            let connections = self.running.borrow().values().cloned().collect::<Vec<_>>();
+            connections.iter().for_each(BackendConnection::close);
+        }
+    }
+
+    fn take(&self, peer: &str, state: &SharedServerState) -> Result<(), rpc::Error> {
+        let Some(limit) = state.backend.limits().rate_limit else {
+            return Ok(());
+        };
+        let now = Instant::now();
+
+        // A bucket left alone long enough to refill is no different from a new one
+        let refill = match limit.per_second {
+            0 => Duration::MAX,
+            per_second => Duration::from_secs_f64(limit.burst as f64 / per_second as f64),
+        };
+        let mut buckets = lock(&self.buckets);
+        buckets.retain(|_, (_, used_at)| now.saturating_duration_since(*used_at) < refill);
+        let (bucket, used_at) = buckets
+            .entry(host(peer))
+            .or_insert_with(|| (TokenBucket::new(limit, now), now));
+        *used_at = now;
+        bucket.take(now)
+    }
+}
+
+/// Runs a `Command` POSTed as JSON with the executor websocket clients share, answering with
+/// the `RpcResult` as JSON. Each request gets a connection of its own, closed once it is answered.
+/// Requests an authenticated client sends again with the same `Idempotency-Key` header are
+/// answered with the first result. Anonymous clients sharing an address can't be told apart,
+/// so their keys only apply within the request.
+pub(super) async fn execute(
+    request: Request<Body>,
+    peer: &str,
+    state: &SharedServerState,
+) -> Response<Body> {
+    let (code, result) = match receive(request, peer, state).await {
+        Ok(Received {
+            command,
+            authentication,
+            idempotency_key,
+        }) => run(command, authentication, idempotency_key, peer, state).await,
+        Err((code, error)) => {
+            // Requests refused before running have no session, and are recorded like unread messages
+            let result = RpcResult::Error(error);
+            if let Some(audit) = &state.audit {
+                audit.record(&AuditRecord::new(peer, "", UNREAD, Duration::ZERO, &result));
+            }
+            (code, result)
+        }
+    };
+    state.metrics.answered(&result);
+
+    let mut response = json(code, &result);
+    if let RpcResult::Error(rpc::Error::RateLimited { retry_after_ms }) = result {
+        let retry_after = retry_after_ms.saturating_add(999) / 1000;
+        response
+            .headers_mut()
+            .insert(header::RETRY_AFTER, retry_after.into());
+    }
+    response
+}
+
+/// A command that may be run, along with who sent it
+struct Received {
+    command: Command,
+    authentication: Option<Authentication>,
+    idempotency_key: Option<Id>,
+}
+
+// Requests that can't reach the executor are refused with a matching HTTP status,
+// while whatever the executor answers, errors included, is a successful exchange
+async fn receive(
+    request: Request<Body>,
+    peer: &str,
+    state: &SharedServerState,
+) -> Result<Received, (StatusCode, rpc::Error)> {
+    let authentication =
+        authenticate(&request, state).map_err(|error| (StatusCode::UNAUTHORIZED, error))?;
+    state
+        .gateway
+        .take(peer, state)
+        .map_err(|error| (StatusCode::TOO_MANY_REQUESTS, error))?;
+    let idempotency_key = request
+        .headers()
+        .get(IDEMPOTENCY_KEY)
+        .and_then(|value| value.to_str().ok())
+        .map(str::to_string);
+    let command = read_command(request, state).await?;
+    info!("[HTTP ->]: {command:#?}");
+
+    match command {
+        // These only make sense on a connection that outlives the request
+        Command::Cancel { .. }
+        | Command::TransferChunk { .. }
+        | Command::ResumeTransfer { .. }
+        | Command::Negotiate { .. }
+        | Command::Authenticate { .. }
+        | Command::Heartbeat { .. }
+        | Command::JoinRoom { .. }
+        | Command::LeaveRoom { .. }
+        | Command::Broadcast { .. } => {
+            Err((StatusCode::BAD_REQUEST, rpc::Error::UnrecognizedMessage))
+        }
+        command => Ok(Received {
+            command,
+            authentication,
+            idempotency_key,
+        }),
+    }
+}
+
+// Bodies are read up to the payload limit, so a large one is refused before it is all buffered
+async fn read_command(
+    request: Request<Body>,
+    state: &SharedServerState,
+) -> Result<Command, (StatusCode, rpc::Error)> {
+    let limit = state.backend.limits().max_payload_size;
+    let mut body = request.into_body();
+    let mut bytes = Vec::new();
+    while let Some(chunk) = body.data().await {
+        let chunk = chunk.map_err(|error| {
+            let error = rpc::Error::MessageDeserialization {
+                error: error.to_string(),
+            };
+            (StatusCode::BAD_REQUEST, error)
+        })?;
+        let size = (bytes.len() + chunk.len()) as u64;
+        if size > limit {
+            let error = rpc::Error::PayloadTooLarge { size, limit };
+            return Err((StatusCode::PAYLOAD_TOO_LARGE, error));
+        }
+        bytes.extend_from_slice(&chunk);
+    }
+
+    serde_json::from_slice(&bytes).map_err(|error| {
+        let error = rpc::Error::MessageDeserialization {
+            error: error.to_string(),
+        };
+        (StatusCode::BAD_REQUEST, error)
+    })
+}
+
+// Commands are dispatched on a connection of their own and run on the blocking pool, like those
+// sent over a websocket. The status is that of the exchange, so whatever the backend answers,
+// errors included, is a success.
+async fn run(
+    command: Command,
+    authentication: Option<Authentication>,
+    idempotency_key: Option<Id>,
+    peer: &str,
+    state: &SharedServerState,
+) -> (StatusCode, RpcResult) {
+    let connection = state.backend.connect_once(peer, authentication);
+
+    // Dropped along with this future when the client disconnects before being answered
+    let _disconnected = Closing(connection.clone());
+
+    let message = Message::new(Uuid::new_v4().to_string(), command);
+    let message = match idempotency_key {
+        Some(key) => message.with_idempotency_key(key),
+        None => message,
+    };
+    let job = match connection.handle(message) {
+        Handled::Queued(job) => job,
+        Handled::Answered(result @ RpcResult::Error(rpc::Error::IdempotencyConflict { .. })) => {
+            return (StatusCode::CONFLICT, result)
+        }
+        Handled::Answered(result) => return (StatusCode::OK, result),
+
+        // Cancellations are refused before they get here
+        Handled::Unanswered => {
+            let error = rpc::Error::UnrecognizedMessage;
+            return (StatusCode::BAD_REQUEST, RpcResult::Error(error));
+        }
+    };
+
+    let client_id = connection.client_id().to_string();
+    state.gateway.running.send_modify(|running| {
+        running.insert(client_id.to_string(), connection.clone());
+    });
+    let finished = Finished {
+        state: state.clone(),
+        client_id,
+    };
+    let task = tokio::task::spawn_blocking(move || {
+        let _finished = finished;
+        connection.execute(job).result
+    });
+
+    match task.await {
+        Ok(result) => (StatusCode::OK, result),
+        Err(error) => {
+            error!("Failed to execute command: {error}");
+            let error = rpc::Error::Internal {
+                error: error.to_string(),
+            };
+            (StatusCode::INTERNAL_SERVER_ERROR, RpcResult::Error(error))
+        }
+    }
+}
+
+// Ports differ between the connections of a client, so only the host identifies it
+fn host(peer: &str) -> String {
+    peer.parse::<SocketAddr>()
+        .map(|address| address.ip().to_string())
+        .unwrap_or_else(|_| peer.to_string())
+}
+
+/// Closes a request's connection, cancelling its command if it is still running
+struct Closing(BackendConnection);
+
+impl Drop for Closing {
+    fn drop(&mut self) {
+        self.0.close();
+    }
+}
+
+/// Tells a shutdown that a command has finished, also when its handler panics
+struct Finished {
+    state: SharedServerState,
+    client_id: Id,
+}
+
+impl Drop for Finished {
+    fn drop(&mut self) {
+        self.state.gateway.running.send_modify(|running| {
+            running.remove(&self.client_id);
+        });
+    }
+}
+
+fn json<T: Serialize>(code: StatusCode, value: &T) -> Response<Body> {
+    match serde_json::to_vec(value) {
+        Ok(body) => Response::builder()
+            .status(code)
+            .header(header::CONTENT_TYPE, "application/json")
+            .body(Body::from(body))
+            .unwrap_or_else(|_| status(StatusCode::INTERNAL_SERVER_ERROR)),
+        Err(error) => {
+            error!("Failed to serialize HTTP response: {error}");
+            status(StatusCode::INTERNAL_SERVER_ERROR)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::{Server, ServerHandle, ServerOptions};
+    use rpc::{
+        AuthConfig, CallContext, Command, Limits, PayloadJson, RateLimit, RpcExecutor, RpcService,
+    };
+    use tokio::{
+        io::{AsyncReadExt, AsyncWriteExt},
+        net::TcpStream,
+    };
+
+    struct Counter {
+        calls: u32,
+    }
+
+    impl RpcService for Counter {
+        fn name(&self) -> &'static str {
+            "counter"
+        }
+
+        fn call(
+            &mut self,
+            _context: &mut CallContext<'_>,
+            _payload: &str,
+        ) -> Result<PayloadJson, String> {
+            self.calls += 1;
+            Ok(self.calls.to_string())
+        }
+    }
+
+    async fn start(options: ServerOptions) -> ServerHandle {
+        Server::bind("127.0.0.1:0".parse().unwrap())
+            .with_options(options)
+            .with_executor(RpcExecutor::default().with_service(Counter { calls: 0 }))
+            .start()
+            .await
+            .unwrap()
+    }
+
+    fn count() -> String {
+        let command = Command::Service {
+            service: "counter".to_string(),
+            call_id: "call".to_string(),
+            payload: "{}".to_string(),
+        };
+        serde_json::to_string(&command).unwrap()
+    }
+
+    // The whole response to a POST on `/rpc`, on a connection closed after it
+    async fn post(server: &ServerHandle, body: &str, headers: &str) -> String {
+        let mut stream = TcpStream::connect(server.address()).await.unwrap();
+        let request = format!(
+            "POST /rpc HTTP/1.1\r\nHost: test\r\nConnection: close\r\n\
+             Content-Length: {}\r\n{headers}\r\n{body}",
+            body.len()
+        );
+        stream.write_all(request.as_bytes()).await.unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).await.unwrap();
+        response
+    }
+
+    fn body(response: &str) -> &str {
+        response.split_once("\r\n\r\n").unwrap().1
+    }
+
+    #[tokio::test]
+    async fn test_status_codes() {
+        let options = ServerOptions {
+            limits: Limits {
+                max_payload_size: 256,
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+        let server = start(options).await;
+
+        let response = post(&server, &count(), "").await;
+        assert!(response.starts_with("HTTP/1.1 200"), "{response}");
+        assert!(body(&response).contains("Success"), "{response}");
+
+        let response = post(&server, "{", "").await;
+        assert!(response.starts_with("HTTP/1.1 400"), "{response}");
+        assert!(body(&response).contains("MessageDeserialization"));
+
+        // Control commands need a connection that outlives the request
+        let heartbeat = serde_json::to_string(&Command::Heartbeat { sequence: 0 }).unwrap();
+        let response = post(&server, &heartbeat, "").await;
+        assert!(response.starts_with("HTTP/1.1 400"), "{response}");
+        assert!(body(&response).contains("UnrecognizedMessage"));
+
+        let response = post(&server, &" ".repeat(1024), "").await;
+        assert!(response.starts_with("HTTP/1.1 413"), "{response}");
+        assert!(body(&response).contains("PayloadTooLarge"));
+        server.shutdown().await;
+    }
+
+    #[tokio::test]
+    async fn test_unauthenticated() {
+        let options = ServerOptions {
+            auth: AuthConfig {
+                tokens: vec!["secret".to_string()],
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+        let server = start(options).await;
+
+        let response = post(&server, &count(), "").await;
+        assert!(response.starts_with("HTTP/1.1 401"), "{response}");
+        assert!(body(&response).contains("Unauthenticated"));
+
+        let response = post(&server, &count(), "Authorization: Bearer wrong\r\n").await;
+        assert!(response.starts_with("HTTP/1.1 401"), "{response}");
+
+        let response = post(&server, &count(), "Authorization: Bearer secret\r\n").await;
+        assert!(response.starts_with("HTTP/1.1 200"), "{response}");
+        server.shutdown().await;
+    }
+
+    #[tokio::test]
+    async fn test_rate_limit() {
+        let options = ServerOptions {
+            limits: Limits {
+                rate_limit: Some(RateLimit {
+                    per_second: 1,
+                    burst: 2,
+                }),
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+        let server = start(options).await;
+
+        // Each request comes on a new connection, yet they share the peer's bucket
+        for _ in 0..2 {
+            let response = post(&server, &count(), "").await;
+            assert!(response.starts_with("HTTP/1.1 200"), "{response}");
+        }
+        let response = post(&server, &count(), "").await;
+        assert!(response.starts_with("HTTP/1.1 429"), "{response}");
+        assert!(
+            response.to_lowercase().contains("retry-after: 1"),
+            "{response}"
+        );
+        assert!(body(&response).contains("RateLimited"));
+        server.shutdown().await;
+    }
+
+    #[tokio::test]
+    async fn test_idempotency_key() {
+        let options = ServerOptions {
+            auth: AuthConfig {
+                tokens: vec!["secret".to_string()],
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+        let server = start(options).await;
+        let headers =
+            |key: &str| format!("Authorization: Bearer secret\r\nIdempotency-Key: {key}\r\n");
+
+        let first = post(&server, &count(), &headers("first")).await;
+        let retry = post(&server, &count(), &headers("first")).await;
+        assert!(retry.starts_with("HTTP/1.1 200"), "{retry}");
+        assert_eq!(body(&first), body(&retry));
+
+        // Only retries are answered from the cache
+        let other = post(&server, &count(), &headers("second")).await;
+        assert_ne!(body(&first), body(&other));
+        server.shutdown().await;
+    }
+
+    #[tokio::test]
+    async fn test_anonymous_idempotency_key() {
+        let server = start(ServerOptions::default()).await;
+
+        // Clients sharing an address can't be told apart, so nothing is answered from the cache
+        let first = post(&server, &count(), "Idempotency-Key: first\r\n").await;
+        let retry = post(&server, &count(), "Idempotency-Key: first\r\n").await;
+        assert!(retry.starts_with("HTTP/1.1 200"), "{retry}");
+        assert_ne!(body(&first), body(&retry));
+        server.shutdown().await;
+    }
+}
@@ -0,0 +1,357 @@
+use super::{accept_websocket, gateway, SharedServerState};
+use crate::app::BACKEND_MARKER;
+use hyper::{
+    header::{self, HeaderValue},
+    server::conn::Http,
+    service::service_fn,
+    upgrade::OnUpgrade,
+    Body, Method, Request, Response, StatusCode,
+};
+use log::error;
+use rpc::{Authentication, Credential};
+use std::{borrow::Cow, convert::Infallible, sync::Arc};
+use tokio::{
+    io::{AsyncRead, AsyncWrite},
+    sync::mpsc,
+};
+use tokio_tungstenite::tungstenite::handshake::derive_accept_key;
+
+/// Looks up a static file by its path relative to the site root
+pub(crate) type Assets = Arc<dyn Fn(&str) -> Option<Cow<'static, [u8]>> + Send + Sync>;
+
+/// The only websocket version tungstenite speaks
+const WEBSOCKET_VERSION: &str = "13";
+
+/// Serves one HTTP connection. A websocket upgrade, on `/ws` or on any other path for clients
+/// that predate it, turns the connection into an RPC one. Commands POSTed to `/rpc` run one at
+/// a time, `/healthz` and `/metrics` are for monitoring, the latter with the same credential
+/// as `/rpc`, and other requests get the server's assets.
+pub(super) async fn serve<S>(stream: S, peer: String, state: SharedServerState)
+where
+    S: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+{
+    let (upgrades, mut upgraded) = mpsc::unbounded_channel();
+    let service_state = state.clone();
+    let service_peer = peer.clone();
+    let service = service_fn(move |request| {
+        let state = service_state.clone();
+        let peer = service_peer.clone();
+        let upgrades = upgrades.clone();
+        async move { Ok::<_, Infallible>(respond(request, &peer, &state, &upgrades).await) }
+    });
+
+    // Resolves once the client disconnects, or once the upgrade response is sent
+    let connection = Http::new()
+        .http1_only(true)
+        .serve_connection(stream, service)
+        .with_upgrades();
+    tokio::pin!(connection);
+    let result = {
+        let stopping = state.stopping();
+        tokio::pin!(stopping);
+        tokio::select! {
+            result = &mut connection => result,
+            _ = &mut stopping => {
+                // Idle keep-alive connections close right away, busy ones once they have answered
+                connection.as_mut().graceful_shutdown();
+                connection.await
+            }
+        }
+    };
+    if let Err(error) = result {
+        error!("Failed to serve HTTP to {peer}: {error}");
+        return;
+    }
+
+    if let Ok(upgrade) = upgraded.try_recv() {
+        match upgrade.await {
+            Ok(stream) => accept_websocket(stream, peer, state).await,
+            Err(error) => error!("The websocket handshake with {peer} failed: {error}"),
+        }
+    }
+}
+
+async fn respond(
+    mut request: Request<Body>,
+    peer: &str,
+    state: &SharedServerState,
+    upgrades: &mpsc::UnboundedSender<OnUpgrade>,
+) -> Response<Body> {
+    if header_is(&request, header::UPGRADE, "websocket") {
+        return match websocket_accept(&request) {
+            Some(accept) => {
+                let _ = upgrades.send(hyper::upgrade::on(&mut request));
+                Response::builder()
+                    .status(StatusCode::SWITCHING_PROTOCOLS)
+                    .header(header::CONNECTION, "upgrade")
+                    .header(header::UPGRADE, "websocket")
+                    .header(header::SEC_WEBSOCKET_ACCEPT, accept)
+                    .body(Body::empty())
+            }
+            None => Response::builder()
+                .status(StatusCode::BAD_REQUEST)
+                .header(header::SEC_WEBSOCKET_VERSION, WEBSOCKET_VERSION)
+                .body(Body::empty()),
+        }
+        .unwrap_or_else(|_| status(StatusCode::INTERNAL_SERVER_ERROR));
+    }
+
+    match (
+        request.method(),
+        request.uri().path(),
+        state.assets.as_ref(),
+    ) {
+        (&Method::POST, "/rpc", _) => gateway::execute(request, peer, state).await,
+        (&Method::GET, "/healthz", _) => health(state),
+        (&Method::GET, "/metrics", _) => metrics(&request, state),
+        (&Method::GET, path, Some(assets)) => serve_asset(assets, path),
+        _ => status(StatusCode::NOT_FOUND),
+    }
+}
+
+// The handshake is only accepted if the client asked for it properly
+fn websocket_accept(request: &Request<Body>) -> Option<String> {
+    let headers = request.headers();
+    let upgrades = request.method() == Method::GET
+        && header_is(request, header::CONNECTION, "upgrade")
+        && headers.get(header::SEC_WEBSOCKET_VERSION)
+            == Some(&HeaderValue::from_static(WEBSOCKET_VERSION));
+    let key = headers.get(header::SEC_WEBSOCKET_KEY)?;
+    upgrades.then(|| derive_accept_key(key.as_bytes()))
+}
+
+// Header values such as `Connection: keep-alive, Upgrade` hold comma separated tokens
+fn header_is(request: &Request<Body>, name: header::HeaderName, token: &str) -> bool {
+    request
+        .headers()
+        .get_all(name)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .any(|value| value.trim().eq_ignore_ascii_case(token))
+}
+
+// Load balancers stop sending clients once the server starts shutting down
+fn health(state: &SharedServerState) -> Response<Body> {
+    let (status, body) = if *state.shutdown.borrow() {
+        (StatusCode::SERVICE_UNAVAILABLE, "shutting down")
+    } else {
+        (StatusCode::OK, "ok")
+    };
+    let mut response = Response::new(Body::from(body));
+    *response.status_mut() = status;
+    response
+}
+
+// Metrics name the commands clients run, so they need the same credential as the commands
+fn metrics(request: &Request<Body>, state: &SharedServerState) -> Response<Body> {
+    if authenticate(request, state).is_err() {
+        return status(StatusCode::UNAUTHORIZED);
+    }
+    let connections = state.backend.connections();
+    Response::builder()
+        .header(header::CONTENT_TYPE, "text/plain; version=0.0.4")
+        .body(Body::from(state.metrics.render(connections)))
+        .unwrap_or_else(|_| status(StatusCode::INTERNAL_SERVER_ERROR))
+}
+
+// Servers that require authentication take the credential text as a bearer token
+pub(super) fn authenticate(
+    request: &Request<Body>,
+    state: &SharedServerState,
+) -> Result<Option<Authentication>, rpc::Error> {
+    if !state.backend.auth().is_enabled() {
+        return Ok(None);
+    }
+    let credential = request
+        .headers()
+        .get(header::AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Bearer "))
+        .map(Credential::from_text)
+        .ok_or(rpc::Error::Unauthenticated)?;
+    let authentication = state.backend.auth().verify(&credential)?;
+    authentication.check_expiry()?;
+    Ok(Some(authentication))
+}
+
+// Unknown paths get the index, so the frontend can route them itself
+fn serve_asset(assets: &Assets, path: &str) -> Response<Body> {
+    let path = match path.trim_start_matches('/') {
+        "" => "index.html",
+        path => path,
+    };
+    let (path, data) = match assets(path) {
+        Some(data) => (path, data),
+        None => match assets("index.html") {
+            Some(data) => ("index.html", data),
+            None => return status(StatusCode::NOT_FOUND),
+        },
+    };
+
+    let data = match path {
+        "index.html" => mark_backend(&data).into(),
+        _ => data,
+    };
+
+    let mime = mime_guess::from_path(path).first_or_octet_stream();
+    Response::builder()
+        .header(header::CONTENT_TYPE, mime.as_ref())
+        .body(Body::from(data))
+        .unwrap_or_else(|_| status(StatusCode::INTERNAL_SERVER_ERROR))
+}
+
+// Tells the frontend it was served by its backend, which it then connects to on `/ws`
+fn mark_backend(page: &[u8]) -> Vec<u8> {
+    let marker = format!("<meta name=\"{BACKEND_MARKER}\" content=\"/ws\">");
+    let head = b"<head>";
+    let at = page
+        .windows(head.len())
+        .position(|window| window == head)
+        .map_or(0, |position| position + head.len());
+    [&page[..at], marker.as_bytes(), &page[at..]].concat()
+}
+
+pub(super) fn status(status: StatusCode) -> Response<Body> {
+    let mut response = Response::new(Body::empty());
+    *response.status_mut() = status;
+    response
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        super::{Server, ServerHandle, ServerOptions},
+        websocket_accept,
+    };
+    use hyper::{Body, Request};
+    use rpc::AuthConfig;
+    use std::borrow::Cow;
+    use tokio::{
+        io::{AsyncReadExt, AsyncWriteExt},
+        net::TcpStream,
+    };
+
+    async fn start(auth: AuthConfig) -> ServerHandle {
+        let options = ServerOptions {
+            auth,
+            ..Default::default()
+        };
+        Server::bind("127.0.0.1:0".parse().unwrap())
+            .with_options(options)
+            .start()
+            .await
+            .unwrap()
+    }
+
+    // The whole response of a request that asks for the connection to be closed after it
+    async fn get(server: &ServerHandle, path: &str, headers: &str) -> String {
+        let mut stream = TcpStream::connect(server.address()).await.unwrap();
+        let request =
+            format!("GET {path} HTTP/1.1\r\nHost: test\r\nConnection: close\r\n{headers}\r\n");
+        stream.write_all(request.as_bytes()).await.unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).await.unwrap();
+        response
+    }
+
+    #[tokio::test]
+    async fn test_metrics_authentication() {
+        let server = start(AuthConfig {
+            tokens: vec!["secret".to_string()],
+            signing_key: None,
+        })
+        .await;
+
+        let refused = get(&server, "/metrics", "").await;
+        assert!(refused.starts_with("HTTP/1.1 401"), "{refused}");
+        let refused = get(&server, "/metrics", "Authorization: Bearer wrong\r\n").await;
+        assert!(refused.starts_with("HTTP/1.1 401"), "{refused}");
+
+        let metrics = get(&server, "/metrics", "Authorization: Bearer secret\r\n").await;
+        assert!(metrics.starts_with("HTTP/1.1 200"), "{metrics}");
+        assert!(metrics.contains("editor_connections 0"));
+
+        // Health checks come from load balancers, which have no credential
+        let health = get(&server, "/healthz", "").await;
+        assert!(health.starts_with("HTTP/1.1 200"), "{health}");
+        server.shutdown().await;
+    }
+
+    #[tokio::test]
+    async fn test_metrics_without_authentication() {
+        let server = start(AuthConfig::default()).await;
+        let metrics = get(&server, "/metrics", "").await;
+        assert!(metrics.starts_with("HTTP/1.1 200"), "{metrics}");
+        server.shutdown().await;
+    }
+
+    fn upgrade(method: &str, connection: &str, version: &str) -> Request<Body> {
+        Request::builder()
+            .method(method)
+            .uri("/ws")
+            .header("Upgrade", "websocket")
+            .header("Connection", connection)
+            .header("Sec-WebSocket-Version", version)
+            .header("Sec-WebSocket-Key", "dGhlIHNhbXBsZSBub25jZQ==")
+            .body(Body::empty())
+            .unwrap()
+    }
+
+    #[test]
+    fn test_websocket_accept() {
+        // The example handshake of RFC 6455
+        let accept = Some("s3pPLMBiTxaQ9kYGzzhZRbK+xOo=".to_string());
+        assert_eq!(websocket_accept(&upgrade("GET", "Upgrade", "13")), accept);
+        assert_eq!(
+            websocket_accept(&upgrade("GET", "keep-alive, Upgrade", "13")),
+            accept
+        );
+
+        assert_eq!(websocket_accept(&upgrade("POST", "Upgrade", "13")), None);
+        assert_eq!(websocket_accept(&upgrade("GET", "keep-alive", "13")), None);
+        assert_eq!(websocket_accept(&upgrade("GET", "Upgrade", "8")), None);
+        let mut request = upgrade("GET", "Upgrade", "13");
+        request.headers_mut().remove("Sec-WebSocket-Key");
+        assert_eq!(websocket_accept(&request), None);
+    }
+
+    #[tokio::test]
+    async fn test_refused_upgrade() {
+        let server = start(AuthConfig::default()).await;
+        let headers = "Upgrade: websocket\r\nConnection: Upgrade\r\n\
+            Sec-WebSocket-Key: dGhlIHNhbXBsZSBub25jZQ==\r\nSec-WebSocket-Version: 8\r\n";
+        let refused = get(&server, "/ws", headers).await;
+        assert!(refused.starts_with("HTTP/1.1 400"), "{refused}");
+        assert!(refused.contains("sec-websocket-version: 13"), "{refused}");
+        server.shutdown().await;
+    }
+
+    #[tokio::test]
+    async fn test_backend_marker() {
+        let server = Server::bind("127.0.0.1:0".parse().unwrap())
+            .with_assets(|path| match path {
+                "index.html" => Some(Cow::Borrowed(b"<html><head></head></html>".as_slice())),
+                "app.js" => Some(Cow::Borrowed(b"<head>".as_slice())),
+                _ => None,
+            })
+            .start()
+            .await
+            .unwrap();
+
+        // Unknown paths get the index too, so every page the frontend routes is marked
+        for path in ["/", "/index.html", "/projects/1"] {
+            let page = get(&server, path, "").await;
+            assert!(
+                page.ends_with(
+                    "<html><head><meta name=\"editor-backend\" content=\"/ws\"></head></html>"
+                ),
+                "{page}"
+            );
+        }
+        assert!(get(&server, "/app.js", "")
+            .await
+            .ends_with("\r\n\r\n<head>"));
+        server.shutdown().await;
+    }
+}
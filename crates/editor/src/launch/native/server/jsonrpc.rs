@@ -0,0 +1,145 @@
+use super::{start_command, Connection};
+use futures_util::future::join_all;
+use log::{error, info};
+use rpc::{
+    Handled, Id, JsonRpcError, JsonRpcMessage, JsonRpcRequest, JsonRpcResponse, Message, Response,
+    RpcMessage, RpcResult,
+};
+use serde::Serialize;
+use serde_json::Value;
+use tokio::task::JoinHandle;
+use tokio_tungstenite::tungstenite::Message as WebsocketMessage;
+
+/// A JSON-RPC request that has either been answered already
+/// or is waiting on a command running in the background
+enum Call {
+    Done(Option<JsonRpcResponse>),
+    Running {
+        id: Option<Value>,
+        task: JoinHandle<Response>,
+    },
+}
+
+impl Call {
+    async fn finish(self, connection: &Connection) -> Option<JsonRpcResponse> {
+        match self {
+            Self::Done(response) => response,
+            Self::Running { id, task } => {
+                let result = match task.await {
+                    Ok(Response { result, .. }) => result,
+                    Err(error) => {
+                        error!("Failed to execute command: {error}");
+                        RpcResult::Error(rpc::Error::Internal {
+                            error: error.to_string(),
+                        })
+                    }
+                };
+                id.map(|id| answer(connection, id, result))
+            }
+        }
+    }
+}
+
+/// Text frames carry JSON-RPC 2.0 requests. As with binary messages, authentication
+/// and cancellation are applied in order in the read loop and only commands run in the background.
+pub(super) fn receive_text(connection: &Connection, text: &str) {
+    let (calls, batch) = match JsonRpcMessage::parse(text) {
+        JsonRpcMessage::Single(request) => (vec![receive_request(connection, request)], false),
+        JsonRpcMessage::Batch(requests) => (
+            requests
+                .into_iter()
+                .map(|request| receive_request(connection, request))
+                .collect(),
+            true,
+        ),
+    };
+
+    let connection = connection.clone();
+    connection.running.send_modify(|running| *running += 1);
+    tokio::spawn(async move {
+        let responses = join_all(calls.into_iter().map(|call| call.finish(&connection)))
+            .await
+            .into_iter()
+            .flatten()
+            .collect::<Vec<_>>();
+
+        // Notifications, and batches made only of notifications, get no reply
+        match (batch, responses.as_slice()) {
+            (_, []) => {}
+            (false, [response]) => send(&connection, response),
+            _ => send(&connection, &responses),
+        }
+        connection.running.send_modify(|running| *running -= 1);
+    });
+}
+
+/// Answers a text frame that was refused before being parsed
+pub(super) fn reject_text(connection: &Connection, error: rpc::Error) {
+    error!("[JSON-RPC ->]: Rejected a request: {error}");
+    let response = JsonRpcResponse::error(Value::Null, JsonRpcError::from_rpc(&error));
+    send(connection, &response);
+}
+
+fn receive_request(
+    connection: &Connection,
+    request: Result<JsonRpcRequest, JsonRpcResponse>,
+) -> Call {
+    let request = match request {
+        Ok(request) => request,
+        Err(response) => return Call::Done(Some(response)),
+    };
+    info!("[JSON-RPC ->]: {request:?}");
+
+    let id = request.id.clone();
+    let command = match request.command() {
+        Ok(command) => command,
+        Err(error) => return Call::Done(id.map(|id| JsonRpcResponse::error(id, error))),
+    };
+
+    // Each request in a batch counts against the rate limit on its own
+    if let Err(error) = connection.dispatch.check_rate_limit(&command) {
+        let result = RpcResult::Error(error);
+        return Call::Done(id.map(|id| answer(connection, id, result)));
+    }
+
+    let message = Message::new(message_id(id.as_ref()), command);
+    let result = match connection.dispatch.handle(message) {
+        Handled::Answered(result) => result,
+
+        // Requests with an id are answered even when they cancel another
+        Handled::Unanswered => RpcResult::value(RpcMessage::Empty),
+        Handled::Queued(job) => {
+            let task = start_command(connection, job);
+            return Call::Running { id, task };
+        }
+    };
+    Call::Done(id.map(|id| answer(connection, id, result)))
+}
+
+fn answer(connection: &Connection, id: Value, result: RpcResult) -> JsonRpcResponse {
+    connection.state.metrics.answered(&result);
+    JsonRpcResponse::from_result(id, result)
+}
+
+// The JSON-RPC id doubles as the id `Cancel` refers to
+fn message_id(id: Option<&Value>) -> Id {
+    match id {
+        Some(Value::String(id)) => id.to_string(),
+        Some(id) => id.to_string(),
+        None => String::new(),
+    }
+}
+
+fn send<T: Serialize + std::fmt::Debug>(connection: &Connection, response: &T) {
+    let text = match serde_json::to_string(response) {
+        Ok(text) => text,
+        Err(error) => {
+            error!("Failed to serialize JSON-RPC response: {error}");
+            return;
+        }
+    };
+    info!("[JSON-RPC <-]: {response:?}");
+    if let Err(error) = connection.outbox.send(WebsocketMessage::Text(text)) {
+        error!("Failed to queue response: {error}")
+    }
+}
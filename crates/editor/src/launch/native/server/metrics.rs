@@ -0,0 +1,200 @@
+use rpc::RpcResult;
+use std::{
+    collections::BTreeMap,
+    fmt::Write,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Mutex,
+    },
+    time::Duration,
+};
+
+/// Upper bounds, in seconds, of the command latency buckets
+const LATENCY_BUCKETS: [f64; 12] = [
+    0.001, 0.0025, 0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 10.0,
+];
+
+/// Counters for the `/metrics` endpoint, kept for the lifetime of the server
+#[derive(Default)]
+pub(crate) struct Metrics {
+    connections_opened: AtomicU64,
+    bytes_received: AtomicU64,
+    bytes_sent: AtomicU64,
+
+    // By command variant
+    commands: Mutex<BTreeMap<&'static str, Histogram>>,
+
+    // By error variant
+    errors: Mutex<BTreeMap<&'static str, u64>>,
+}
+
+#[derive(Default)]
+struct Histogram {
+    buckets: [u64; LATENCY_BUCKETS.len()],
+    count: u64,
+    sum: f64,
+}
+
+impl Histogram {
+    fn observe(&mut self, seconds: f64) {
+        self.buckets
+            .iter_mut()
+            .zip(LATENCY_BUCKETS)
+            .filter(|(_, bound)| seconds <= *bound)
+            .for_each(|(bucket, _)| *bucket += 1);
+        self.count += 1;
+        self.sum += seconds;
+    }
+}
+
+impl Metrics {
+    pub(crate) fn connection_opened(&self) {
+        self.connections_opened.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn received(&self, bytes: usize) {
+        self.bytes_received
+            .fetch_add(bytes as u64, Ordering::Relaxed);
+    }
+
+    pub(crate) fn sent(&self, bytes: usize) {
+        self.bytes_sent.fetch_add(bytes as u64, Ordering::Relaxed);
+    }
+
+    /// Records a command the executor ran and how long it took
+    pub(crate) fn executed(&self, command: &'static str, duration: Duration) {
+        super::lock(&self.commands)
+            .entry(command)
+            .or_default()
+            .observe(duration.as_secs_f64());
+    }
+
+    /// Counts the error, if the result sent to a client is one
+    pub(crate) fn answered(&self, result: &RpcResult) {
+        if let RpcResult::Error(error) = result {
+            *super::lock(&self.errors).entry(error.name()).or_default() += 1;
+        }
+    }
+
+    /// The Prometheus text exposition of every metric
+    pub(crate) fn render(&self, connections: usize) -> String {
+        let mut text = String::new();
+        gauge(
+            &mut text,
+            "editor_connections",
+            "Clients currently connected",
+            connections as u64,
+        );
+        counter(
+            &mut text,
+            "editor_connections_opened_total",
+            "Clients that have connected since the server started",
+            self.connections_opened.load(Ordering::Relaxed),
+        );
+        counter(
+            &mut text,
+            "editor_received_bytes_total",
+            "Bytes received in websocket messages and stream frames",
+            self.bytes_received.load(Ordering::Relaxed),
+        );
+        counter(
+            &mut text,
+            "editor_sent_bytes_total",
+            "Bytes sent in websocket messages and stream frames",
+            self.bytes_sent.load(Ordering::Relaxed),
+        );
+
+        // The histogram's count is how many of each command ran
+        let commands = super::lock(&self.commands);
+        header(
+            &mut text,
+            "editor_command_duration_seconds",
+            "How long the executor took to run each command, by command",
+            "histogram",
+        );
+        for (command, histogram) in commands.iter() {
+            for (bound, count) in LATENCY_BUCKETS.iter().zip(histogram.buckets) {
+                let _ = writeln!(
+                    text,
+                    "editor_command_duration_seconds_bucket{{command=\"{command}\",le=\"{bound}\"}} {count}"
+                );
+            }
+            let _ = writeln!(
+                text,
+                "editor_command_duration_seconds_bucket{{command=\"{command}\",le=\"+Inf\"}} {}",
+                histogram.count
+            );
+            let _ = writeln!(
+                text,
+                "editor_command_duration_seconds_sum{{command=\"{command}\"}} {}",
+                histogram.sum
+            );
+            let _ = writeln!(
+                text,
+                "editor_command_duration_seconds_count{{command=\"{command}\"}} {}",
+                histogram.count
+            );
+        }
+        drop(commands);
+
+        header(
+            &mut text,
+            "editor_errors_total",
+            "Error results sent to clients, by error",
+            "counter",
+        );
+        for (error, count) in super::lock(&self.errors).iter() {
+            let _ = writeln!(text, "editor_errors_total{{error=\"{error}\"}} {count}");
+        }
+        text
+    }
+}
+
+fn header(text: &mut String, name: &str, help: &str, kind: &str) {
+    let _ = writeln!(text, "# HELP {name} {help}");
+    let _ = writeln!(text, "# TYPE {name} {kind}");
+}
+
+fn gauge(text: &mut String, name: &str, help: &str, value: u64) {
+    header(text, name, help, "gauge");
+    let _ = writeln!(text, "{name} {value}");
+}
+
+fn counter(text: &mut String, name: &str, help: &str, value: u64) {
+    header(text, name, help, "counter");
+    let _ = writeln!(text, "{name} {value}");
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_render() {
+        let metrics = Metrics::default();
+        metrics.connection_opened();
+        metrics.received(10);
+        metrics.sent(32);
+        metrics.executed("Schema", Duration::from_millis(3));
+        metrics.executed("Schema", Duration::from_secs(20));
+        metrics.answered(&RpcResult::Error(rpc::Error::Timeout));
+        metrics.answered(&RpcResult::value(rpc::RpcMessage::Empty));
+
+        let text = metrics.render(2);
+        for line in [
+            "editor_connections 2",
+            "editor_connections_opened_total 1",
+            "editor_received_bytes_total 10",
+            "editor_sent_bytes_total 32",
+            "editor_command_duration_seconds_bucket{command=\"Schema\",le=\"0.0025\"} 0",
+            "editor_command_duration_seconds_bucket{command=\"Schema\",le=\"0.005\"} 1",
+            "editor_command_duration_seconds_bucket{command=\"Schema\",le=\"+Inf\"} 2",
+            "editor_command_duration_seconds_count{command=\"Schema\"} 2",
+            "editor_errors_total{error=\"Timeout\"} 1",
+        ] {
+            assert!(text.lines().any(|rendered| rendered == line), "{line}");
+        }
+        assert!(!text.contains("editor_commands_total"));
+        assert!(!text.contains("error=\"Empty\""));
+    }
+}
@@ -0,0 +1,103 @@
+use enum2str::EnumStr;
+use std::{
+    fs::File,
+    io::BufReader,
+    path::{Path, PathBuf},
+    sync::Arc,
+};
+use tokio_rustls::{
+    rustls::{Certificate, PrivateKey, ServerConfig},
+    TlsAcceptor,
+};
+
+/// Where the server's certificate comes from
+#[derive(Debug, Clone)]
+pub enum TlsConfig {
+    /// PEM encoded certificate chain and private key files
+    Files { certificate: PathBuf, key: PathBuf },
+
+    /// A certificate generated at startup, for development.
+    /// Clients will reject it unless they are told to trust it.
+    SelfSigned { hostnames: Vec<String> },
+}
+
+#[derive(Debug, EnumStr)]
+pub(crate) enum TlsError {
+    #[enum2str("Failed to read '{path}'. Error: {error}")]
+    Read { path: String, error: String },
+
+    #[enum2str("No private key was found in '{path}'.")]
+    MissingKey { path: String },
+
+    #[enum2str("Failed to generate a self-signed certificate. Error: {error}")]
+    SelfSigned { error: String },
+
+    #[enum2str("The certificate or key is invalid. Error: {error}")]
+    Config { error: String },
+}
+
+pub(crate) fn acceptor(config: &TlsConfig) -> Result<TlsAcceptor, TlsError> {
+    let (certificates, key) = match config {
+        TlsConfig::Files { certificate, key } => (read_certificates(certificate)?, read_key(key)?),
+        TlsConfig::SelfSigned { hostnames } => self_signed(hostnames)?,
+    };
+
+    let server_config = ServerConfig::builder()
+        .with_safe_defaults()
+        .with_no_client_auth()
+        .with_single_cert(certificates, key)
+        .map_err(|error| TlsError::Config {
+            error: error.to_string(),
+        })?;
+
+    Ok(TlsAcceptor::from(Arc::new(server_config)))
+}
+
+fn read_certificates(path: &Path) -> Result<Vec<Certificate>, TlsError> {
+    let mut reader = open(path)?;
+    let certificates =
+        rustls_pemfile::certs(&mut reader).map_err(|error| read_error(path, error))?;
+    Ok(certificates.into_iter().map(Certificate).collect())
+}
+
+fn read_key(path: &Path) -> Result<PrivateKey, TlsError> {
+    let mut reader = open(path)?;
+    loop {
+        match rustls_pemfile::read_one(&mut reader).map_err(|error| read_error(path, error))? {
+            Some(rustls_pemfile::Item::PKCS8Key(key))
+            | Some(rustls_pemfile::Item::RSAKey(key))
+            | Some(rustls_pemfile::Item::ECKey(key)) => return Ok(PrivateKey(key)),
+            Some(_) => continue,
+            None => {
+                return Err(TlsError::MissingKey {
+                    path: path.display().to_string(),
+                })
+            }
+        }
+    }
+}
+
+fn self_signed(hostnames: &[String]) -> Result<(Vec<Certificate>, PrivateKey), TlsError> {
+    let self_signed_error = |error: rcgen::RcgenError| TlsError::SelfSigned {
+        error: error.to_string(),
+    };
+    let certificate =
+        rcgen::generate_simple_self_signed(hostnames.to_vec()).map_err(self_signed_error)?;
+    let certificate_der = certificate.serialize_der().map_err(self_signed_error)?;
+    let key_der = certificate.serialize_private_key_der();
+    log::warn!("Serving a self-signed certificate for {hostnames:?}, which is only suitable for development");
+    Ok((vec![Certificate(certificate_der)], PrivateKey(key_der)))
+}
+
+fn open(path: &Path) -> Result<BufReader<File>, TlsError> {
+    File::open(path)
+        .map(BufReader::new)
+        .map_err(|error| read_error(path, error))
+}
+
+fn read_error(path: &Path, error: std::io::Error) -> TlsError {
+    TlsError::Read {
+        path: path.display().to_string(),
+        error: error.to_string(),
+    }
+}
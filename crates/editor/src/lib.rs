@@ -3,10 +3,13 @@
 #![warn(clippy::all, rust_2018_idioms)]
 
 pub mod app;
+#[cfg(not(target_arch = "wasm32"))]
+pub mod discovery;
 pub mod filesystem;
 pub mod launch;
 pub mod notification;
 pub mod pane;
 pub mod project;
 pub mod rpc;
+pub mod schema;
 pub mod tree;
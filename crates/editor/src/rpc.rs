@@ -1,17 +1,20 @@
 use broker::Client;
-use rpc::{Response, RpcClient};
+use rpc::{
+    Command, Compression, Credential, Error, Id, OfflineQueue, Response, RpcClient, RpcMessage,
+    RpcResult, WebsocketTransport, DEFAULT_COMPRESSION_THRESHOLD, DEFAULT_HEARTBEAT_INTERVAL,
+    DEFAULT_LIVENESS_TIMEOUT,
+};
+use std::time::Duration;
 use ui::contract::{Broker, ClientHandle, Message};
+use web_time::Instant;
 
 #[cfg(not(target_arch = "wasm32"))]
-use rpc::RpcExecutor;
+use rpc::{LoopbackTransport, RpcExecutor};
 
 #[cfg(not(target_arch = "wasm32"))]
 use crate::app::BackendConnectionStrategy;
 
 pub struct Rpc {
-    #[cfg(not(target_arch = "wasm32"))]
-    rpc_executor: RpcExecutor,
-
     #[cfg(not(target_arch = "wasm32"))]
     pub connection_strategy: BackendConnectionStrategy,
 
@@ -19,14 +22,20 @@ pub struct Rpc {
     frontend_client: ClientHandle,
     subscribed: bool,
     has_connected: bool,
+    client_id: Option<Id>,
+    session_announced: bool,
+    compression: Compression,
+    credential: Credential,
+    heartbeat_interval: Duration,
+    liveness_timeout: Duration,
+
+    // Commands issued before the connection opens wait here
+    offline_queue: OfflineQueue,
 }
 
 impl Default for Rpc {
     fn default() -> Self {
         Self {
-            #[cfg(not(target_arch = "wasm32"))]
-            rpc_executor: RpcExecutor,
-
             #[cfg(not(target_arch = "wasm32"))]
             connection_strategy: BackendConnectionStrategy::Internal,
 
@@ -34,6 +43,13 @@ impl Default for Rpc {
             rpc_client: None,
             subscribed: false,
             has_connected: false,
+            client_id: None,
+            session_announced: false,
+            compression: Compression::None,
+            credential: Credential::None,
+            heartbeat_interval: DEFAULT_HEARTBEAT_INTERVAL,
+            liveness_timeout: DEFAULT_LIVENESS_TIMEOUT,
+            offline_queue: OfflineQueue::default(),
         }
     }
 }
@@ -47,17 +63,94 @@ impl Rpc {
     #[cfg(not(target_arch = "wasm32"))]
     pub fn set_connection_strategy(&mut self, strategy: &BackendConnectionStrategy) {
         self.connection_strategy = *strategy;
+        self.session_announced = false;
+
+        // The internal backend is started by `connect_internal` on the next update
+        if *strategy == BackendConnectionStrategy::Internal {
+            self.rpc_client = None;
+            self.has_connected = false;
+            self.client_id = None;
+        }
     }
 
     pub fn has_connected(&self) -> bool {
         self.has_connected
     }
 
+    /// The client id assigned to this frontend by the backend it is connected to
+    pub fn client_id(&self) -> Option<&Id> {
+        self.client_id.as_ref()
+    }
+
+    /// The compression to propose to the backend on the next connection
+    pub fn set_compression(&mut self, compression: Compression) {
+        self.compression = compression;
+    }
+
+    /// The compression the backend agreed to, if connected
+    pub fn compression(&self) -> Option<Compression> {
+        self.rpc_client.as_ref().map(RpcClient::compression)
+    }
+
+    /// The credential to present to the backend on the next connection
+    pub fn set_credential(&mut self, credential: Credential) {
+        self.credential = credential;
+    }
+
+    /// Why the backend refused the credential, if it did
+    pub fn authentication_error(&self) -> Option<&Error> {
+        match self.rpc_client.as_ref()?.authentication()? {
+            Ok(_) => None,
+            Err(error) => Some(error),
+        }
+    }
+
+    /// How often to ping the backend, and how long it may stay silent
+    /// before it is considered gone, from the next connection on
+    pub fn set_heartbeat(&mut self, interval: Duration, timeout: Duration) {
+        self.heartbeat_interval = interval;
+        self.liveness_timeout = timeout;
+    }
+
+    pub fn heartbeat_interval(&self) -> Duration {
+        self.heartbeat_interval
+    }
+
+    /// The round trip time to the backend, measured with heartbeats
+    pub fn latency(&self) -> Option<Duration> {
+        self.rpc_client.as_ref()?.latency()
+    }
+
+    /// Whether the backend has been heard from within the liveness timeout
+    pub fn is_responsive(&self) -> bool {
+        self.rpc_client.as_ref().map_or(false, RpcClient::is_alive)
+    }
+
     pub fn connect(&mut self, url: &str, wake_up: impl Fn() + Send + Sync + 'static) {
-        match ewebsock::connect_with_wakeup(url, wake_up) {
-            Ok((ws_sender, ws_receiver)) => {
-                self.rpc_client = Some(RpcClient::new(ws_sender, ws_receiver));
+        match WebsocketTransport::connect(url, wake_up) {
+            Ok(transport) => {
+                // Uploads started on the internal backend cannot resume on a remote one
+                #[cfg(not(target_arch = "wasm32"))]
+                if self.connection_strategy == BackendConnectionStrategy::Internal {
+                    self.rpc_client = None;
+                    self.connection_strategy = BackendConnectionStrategy::Remote;
+                }
+
+                // Reusing the client lets interrupted uploads resume on the new connection
+                let client = match self.rpc_client.take() {
+                    Some(mut client) => {
+                        client.reconnect(transport);
+                        client
+                    }
+                    None => RpcClient::new(transport),
+                };
+                let client = client
+                    .with_compression(self.compression, DEFAULT_COMPRESSION_THRESHOLD)
+                    .with_credential(self.credential.clone())
+                    .with_heartbeat(self.heartbeat_interval, self.liveness_timeout);
+                self.rpc_client = Some(client);
                 self.has_connected = true;
+                self.client_id = None;
             }
             Err(error) => {
                 log::error!("Failed to connect to {url:?}: {error}");
@@ -65,12 +158,27 @@ impl Rpc {
         }
     }
 
+    /// Runs the contract in-process on a background thread. Commands still go through
+    /// the same encoding and decoding as a remote backend, so both behave alike.
+    #[cfg(not(target_arch = "wasm32"))]
+    pub fn connect_internal(&mut self, wake_up: impl Fn() + Send + Sync + 'static) {
+        let executor = RpcExecutor::default().with_schema(crate::schema::contract_schema());
+        let client = RpcClient::new(LoopbackTransport::new(executor, wake_up))
+            .with_compression(self.compression, DEFAULT_COMPRESSION_THRESHOLD)
+            .with_heartbeat(self.heartbeat_interval, self.liveness_timeout);
+        self.rpc_client = Some(client);
+        self.connection_strategy = BackendConnectionStrategy::Internal;
+        self.has_connected = true;
+        self.client_id = None;
+    }
+
     pub fn client_available(&mut self) -> bool {
         self.rpc_client.is_some()
     }
 
     fn subscribe(&mut self, broker: &mut Broker) {
         broker.subscribe(&Message::rpc_command_topic(), &self.frontend_client);
+        broker.subscribe(&Message::rpc_session_request_topic(), &self.frontend_client);
         self.subscribed = true;
     }
 
@@ -79,35 +187,134 @@ impl Rpc {
             self.subscribe(broker);
         }
 
+        if !self.session_announced {
+            self.announce_session(broker);
+        }
+
         let mut messages = Vec::new();
         while let Some(message) = self.frontend_client.borrow_mut().next_message() {
             log::debug!("RPC message: {message:#?}");
             messages.push(message);
         }
 
-        #[cfg(not(target_arch = "wasm32"))]
+        // Widgets created after the session was announced ask for it again
+        if messages
+            .iter()
+            .any(|message| matches!(message, Message::RpcSessionRequest))
+        {
+            self.announce_session(broker);
+        }
+
+        self.flush_offline_queue(broker);
+
         messages.into_iter().for_each(|message| {
-            if let Message::RpcCommand { id, command } = message {
-                match self.connection_strategy {
-                    BackendConnectionStrategy::Internal => {
-                        log::debug!("Executing internal rpc command: {command:#?}");
-                        let result = self.rpc_executor.execute(&id, command);
-                        publish_result(broker, &id, result);
-                    }
-                    BackendConnectionStrategy::Remote => {
-                        if let Some(client) = self.rpc_client.as_mut() {
-                            client.send(id, command)
-                        }
-                    }
-                }
+            if let Message::RpcCommand {
+                id,
+                command,
+                idempotency_key,
+            } = message
+            {
+                self.send(broker, command_message(id, command, idempotency_key));
             }
         });
 
-        if let Some(response) = self.rpc_client.as_mut().and_then(|client| client.receive()) {
-            let Response { id, result } = response;
-            publish_result(broker, &id, result);
+        while let Some(response) = self.rpc_client.as_mut().and_then(|client| client.receive()) {
+            match response {
+                Response {
+                    result: RpcResult::Success(RpcMessage::ClientId { id: client_id }),
+                    ..
+                } => {
+                    log::info!("Assigned client id '{client_id}' by the backend");
+                    self.client_id = Some(client_id);
+                    self.session_announced = false;
+                }
+
+                // Broadcasts answer no command, so they go to whoever watches the room
+                Response {
+                    result:
+                        RpcResult::Success(RpcMessage::RoomMessage {
+                            room,
+                            sender,
+                            payload,
+                        }),
+                    ..
+                } => broker.publish(
+                    &Message::rpc_room_message_topic(&room),
+                    Message::RpcRoomMessage {
+                        room,
+                        sender,
+                        payload,
+                    },
+                ),
+                Response { id, result } => publish_result(broker, &id, result),
+            }
+        }
+
+        while let Some((id, progress)) = self.rpc_client.as_mut().and_then(|c| c.next_progress()) {
+            broker.publish(
+                &Message::rpc_transfer_progress_topic(&id),
+                Message::RpcTransferProgress { progress },
+            );
         }
     }
+
+    fn send(&mut self, broker: &mut Broker, message: rpc::Message) {
+        match self.rpc_client.as_mut() {
+            // Anything already queued goes first, so commands arrive in the order they were issued
+            Some(client) if client.is_open() && self.offline_queue.is_empty() => {
+                client.send_message(message)
+            }
+            _ => {
+                if let Err(message) = self.offline_queue.push(message, Instant::now()) {
+                    log::warn!(
+                        "Dropping command for '{}', the offline queue is full",
+                        message.id
+                    );
+                    let capacity = self.offline_queue.capacity();
+                    let result = RpcResult::Error(Error::QueueFull { capacity });
+                    publish_result(broker, &message.id, result);
+                }
+            }
+        }
+    }
+
+    fn flush_offline_queue(&mut self, broker: &mut Broker) {
+        for message in self.offline_queue.expire(Instant::now()) {
+            log::warn!(
+                "A command for '{}' expired in the offline queue",
+                message.id
+            );
+            publish_result(broker, &message.id, RpcResult::Error(Error::QueueExpired));
+        }
+
+        let Some(client) = self.rpc_client.as_mut().filter(|client| client.is_open()) else {
+            return;
+        };
+        if !self.offline_queue.is_empty() {
+            log::info!("Replaying {} queued command(s)", self.offline_queue.len());
+        }
+        self.offline_queue
+            .drain()
+            .for_each(|message| client.send_message(message));
+    }
+
+    fn announce_session(&mut self, broker: &mut Broker) {
+        if let Some(client_id) = self.client_id().cloned() {
+            broker.publish(
+                &Message::rpc_session_topic(),
+                Message::RpcSession { client_id },
+            );
+            self.session_announced = true;
+        }
+    }
+}
+
+fn command_message(id: Id, command: Command, idempotency_key: Option<Id>) -> rpc::Message {
+    let message = rpc::Message::new(id, command);
+    match idempotency_key {
+        Some(idempotency_key) => message.with_idempotency_key(idempotency_key),
+        None => message,
+    }
 }
 
 fn publish_result(broker: &mut broker::Broker<Message>, id: &str, result: rpc::RpcResult) {
@@ -116,3 +323,117 @@ fn publish_result(broker: &mut broker::Broker<Message>, id: &str, result: rpc::R
         Message::RpcResult { result },
     );
 }
+
+#[cfg(test)]
+mod tests {
+    use super::Rpc;
+    use mock_server::{MockServer, Rule};
+    use rpc::{Command, Error, Response, RpcMessage, RpcResult};
+    use std::{
+        thread,
+        time::{Duration, Instant},
+    };
+    use ui::{
+        connection::WidgetClient,
+        contract::{Broker, Message},
+    };
+
+    const TIMEOUT: Duration = Duration::from_secs(5);
+
+    // Updates the frontend and a widget until `poll` finds what it waits for
+    fn update_until<T>(
+        rpc: &mut Rpc,
+        broker: &mut Broker,
+        widget: &mut WidgetClient,
+        mut poll: impl FnMut(&mut WidgetClient) -> Option<T>,
+    ) -> T {
+        let deadline = Instant::now() + TIMEOUT;
+        loop {
+            rpc.update(broker);
+            widget.update(broker);
+            if let Some(value) = poll(widget) {
+                return value;
+            }
+            assert!(
+                Instant::now() < deadline,
+                "Timed out waiting for the backend"
+            );
+            thread::sleep(Duration::from_millis(5));
+        }
+    }
+
+    fn connect(server: &MockServer) -> (Rpc, Broker, WidgetClient) {
+        let mut rpc = Rpc::new();
+        rpc.connect(&server.url(), || {});
+        let mut broker = Broker::new();
+        let mut widget = WidgetClient::default();
+        update_until(&mut rpc, &mut broker, &mut widget, |widget| {
+            widget.has_connected().then_some(())
+        });
+        (rpc, broker, widget)
+    }
+
+    #[test]
+    fn test_widget_command() {
+        let server = MockServer::start().unwrap();
+        server.expect(Rule::command(Command::Schema).fail(Error::Timeout));
+        let (mut rpc, mut broker, mut widget) = connect(&server);
+        assert_eq!(widget.id(), rpc.client_id());
+
+        widget.publish_rpc_command(&mut broker, Command::Example);
+        let message = update_until(&mut rpc, &mut broker, &mut widget, |widget| {
+            widget.next_rpc_message()
+        });
+        assert_eq!(message, RpcMessage::Empty);
+
+        widget.publish_rpc_command(&mut broker, Command::Schema);
+        let result = update_until(&mut rpc, &mut broker, &mut widget, |widget| {
+            match widget.next_message() {
+                Some(Message::RpcResult { result }) => Some(result),
+                _ => None,
+            }
+        });
+        assert_eq!(result, RpcResult::Error(Error::Timeout));
+
+        let commands = server
+            .received()
+            .into_iter()
+            .map(|message| message.command)
+            .collect::<Vec<_>>();
+        assert!(commands.ends_with(&[Command::Example, Command::Schema]));
+    }
+
+    #[test]
+    fn test_room_message() {
+        let server = MockServer::start().unwrap();
+        let (mut rpc, mut broker, mut widget) = connect(&server);
+
+        widget.join_room(&mut broker, "lobby");
+        let joined = update_until(&mut rpc, &mut broker, &mut widget, |widget| {
+            widget.next_rpc_message()
+        });
+        assert_eq!(
+            joined,
+            RpcMessage::RoomJoined {
+                room: "lobby".to_string(),
+                members: 1,
+            }
+        );
+
+        server.push(Response {
+            id: "lobby".to_string(),
+            result: RpcResult::value(RpcMessage::RoomMessage {
+                room: "lobby".to_string(),
+                sender: "another-client".to_string(),
+                payload: "{}".to_string(),
+            }),
+        });
+        let sender = update_until(&mut rpc, &mut broker, &mut widget, |widget| {
+            match widget.next_message() {
+                Some(Message::RpcRoomMessage { sender, .. }) => Some(sender),
+                _ => None,
+            }
+        });
+        assert_eq!(sender, "another-client");
+    }
+}
@@ -0,0 +1,7 @@
+use rpc::{ContractSchema, PayloadJson};
+use ui::contract::Message;
+
+/// The JSON Schema of every contract type, including the widget messages on the broker
+pub fn contract_schema() -> PayloadJson {
+    ContractSchema::new().with::<Message>().to_json()
+}
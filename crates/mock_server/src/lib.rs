@@ -0,0 +1,21 @@
+//! A scripted stand-in for the backend's `server` subcommand, for widget and integration tests.
+//!
+//! `MockServer` listens for websocket connections on an ephemeral port. Each command it
+//! receives is recorded, then answered by the first `Rule` that matches it, with a result,
+//! an error, a delay, silence, or a dropped connection. Commands no rule matches go through
+//! the same dispatch as the real server's. Tests can then assert on exactly
+//! what a client sent, and on how it handled what came back.
+//!
+//! ```no_run
+//! use mock_server::{MockServer, Rule};
+//! use rpc::{Command, Error};
+//!
+//! let server = MockServer::start().unwrap();
+//! server.expect(Rule::command(Command::Schema).fail(Error::SchemaUnavailable));
+//! // Point the client under test at `server.url()`
+//! ```
+
+mod rule;
+mod server;
+
+pub use self::{rule::*, server::*};
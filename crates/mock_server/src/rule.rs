@@ -0,0 +1,118 @@
+use rpc::{Command, Error, RpcMessage, RpcResult};
+use std::{fmt, time::Duration};
+
+/// What the server does with a command that matched a rule
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Action {
+    Respond(RpcResult),
+
+    /// Never answers, so the client's timeout has to fire
+    Ignore,
+
+    /// Closes the connection instead of answering
+    Disconnect,
+}
+
+/// Answers the commands it matches, `times` times or forever
+pub struct Rule {
+    matcher: Box<dyn Fn(&Command) -> bool + Send>,
+    action: Action,
+    delay: Duration,
+    remaining: Option<usize>,
+}
+
+impl fmt::Debug for Rule {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Rule")
+            .field("action", &self.action)
+            .field("delay", &self.delay)
+            .field("remaining", &self.remaining)
+            .finish_non_exhaustive()
+    }
+}
+
+impl Rule {
+    /// Matches commands `matcher` returns true for, answering them with `RpcResult::default()`
+    pub fn when(matcher: impl Fn(&Command) -> bool + Send + 'static) -> Self {
+        Self {
+            matcher: Box::new(matcher),
+            action: Action::Respond(RpcResult::default()),
+            delay: Duration::ZERO,
+            remaining: None,
+        }
+    }
+
+    /// Matches commands equal to `command`
+    pub fn command(command: Command) -> Self {
+        Self::when(move |received| *received == command)
+    }
+
+    pub fn respond(self, message: RpcMessage) -> Self {
+        self.then(Action::Respond(RpcResult::value(message)))
+    }
+
+    pub fn fail(self, error: Error) -> Self {
+        self.then(Action::Respond(RpcResult::Error(error)))
+    }
+
+    pub fn ignore(self) -> Self {
+        self.then(Action::Ignore)
+    }
+
+    pub fn disconnect(self) -> Self {
+        self.then(Action::Disconnect)
+    }
+
+    pub fn then(mut self, action: Action) -> Self {
+        self.action = action;
+        self
+    }
+
+    /// Waits before acting. Commands answered later still arrive in the order they are due.
+    pub fn after(mut self, delay: Duration) -> Self {
+        self.delay = delay;
+        self
+    }
+
+    /// Only matches the next `count` commands, so later rules can answer the ones after them
+    pub fn times(mut self, count: usize) -> Self {
+        self.remaining = Some(count);
+        self
+    }
+
+    pub(crate) fn matches(&self, command: &Command) -> bool {
+        self.remaining != Some(0) && (self.matcher)(command)
+    }
+
+    pub(crate) fn take(&mut self) -> (Action, Duration) {
+        if let Some(remaining) = &mut self.remaining {
+            *remaining = remaining.saturating_sub(1);
+        }
+        (self.action.clone(), self.delay)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Action, Rule};
+    use rpc::{Command, Error, RpcResult};
+    use std::time::Duration;
+
+    #[test]
+    fn test_times() {
+        let mut rule = Rule::command(Command::Example)
+            .fail(Error::Timeout)
+            .after(Duration::from_millis(5))
+            .times(1);
+        assert!(!rule.matches(&Command::Schema));
+        assert!(rule.matches(&Command::Example));
+        assert_eq!(
+            rule.take(),
+            (
+                Action::Respond(RpcResult::Error(Error::Timeout)),
+                Duration::from_millis(5)
+            )
+        );
+        assert!(!rule.matches(&Command::Example));
+    }
+}
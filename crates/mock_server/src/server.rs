@@ -0,0 +1,440 @@
+use crate::{Action, Rule};
+use rpc::{Backend, BackendConnection, Command, Id, Message, Outgoing, Response, RpcExecutor};
+use std::{
+    collections::HashMap,
+    io,
+    net::{SocketAddr, TcpListener, TcpStream},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        mpsc, Arc, Condvar, Mutex, MutexGuard,
+    },
+    thread,
+    time::{Duration, Instant},
+};
+use tungstenite::{Message as WebsocketMessage, WebSocket};
+
+/// How often connections check for due responses, pushes and shutdown while the client is quiet
+const POLL_INTERVAL: Duration = Duration::from_millis(10);
+
+/// What a connection sends once it is due
+enum Scheduled {
+    /// A frame the dispatch already encoded
+    Frame(Vec<u8>),
+
+    /// Sent through the dispatch, which encodes and chunks it like any other answer
+    Response(Response),
+
+    Disconnect,
+}
+
+type Sender = mpsc::Sender<(Instant, Scheduled)>;
+
+struct State {
+    rules: Vec<Rule>,
+    received: Vec<Message>,
+    connections: HashMap<Id, Sender>,
+    answer_heartbeats: bool,
+    connected: usize,
+}
+
+struct Shared {
+    state: Mutex<State>,
+
+    /// Notified whenever a message is received or a connection opens or closes
+    changed: Condvar,
+    stopped: AtomicBool,
+}
+
+impl Shared {
+    fn state(&self) -> MutexGuard<'_, State> {
+        lock(&self.state)
+    }
+
+    // Records a message and applies the first rule that matches it,
+    // leaving the messages no rule matches to the dispatch
+    fn intercept(&self, connection: &BackendConnection, message: Message) -> Option<Message> {
+        match &message.command {
+            Command::TransferChunk { .. } => return Some(message),
+            Command::Heartbeat { .. } => {
+                return self.state().answer_heartbeats.then_some(message);
+            }
+            _ => {}
+        }
+
+        let (taken, sender) = {
+            let mut state = self.state();
+            state.received.push(message.clone());
+            let taken = state
+                .rules
+                .iter_mut()
+                .find(|rule| rule.matches(&message.command))
+                .map(Rule::take);
+            (
+                taken,
+                state.connections.get(connection.client_id()).cloned(),
+            )
+        };
+        self.changed.notify_all();
+
+        let Some((action, delay)) = taken else {
+            return Some(message);
+        };
+        let scheduled = match action {
+            Action::Respond(result) => Scheduled::Response(Response {
+                id: message.id,
+                result,
+            }),
+            Action::Disconnect => Scheduled::Disconnect,
+            Action::Ignore => return None,
+        };
+        if let Some(sender) = sender {
+            let _ = sender.send((Instant::now() + delay, scheduled));
+        }
+        None
+    }
+}
+
+/// Serves websocket connections on `127.0.0.1` until it is dropped
+pub struct MockServer {
+    address: SocketAddr,
+    shared: Arc<Shared>,
+}
+
+impl MockServer {
+    /// Listens on an ephemeral port, answering the commands no rule matches
+    /// the way a server with a default `RpcExecutor` does
+    pub fn start() -> io::Result<Self> {
+        Self::start_with(RpcExecutor::default())
+    }
+
+    /// Like `start`, but has `executor` run the commands no rule matches
+    pub fn start_with(executor: RpcExecutor) -> io::Result<Self> {
+        let listener = TcpListener::bind("127.0.0.1:0")?;
+        listener.set_nonblocking(true)?;
+        let address = listener.local_addr()?;
+        let shared = Arc::new(Shared {
+            state: Mutex::new(State {
+                rules: Vec::new(),
+                received: Vec::new(),
+                connections: HashMap::new(),
+                answer_heartbeats: true,
+                connected: 0,
+            }),
+            changed: Condvar::new(),
+            stopped: AtomicBool::new(false),
+        });
+
+        let intercepting = shared.clone();
+        let backend = Arc::new(Backend::new(executor).with_interceptor(
+            move |connection, message| intercepting.intercept(connection, message),
+        ));
+        let accepting = shared.clone();
+        thread::spawn(move || accept_connections(&listener, &backend, &accepting));
+        Ok(Self { address, shared })
+    }
+
+    pub fn address(&self) -> SocketAddr {
+        self.address
+    }
+
+    /// The `ws://` url clients connect to
+    pub fn url(&self) -> String {
+        format!("ws://{}", self.address)
+    }
+
+    /// Adds a rule after the existing ones. Each command is answered by the first rule
+    /// that matches it. Unmatched commands are dispatched as on a real server.
+    pub fn expect(&self, rule: Rule) {
+        self.shared.state().rules.push(rule);
+    }
+
+    /// Stops or resumes answering heartbeats, to make clients think the server is unresponsive
+    pub fn set_answer_heartbeats(&self, answer: bool) {
+        self.shared.state().answer_heartbeats = answer;
+    }
+
+    /// Every message received so far, in order, with chunked messages reassembled.
+    /// Heartbeats and the chunks themselves are left out.
+    pub fn received(&self) -> Vec<Message> {
+        self.shared.state().received.clone()
+    }
+
+    /// Waits for a message that `predicate` returns true for, including ones already received
+    pub fn wait_for(
+        &self,
+        predicate: impl Fn(&Message) -> bool,
+        timeout: Duration,
+    ) -> Option<Message> {
+        let deadline = Instant::now() + timeout;
+        let mut state = self.shared.state();
+        loop {
+            if let Some(message) = state.received.iter().find(|message| predicate(message)) {
+                return Some(message.clone());
+            }
+            let now = Instant::now();
+            if now >= deadline {
+                return None;
+            }
+            state = self
+                .shared
+                .changed
+                .wait_timeout(state, deadline - now)
+                .unwrap_or_else(|poisoned| poisoned.into_inner())
+                .0;
+        }
+    }
+
+    /// How many clients are connected
+    pub fn connections(&self) -> usize {
+        self.shared.state().connections.len()
+    }
+
+    /// How many clients have connected since the server started, to check that one reconnected
+    pub fn connected(&self) -> usize {
+        self.shared.state().connected
+    }
+
+    /// Waits until `count` clients are connected
+    pub fn wait_for_connections(&self, count: usize, timeout: Duration) -> bool {
+        let state = self.shared.state();
+        let (state, _) = self
+            .shared
+            .changed
+            .wait_timeout_while(state, timeout, |state| state.connections.len() != count)
+            .unwrap_or_else(|poisoned| poisoned.into_inner());
+        state.connections.len() == count
+    }
+
+    /// Sends a response nobody asked for to every client, such as a `RpcMessage::RoomMessage`
+    pub fn push(&self, response: Response) {
+        self.schedule(|| Scheduled::Response(response.clone()));
+    }
+
+    /// Closes every connection, so clients have to reconnect
+    pub fn disconnect_all(&self) {
+        self.schedule(|| Scheduled::Disconnect);
+    }
+
+    fn schedule(&self, scheduled: impl Fn() -> Scheduled) {
+        let now = Instant::now();
+        for connection in self.shared.state().connections.values() {
+            let _ = connection.send((now, scheduled()));
+        }
+    }
+}
+
+impl Drop for MockServer {
+    fn drop(&mut self) {
+        self.shared.stopped.store(true, Ordering::SeqCst);
+    }
+}
+
+fn accept_connections(listener: &TcpListener, backend: &Arc<Backend>, shared: &Arc<Shared>) {
+    while !shared.stopped.load(Ordering::SeqCst) {
+        match listener.accept() {
+            Ok((stream, peer)) => {
+                log::debug!("Mock server accepted {peer}");
+                let backend = backend.clone();
+                let shared = shared.clone();
+                thread::spawn(move || {
+                    if let Err(error) = serve(stream, &peer.to_string(), &backend, &shared) {
+                        log::debug!("Mock connection from {peer} ended: {error}");
+                    }
+                });
+            }
+            Err(error) if error.kind() == io::ErrorKind::WouldBlock => thread::sleep(POLL_INTERVAL),
+            Err(error) => log::error!("Mock server failed to accept a connection: {error}"),
+        }
+    }
+}
+
+fn serve(
+    stream: TcpStream,
+    peer: &str,
+    backend: &Arc<Backend>,
+    shared: &Shared,
+) -> Result<(), tungstenite::Error> {
+    stream.set_nonblocking(false)?;
+    let websocket = tungstenite::accept(stream).map_err(|error| match error {
+        tungstenite::HandshakeError::Failure(error) => error,
+        tungstenite::HandshakeError::Interrupted(_) => tungstenite::Error::ConnectionClosed,
+    })?;
+    websocket.get_ref().set_read_timeout(Some(POLL_INTERVAL))?;
+
+    // The dispatch answers from whichever thread runs a command,
+    // so its frames are queued for the connection's thread to write
+    let (sender, scheduled) = mpsc::channel();
+    let outbox = Mutex::new(sender.clone());
+    let dispatch = backend.connect(peer, move |outgoing| {
+        if let Outgoing::Binary(frame) = outgoing {
+            let _ = lock(&outbox).send((Instant::now(), Scheduled::Frame(frame)));
+        }
+    });
+    {
+        let mut state = shared.state();
+        state.connected += 1;
+        state
+            .connections
+            .insert(dispatch.client_id().to_string(), sender);
+    }
+    shared.changed.notify_all();
+
+    let mut connection = Connection {
+        websocket,
+        dispatch,
+        scheduled: Vec::new(),
+    };
+    let result = connection.run(shared, &scheduled);
+
+    connection.dispatch.close();
+    shared
+        .state()
+        .connections
+        .remove(connection.dispatch.client_id());
+    shared.changed.notify_all();
+    result
+}
+
+struct Connection {
+    websocket: WebSocket<TcpStream>,
+    dispatch: BackendConnection,
+
+    /// What waits to be sent, in the order it is due
+    scheduled: Vec<(Instant, Scheduled)>,
+}
+
+impl Connection {
+    fn run(
+        &mut self,
+        shared: &Shared,
+        incoming: &mpsc::Receiver<(Instant, Scheduled)>,
+    ) -> Result<(), tungstenite::Error> {
+        loop {
+            if shared.stopped.load(Ordering::SeqCst) {
+                return self.websocket.close(None);
+            }
+            if !self.send_due(incoming)? {
+                return Ok(());
+            }
+
+            match self.websocket.read() {
+                Ok(WebsocketMessage::Binary(frame)) => {
+                    if let Some(job) = self.dispatch.receive_frame(&frame) {
+                        let dispatch = self.dispatch.clone();
+                        thread::spawn(move || dispatch.respond(dispatch.execute(job)));
+                    }
+                }
+                Ok(WebsocketMessage::Close(_)) => return Ok(()),
+                Ok(_) => {}
+                Err(tungstenite::Error::Io(error))
+                    if matches!(
+                        error.kind(),
+                        io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut
+                    ) => {}
+                Err(error) => return Err(error),
+            }
+        }
+    }
+
+    // Sends everything that is due, returning false once the connection is closed
+    fn send_due(
+        &mut self,
+        incoming: &mpsc::Receiver<(Instant, Scheduled)>,
+    ) -> Result<bool, tungstenite::Error> {
+        loop {
+            while let Ok((due, scheduled)) = incoming.try_recv() {
+                let index = self.scheduled.partition_point(|(other, _)| *other <= due);
+                self.scheduled.insert(index, (due, scheduled));
+            }
+            if !self
+                .scheduled
+                .first()
+                .map_or(false, |(due, _)| *due <= Instant::now())
+            {
+                return Ok(true);
+            }
+            match self.scheduled.remove(0).1 {
+                Scheduled::Frame(frame) => self.websocket.send(WebsocketMessage::Binary(frame))?,
+                Scheduled::Response(response) => self.dispatch.respond(response),
+                Scheduled::Disconnect => {
+                    self.websocket.close(None)?;
+                    return Ok(false);
+                }
+            }
+        }
+    }
+}
+
+fn lock<T>(mutex: &Mutex<T>) -> MutexGuard<'_, T> {
+    mutex
+        .lock()
+        .unwrap_or_else(|poisoned| poisoned.into_inner())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::MockServer;
+    use crate::Rule;
+    use rpc::{Command, Error, RpcMessage};
+    use sdk::{blocking::Client, Endpoint, Options};
+    use std::time::Duration;
+
+    const TIMEOUT: Duration = Duration::from_secs(5);
+
+    fn connect(server: &MockServer) -> Client {
+        Client::connect(Options::new(Endpoint::Websocket(server.url()))).unwrap()
+    }
+
+    #[test]
+    fn test_scripted_responses() {
+        let server = MockServer::start().unwrap();
+        server.expect(
+            Rule::command(Command::Example)
+                .fail(Error::Timeout)
+                .times(1),
+        );
+        server.expect(Rule::command(Command::Example).respond(RpcMessage::Empty));
+
+        let client = connect(&server);
+        assert!(!client.client_id().is_empty());
+        assert_eq!(client.call(Command::Example), Err(Error::Timeout));
+        assert_eq!(client.call(Command::Example), Ok(RpcMessage::Empty));
+        assert_eq!(client.call(Command::Schema), Err(Error::SchemaUnavailable));
+
+        let commands = server
+            .received()
+            .into_iter()
+            .map(|message| message.command)
+            .collect::<Vec<_>>();
+        assert_eq!(
+            commands,
+            [Command::Example, Command::Example, Command::Schema]
+        );
+    }
+
+    #[test]
+    fn test_ignore_and_disconnect() {
+        let server = MockServer::start().unwrap();
+        server.expect(Rule::command(Command::Schema).ignore());
+        server.expect(
+            Rule::command(Command::Example)
+                .disconnect()
+                .after(Duration::from_millis(20)),
+        );
+
+        let options = Options::new(Endpoint::Websocket(server.url()))
+            .with_request_timeout(Duration::from_millis(100));
+        let client = Client::connect(options).unwrap();
+        assert_eq!(client.call(Command::Schema), Err(Error::Timeout));
+        assert!(server
+            .wait_for(
+                |message| matches!(message.command, Command::Cancel { .. }),
+                TIMEOUT
+            )
+            .is_some());
+
+        assert_eq!(client.call(Command::Example), Err(Error::Connection));
+        assert!(server.wait_for_connections(0, TIMEOUT));
+        assert_eq!(server.connected(), 1);
+    }
+}
@@ -0,0 +1,181 @@
+use crate::{Credential, Error};
+use hmac::{Hmac, Mac};
+use sha2::{Digest, Sha256};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+type HmacSha256 = Hmac<Sha256>;
+
+/// The identity a connection proved with its credential
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Authentication {
+    pub subject: String,
+
+    /// Seconds since the unix epoch, for signed credentials
+    pub expires_at: Option<u64>,
+}
+
+impl Authentication {
+    pub fn check_expiry(&self) -> Result<(), Error> {
+        match self.expires_at {
+            Some(expires_at) if expires_at <= unix_time() => {
+                Err(Error::CredentialExpired { expires_at })
+            }
+            _ => Ok(()),
+        }
+    }
+}
+
+/// Which credentials the server accepts. With neither tokens nor a signing key,
+/// authentication is disabled and every connection may send commands.
+#[derive(Default, Debug, Clone)]
+pub struct AuthConfig {
+    /// Pre-shared tokens, any of which is accepted. Each authenticates as a subject of its own.
+    pub tokens: Vec<String>,
+
+    /// The key signed credentials must be signed with
+    pub signing_key: Option<String>,
+}
+
+impl AuthConfig {
+    pub fn is_enabled(&self) -> bool {
+        !self.tokens.is_empty() || self.signing_key.is_some()
+    }
+
+    pub fn verify(&self, credential: &Credential) -> Result<Authentication, Error> {
+        match credential {
+            Credential::None => Err(Error::InvalidCredential),
+            Credential::Token { token } => {
+                let accepted = self
+                    .tokens
+                    .iter()
+                    .any(|candidate| constant_time_eq(candidate.as_bytes(), token.as_bytes()));
+                if !accepted {
+                    return Err(Error::InvalidCredential);
+                }
+                Ok(Authentication {
+                    subject: token_subject(token),
+                    expires_at: None,
+                })
+            }
+            Credential::Signed {
+                subject,
+                expires_at,
+                signature,
+            } => {
+                let key = self.signing_key.as_ref().ok_or(Error::InvalidCredential)?;
+                let signature = hex::decode(signature).map_err(|_| Error::InvalidCredential)?;
+                signer(key, subject, *expires_at)
+                    .verify_slice(&signature)
+                    .map_err(|_| Error::InvalidCredential)?;
+
+                let authentication = Authentication {
+                    subject: subject.to_string(),
+                    expires_at: Some(*expires_at),
+                };
+                authentication.check_expiry()?;
+                Ok(authentication)
+            }
+        }
+    }
+}
+
+/// Creates a credential for `subject` that is valid until `expires_at`, in seconds since the unix epoch
+pub fn sign_credential(signing_key: &str, subject: &str, expires_at: u64) -> Credential {
+    let signature = signer(signing_key, subject, expires_at)
+        .finalize()
+        .into_bytes();
+    Credential::Signed {
+        subject: subject.to_string(),
+        expires_at,
+        signature: hex::encode(signature),
+    }
+}
+
+pub fn unix_time() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|duration| duration.as_secs())
+        .unwrap_or_default()
+}
+
+fn signer(signing_key: &str, subject: &str, expires_at: u64) -> HmacSha256 {
+    let mut mac = HmacSha256::new_from_slice(signing_key.as_bytes())
+        .expect("hmac accepts keys of any length");
+    mac.update(format!("{subject}:{expires_at}").as_bytes());
+    mac
+}
+
+// Tells holders of different tokens apart without keeping the token itself around
+fn token_subject(token: &str) -> String {
+    let digest = Sha256::digest(token.as_bytes());
+    format!("token:{}", hex::encode(&digest[..8]))
+}
+
+fn constant_time_eq(left: &[u8], right: &[u8]) -> bool {
+    left.len() == right.len()
+        && left
+            .iter()
+            .zip(right)
+            .fold(0, |difference, (left, right)| difference | (left ^ right))
+            == 0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{sign_credential, unix_time, AuthConfig};
+    use crate::{Credential, Error};
+
+    fn config() -> AuthConfig {
+        AuthConfig {
+            tokens: vec!["secret".to_string(), "other".to_string()],
+            signing_key: Some("key".to_string()),
+        }
+    }
+
+    #[test]
+    fn test_token() {
+        let token = |token: &str| Credential::Token {
+            token: token.to_string(),
+        };
+        let secret = config().verify(&token("secret")).unwrap();
+        let other = config().verify(&token("other")).unwrap();
+        assert!(secret.subject.starts_with("token:"));
+        assert_ne!(secret.subject, other.subject);
+        assert_eq!(
+            config().verify(&token("wrong")),
+            Err(Error::InvalidCredential)
+        );
+    }
+
+    #[test]
+    fn test_signed_credential() {
+        let credential = sign_credential("key", "alice", unix_time() + 60);
+        let authentication = config().verify(&credential).unwrap();
+        assert_eq!(authentication.subject, "alice");
+
+        let forged = sign_credential("other key", "alice", unix_time() + 60);
+        assert_eq!(config().verify(&forged), Err(Error::InvalidCredential));
+    }
+
+    #[test]
+    fn test_expired_credential() {
+        let credential = sign_credential("key", "alice", 1);
+        assert_eq!(
+            config().verify(&credential),
+            Err(Error::CredentialExpired { expires_at: 1 })
+        );
+    }
+
+    #[test]
+    fn test_credential_text_round_trip() {
+        let credential = sign_credential("key", "alice", 42);
+        assert_eq!(Credential::from_text(&credential.to_text()), credential);
+        assert_eq!(
+            Credential::from_text("secret"),
+            Credential::Token {
+                token: "secret".to_string()
+            }
+        );
+        assert_eq!(Credential::from_text("  "), Credential::None);
+    }
+}
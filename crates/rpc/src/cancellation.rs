@@ -0,0 +1,90 @@
+use crate::Id;
+use std::{
+    collections::HashMap,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+};
+
+/// A flag shared between the server and an executing command handler.
+/// Handlers should check it periodically and return early once it is set.
+#[derive(Default, Debug, Clone)]
+pub struct CancellationToken {
+    cancelled: Arc<AtomicBool>,
+}
+
+impl CancellationToken {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn cancel(&self) {
+        self.cancelled.store(true, Ordering::SeqCst);
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.cancelled.load(Ordering::SeqCst)
+    }
+
+    fn is_same(&self, other: &Self) -> bool {
+        Arc::ptr_eq(&self.cancelled, &other.cancelled)
+    }
+}
+
+/// The cancellation tokens of every command a connection has in flight, keyed by message id
+#[derive(Default)]
+pub struct InFlight {
+    tokens: HashMap<Id, Vec<CancellationToken>>,
+}
+
+impl InFlight {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn register(&mut self, id: &Id) -> CancellationToken {
+        let token = CancellationToken::new();
+        self.tokens
+            .entry(id.to_string())
+            .or_default()
+            .push(token.clone());
+        token
+    }
+
+    pub fn complete(&mut self, id: &Id, token: &CancellationToken) {
+        if let Some(tokens) = self.tokens.get_mut(id) {
+            tokens.retain(|registered| !registered.is_same(token));
+            if tokens.is_empty() {
+                self.tokens.remove(id);
+            }
+        }
+    }
+
+    /// Cancels every command in flight with the given message id,
+    /// returning how many commands were cancelled
+    pub fn cancel(&mut self, id: &Id) -> usize {
+        self.tokens
+            .get(id)
+            .map(|tokens| {
+                tokens.iter().for_each(CancellationToken::cancel);
+                tokens.len()
+            })
+            .unwrap_or_default()
+    }
+
+    pub fn cancel_all(&mut self) {
+        self.tokens
+            .values()
+            .flatten()
+            .for_each(CancellationToken::cancel);
+    }
+
+    pub fn len(&self) -> usize {
+        self.tokens.values().map(Vec::len).sum()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.tokens.is_empty()
+    }
+}
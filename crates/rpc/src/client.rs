@@ -1,19 +1,152 @@
-use crate::{Command, Id, Message, Response};
-use ewebsock::{WsEvent, WsMessage, WsReceiver, WsSender};
+use crate::{
+    Chunk, Codec, Command, Compression, Credential, Error, Heartbeat, Id, Message,
+    OutgoingTransfer, Received, Response, RpcMessage, RpcResult, TransferDirection,
+    TransferProgress, Transfers, Transport, TransportEvent, DEFAULT_CHUNK_SIZE,
+    DEFAULT_COMPRESSION_THRESHOLD,
+};
+use std::{
+    collections::{HashMap, VecDeque},
+    time::Duration,
+};
+use web_time::Instant;
+
+/// The message id used for authenticating, so the server's answer can be told apart
+const AUTHENTICATION_ID: &str = "authentication";
+
+/// The message id heartbeats are sent with
+const HEARTBEAT_ID: &str = "heartbeat";
+
+/// A message that was too large for one frame and is being sent in chunks
+struct Upload {
+    id: Id,
+    transfer: OutgoingTransfer,
+
+    /// Set after reconnecting, until the server reports how much it already has
+    resuming: bool,
+
+    /// Given by the server with its first acknowledgement, to resume the upload with
+    token: Option<Id>,
+}
 
 pub struct RpcClient {
-    sender: WsSender,
-    receiver: WsReceiver,
+    transport: Box<dyn Transport>,
+    chunk_size: usize,
+    uploads: HashMap<Id, Upload>,
+    downloads: Transfers,
+    progress: VecDeque<(Id, TransferProgress)>,
+
+    /// The compression proposed to the server when the connection opens
+    compression: Compression,
+    compression_threshold: u32,
+
+    /// The codec agreed with the server, uncompressed until it answers the proposal
+    codec: Codec,
+
+    /// Presented to the server when the connection opens
+    credential: Credential,
+    authentication: Option<Result<String, Error>>,
+
+    heartbeat: Heartbeat,
+    open: bool,
 }
 
 impl RpcClient {
-    pub fn new(sender: WsSender, receiver: WsReceiver) -> Self {
-        Self { sender, receiver }
+    pub fn new(transport: impl Transport + 'static) -> Self {
+        Self {
+            transport: Box::new(transport),
+            chunk_size: DEFAULT_CHUNK_SIZE,
+            uploads: HashMap::new(),
+            downloads: Transfers::new(TransferDirection::Download),
+            progress: VecDeque::new(),
+            compression: Compression::None,
+            compression_threshold: DEFAULT_COMPRESSION_THRESHOLD,
+            codec: Codec::default(),
+            credential: Credential::None,
+            authentication: None,
+            heartbeat: Heartbeat::default(),
+            open: false,
+        }
+    }
+
+    pub fn with_chunk_size(mut self, chunk_size: usize) -> Self {
+        self.chunk_size = chunk_size;
+        self
+    }
+
+    /// Compresses frames of at least `threshold` bytes, if the server agrees to it
+    pub fn with_compression(mut self, compression: Compression, threshold: u32) -> Self {
+        self.set_compression(compression, threshold);
+        self
+    }
+
+    /// Takes effect the next time the connection opens
+    pub fn set_compression(&mut self, compression: Compression, threshold: u32) {
+        self.compression = compression;
+        self.compression_threshold = threshold;
+    }
+
+    pub fn compression(&self) -> Compression {
+        self.codec.compression()
+    }
+
+    pub fn with_credential(mut self, credential: Credential) -> Self {
+        self.set_credential(credential);
+        self
+    }
+
+    /// Takes effect the next time the connection opens
+    pub fn set_credential(&mut self, credential: Credential) {
+        self.credential = credential;
+    }
+
+    /// The subject the server authenticated this client as, or why it refused.
+    /// `None` until the server has answered, or if no credential was presented.
+    pub fn authentication(&self) -> Option<&Result<String, Error>> {
+        self.authentication.as_ref()
+    }
+
+    /// Sends a heartbeat every `interval`, and considers the server gone
+    /// when nothing has been received from it for `timeout`
+    pub fn with_heartbeat(mut self, interval: Duration, timeout: Duration) -> Self {
+        self.heartbeat = Heartbeat::new(interval, timeout);
+        self
+    }
+
+    /// The round trip time of the last answered heartbeat
+    pub fn latency(&self) -> Option<Duration> {
+        self.heartbeat.latency()
+    }
+
+    /// Whether the transport has opened, and has not closed or gone silent since
+    pub fn is_open(&self) -> bool {
+        self.open
+    }
+
+    /// Whether the connection is open and the server was heard from within the liveness timeout
+    pub fn is_alive(&self) -> bool {
+        self.heartbeat.is_alive(Instant::now())
+    }
+
+    /// Replaces the transport, keeping any unfinished uploads so they resume once the new
+    /// connection opens. Uploads the server never acknowledged start over instead.
+    pub fn reconnect(&mut self, transport: impl Transport + 'static) {
+        self.transport = Box::new(transport);
+        self.codec = Codec::default();
+        self.authentication = None;
+        self.heartbeat.stop();
+        self.open = false;
+        self.uploads
+            .values_mut()
+            .for_each(|upload| upload.resuming = true);
     }
 
     pub fn send(&mut self, id: Id, command: Command) {
-        log::debug!("Executing command: {command:#?}");
-        let message = Message { id, command };
+        self.send_message(Message::new(id, command));
+    }
+
+    /// Sends a message, in chunks if it is larger than the chunk size
+    pub fn send_message(&mut self, message: Message) {
+        log::debug!("Executing command: {:#?}", message.command);
         let message_bytes = match bincode::serialize(&message) {
             Ok(bytes) => bytes,
             Err(error) => {
@@ -21,25 +154,314 @@ impl RpcClient {
                 return;
             }
         };
-        self.sender.send(WsMessage::Binary(message_bytes));
+
+        if message_bytes.len() <= self.chunk_size {
+            self.send_bytes(&message_bytes);
+            return;
+        }
+
+        let Message { id, .. } = message;
+        let transfer = OutgoingTransfer::new(message_bytes, self.chunk_size);
+        log::debug!(
+            "Uploading message in chunks as '{}'",
+            transfer.transfer_id()
+        );
+        let mut upload = Upload {
+            id,
+            transfer,
+            resuming: false,
+            token: None,
+        };
+        self.send_upload_window(&mut upload);
+        self.uploads
+            .insert(upload.transfer.transfer_id().to_string(), upload);
     }
 
     pub fn receive(&mut self) -> Option<Response> {
-        while let Some(event) = self.receiver.try_recv() {
-            log::trace!("Received websocket event: {event:?}");
-            if let WsEvent::Message(WsMessage::Binary(bytes)) = event {
-                return match bincode::deserialize::<Response>(&bytes) {
-                    Ok(response) => {
-                        log::debug!("Received RPC response: {response:#?}");
-                        Some(response)
-                    }
-                    Err(error) => {
-                        log::error!("{error}");
-                        None
+        self.send_heartbeat();
+
+        while let Some(event) = self.transport.try_recv() {
+            log::trace!("Received transport event: {event:?}");
+            match event {
+                TransportEvent::Opened => {
+                    self.open = true;
+                    self.heartbeat.start(Instant::now());
+                    self.authenticate();
+                    self.negotiate();
+                    self.resume_uploads();
+                }
+                TransportEvent::Closed | TransportEvent::Error(_) => {
+                    self.open = false;
+                    self.heartbeat.stop();
+                }
+                TransportEvent::KeepAlive => self.heartbeat.received(Instant::now()),
+                TransportEvent::Frame(frame) => {
+                    self.heartbeat.received(Instant::now());
+                    let bytes = match Codec::decode(&frame) {
+                        Ok(bytes) => bytes,
+                        Err(error) => {
+                            log::error!("{error}");
+                            return None;
+                        }
+                    };
+                    match bincode::deserialize::<Response>(&bytes) {
+                        Ok(response) => {
+                            if let Some(response) = self.receive_response(response) {
+                                log::debug!("Received RPC response: {response:#?}");
+                                return Some(response);
+                            }
+                        }
+                        Err(error) => {
+                            log::error!("{error}");
+                            return None;
+                        }
                     }
-                };
-            };
+                }
+            }
+        }
+
+        // A server that stopped answering is as good as closed, until the transport reopens
+        if self.open && !self.is_alive() {
+            log::warn!("The server was not heard from within the liveness timeout");
+            self.open = false;
+            self.heartbeat.stop();
         }
         None
     }
+
+    /// Returns the next progress update for a chunked transfer, with the message id it belongs to
+    pub fn next_progress(&mut self) -> Option<(Id, TransferProgress)> {
+        self.progress.pop_front()
+    }
+
+    // Transfer bookkeeping is consumed here, everything else is returned to the caller
+    fn receive_response(&mut self, response: Response) -> Option<Response> {
+        match response {
+            Response {
+                result:
+                    RpcResult::Success(RpcMessage::TransferAck {
+                        transfer_id,
+                        received,
+                        token,
+                    }),
+                ..
+            } => {
+                self.acknowledge_upload(&transfer_id, received, token);
+                None
+            }
+            Response {
+                id,
+                result: RpcResult::Success(RpcMessage::TransferChunk { chunk }),
+            } => self.receive_chunk(id, chunk),
+            Response {
+                result:
+                    RpcResult::Success(RpcMessage::Negotiated {
+                        compression,
+                        threshold,
+                    }),
+                ..
+            } => {
+                log::debug!("Negotiated {compression} compression above {threshold} bytes");
+                self.codec = Codec::new(compression, threshold);
+                None
+            }
+            Response {
+                result: RpcResult::Success(RpcMessage::Heartbeat { sequence }),
+                ..
+            } => {
+                self.heartbeat.answered(sequence, Instant::now());
+                None
+            }
+            Response { id, result } if id == AUTHENTICATION_ID => {
+                self.authentication = Some(match result {
+                    RpcResult::Success(RpcMessage::Authenticated { subject }) => Ok(subject),
+                    RpcResult::Error(error) => Err(error),
+                    RpcResult::Success(_) => Err(Error::UnrecognizedMessage),
+                });
+                None
+            }
+            Response {
+                id,
+                result: RpcResult::Error(Error::UnknownTransfer { transfer_id }),
+            } => {
+                // The server lost the partial upload, so it cannot be resumed
+                self.uploads.remove(&transfer_id);
+                Some(Response {
+                    id,
+                    result: RpcResult::Error(Error::UnknownTransfer { transfer_id }),
+                })
+            }
+            response => Some(response),
+        }
+    }
+
+    fn receive_chunk(&mut self, id: Id, chunk: Chunk) -> Option<Response> {
+        match self.downloads.receive(chunk) {
+            Ok(Received {
+                progress, payload, ..
+            }) => {
+                self.progress.push_back((id.to_string(), progress));
+                let bytes = payload?;
+                match bincode::deserialize::<Response>(&bytes) {
+                    Ok(response) => Some(response),
+                    Err(error) => Some(Response {
+                        id,
+                        result: RpcResult::Error(Error::RpcResultDeserialization {
+                            error: error.to_string(),
+                        }),
+                    }),
+                }
+            }
+            Err(error) => Some(Response {
+                id,
+                result: RpcResult::Error(error),
+            }),
+        }
+    }
+
+    fn acknowledge_upload(&mut self, transfer_id: &str, received: u32, token: Id) {
+        let Some(mut upload) = self.uploads.remove(transfer_id) else {
+            return;
+        };
+        upload.token = Some(token);
+
+        if upload.resuming {
+            upload.transfer.resume_from(received);
+            upload.resuming = false;
+        } else {
+            upload.transfer.acknowledge(received);
+        }
+
+        self.progress
+            .push_back((upload.id.to_string(), upload.transfer.progress()));
+
+        if upload.transfer.is_complete() {
+            log::debug!("Finished uploading '{transfer_id}'");
+            return;
+        }
+
+        self.send_upload_window(&mut upload);
+        self.uploads.insert(transfer_id.to_string(), upload);
+    }
+
+    fn send_upload_window(&mut self, upload: &mut Upload) {
+        for chunk in upload.transfer.next_window() {
+            self.send_frame(&Message::new(
+                upload.id.to_string(),
+                Command::TransferChunk { chunk },
+            ));
+        }
+    }
+
+    fn resume_uploads(&mut self) {
+        let (restarted, resumed) = std::mem::take(&mut self.uploads)
+            .into_values()
+            .partition::<Vec<_>, _>(|upload| upload.resuming && upload.token.is_none());
+
+        let requests = resumed
+            .iter()
+            .filter(|upload| upload.resuming)
+            .filter_map(|upload| {
+                let command = Command::ResumeTransfer {
+                    transfer_id: upload.transfer.transfer_id().to_string(),
+                    token: upload.token.clone()?,
+                };
+                Some(Message::new(upload.id.to_string(), command))
+            })
+            .collect::<Vec<_>>();
+        requests.iter().for_each(|message| self.send_frame(message));
+        for upload in resumed {
+            self.uploads
+                .insert(upload.transfer.transfer_id().to_string(), upload);
+        }
+
+        // Nothing proves the server kept what it received before acknowledging it
+        for mut upload in restarted {
+            upload.transfer.restart();
+            upload.resuming = false;
+            log::debug!(
+                "Uploading message in chunks again as '{}'",
+                upload.transfer.transfer_id()
+            );
+            self.send_upload_window(&mut upload);
+            self.uploads
+                .insert(upload.transfer.transfer_id().to_string(), upload);
+        }
+    }
+
+    fn send_heartbeat(&mut self) {
+        if let Some(sequence) = self.heartbeat.due(Instant::now()) {
+            self.send_frame(&Message::new(
+                HEARTBEAT_ID.to_string(),
+                Command::Heartbeat { sequence },
+            ));
+        }
+    }
+
+    fn authenticate(&mut self) {
+        if self.credential == Credential::None {
+            return;
+        }
+        let command = Command::Authenticate {
+            credential: self.credential.clone(),
+        };
+        self.send_frame(&Message::new(AUTHENTICATION_ID.to_string(), command));
+    }
+
+    fn negotiate(&mut self) {
+        if self.compression == Compression::None {
+            return;
+        }
+        let command = Command::Negotiate {
+            compression: self.compression,
+            threshold: self.compression_threshold,
+        };
+        self.send_frame(&Message::new(String::new(), command));
+    }
+
+    fn send_frame(&mut self, message: &Message) {
+        match bincode::serialize(message) {
+            Ok(bytes) => self.send_bytes(&bytes),
+            Err(error) => log::error!("{error}"),
+        }
+    }
+
+    fn send_bytes(&mut self, bytes: &[u8]) {
+        let frame = self.codec.encode(bytes);
+        self.transport.send(frame);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::RpcClient;
+    use crate::{Transport, TransportEvent};
+    use std::time::Duration;
+
+    // Opens, then never hears from the server again
+    struct Silent {
+        opened: bool,
+    }
+
+    impl Transport for Silent {
+        fn send(&mut self, _frame: Vec<u8>) {}
+
+        fn try_recv(&mut self) -> Option<TransportEvent> {
+            let opened = std::mem::replace(&mut self.opened, true);
+            (!opened).then_some(TransportEvent::Opened)
+        }
+    }
+
+    #[test]
+    fn test_liveness_timeout() {
+        let mut client = RpcClient::new(Silent { opened: false })
+            .with_heartbeat(Duration::from_secs(60), Duration::from_millis(10));
+        client.receive();
+        assert!(client.is_open());
+
+        std::thread::sleep(Duration::from_millis(20));
+        client.receive();
+        assert!(!client.is_open());
+        assert!(!client.is_alive());
+    }
 }
@@ -0,0 +1,155 @@
+use crate::Error;
+use enum2str::EnumStr;
+use serde::{Deserialize, Serialize};
+
+#[cfg(feature = "gui")]
+use enum2egui::{egui, Gui, GuiInspect};
+
+/// Frames smaller than this are not worth compressing
+pub const DEFAULT_COMPRESSION_THRESHOLD: u32 = 1024;
+
+#[derive(Default, Debug, Copy, Clone, PartialEq, Eq, Serialize, Deserialize, EnumStr)]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
+#[cfg_attr(feature = "gui", derive(Gui))]
+pub enum Compression {
+    #[default]
+    None,
+
+    Lz4,
+}
+
+impl Compression {
+    /// The compression algorithms this build can decode
+    pub const SUPPORTED: [Compression; 2] = [Compression::None, Compression::Lz4];
+
+    fn header(&self) -> u8 {
+        match self {
+            Self::None => 0,
+            Self::Lz4 => 1,
+        }
+    }
+
+    fn from_header(header: u8) -> Option<Self> {
+        Self::SUPPORTED
+            .into_iter()
+            .find(|compression| compression.header() == header)
+    }
+}
+
+/// Encodes serialized messages into websocket frames.
+/// Every frame starts with a one byte header naming the compression applied to the rest,
+/// so either side can decode a frame without knowing what the other side agreed to.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct Codec {
+    compression: Compression,
+    threshold: usize,
+}
+
+impl Default for Codec {
+    fn default() -> Self {
+        Self::new(Compression::None, DEFAULT_COMPRESSION_THRESHOLD)
+    }
+}
+
+impl Codec {
+    pub fn new(compression: Compression, threshold: u32) -> Self {
+        Self {
+            compression,
+            threshold: threshold as usize,
+        }
+    }
+
+    pub fn compression(&self) -> Compression {
+        self.compression
+    }
+
+    pub fn encode(&self, bytes: &[u8]) -> Vec<u8> {
+        let compression = if bytes.len() >= self.threshold {
+            self.compression
+        } else {
+            Compression::None
+        };
+
+        let mut frame = vec![compression.header()];
+        match compression {
+            Compression::None => frame.extend_from_slice(bytes),
+            Compression::Lz4 => frame.extend(lz4_flex::compress_prepend_size(bytes)),
+        }
+        frame
+    }
+
+    pub fn decode(frame: &[u8]) -> Result<Vec<u8>, Error> {
+        Self::decode_with_limit(frame, u64::MAX)
+    }
+
+    /// Decodes a frame, failing with `Error::PayloadTooLarge` before decompressing
+    /// anything that would grow beyond `limit` bytes
+    pub fn decode_with_limit(frame: &[u8], limit: u64) -> Result<Vec<u8>, Error> {
+        let (header, payload) = frame.split_first().ok_or_else(|| Error::Decompression {
+            error: "The frame is empty".to_string(),
+        })?;
+
+        match Compression::from_header(*header) {
+            Some(Compression::None) => Ok(payload.to_vec()),
+            Some(Compression::Lz4) => {
+                // lz4_flex prepends the decompressed size as a little endian u32
+                if let Some(size) = payload.get(..4) {
+                    let size = u32::from_le_bytes([size[0], size[1], size[2], size[3]]) as u64;
+                    if size > limit {
+                        return Err(Error::PayloadTooLarge { size, limit });
+                    }
+                }
+                lz4_flex::decompress_size_prepended(payload).map_err(|error| Error::Decompression {
+                    error: error.to_string(),
+                })
+            }
+            None => Err(Error::Decompression {
+                error: format!("Unknown compression header {header}"),
+            }),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Codec, Compression};
+    use crate::Error;
+
+    #[test]
+    fn test_round_trip() {
+        let codec = Codec::new(Compression::Lz4, 16);
+        let bytes = b"hello hello hello hello hello hello".to_vec();
+        let frame = codec.encode(&bytes);
+        assert!(frame.len() < bytes.len());
+        assert_eq!(Codec::decode(&frame).unwrap(), bytes);
+    }
+
+    #[test]
+    fn test_below_threshold_is_not_compressed() {
+        let codec = Codec::new(Compression::Lz4, 1024);
+        let frame = codec.encode(b"small");
+        assert_eq!(frame[0], Compression::None.header());
+        assert_eq!(Codec::decode(&frame).unwrap(), b"small");
+    }
+
+    #[test]
+    fn test_limit() {
+        let codec = Codec::new(Compression::Lz4, 16);
+        let frame = codec.encode(&[0; 4096]);
+        assert!(frame.len() < 100);
+        assert_eq!(
+            Codec::decode_with_limit(&frame, 1024),
+            Err(Error::PayloadTooLarge {
+                size: 4096,
+                limit: 1024
+            })
+        );
+        assert!(Codec::decode_with_limit(&frame, 4096).is_ok());
+    }
+
+    #[test]
+    fn test_unknown_header() {
+        assert!(Codec::decode(&[42, 1, 2, 3]).is_err());
+        assert!(Codec::decode(&[]).is_err());
+    }
+}
@@ -1,3 +1,4 @@
+use crate::{Chunk, Compression};
 use enum2str::EnumStr;
 use serde::{Deserialize, Serialize};
 
@@ -10,13 +11,35 @@ pub type PayloadBytes = Vec<u8>;
 pub type PayloadJson = String;
 pub type IpAddress = String;
 
-#[derive(Default, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[derive(Default, Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
 pub struct Message {
     pub id: Id,
     pub command: Command,
+
+    /// Servers answer a key repeated by the same client, or by a client authenticated as the
+    /// same subject, with the result of the first command instead of running it again,
+    /// so commands can be retried safely
+    pub idempotency_key: Option<Id>,
+}
+
+impl Message {
+    pub fn new(id: Id, command: Command) -> Self {
+        Self {
+            id,
+            command,
+            idempotency_key: None,
+        }
+    }
+
+    pub fn with_idempotency_key(mut self, idempotency_key: Id) -> Self {
+        self.idempotency_key = Some(idempotency_key);
+        self
+    }
 }
 
 #[derive(Default, Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
 #[cfg_attr(feature = "gui", derive(Gui))]
 pub struct Response {
     pub id: Id,
@@ -24,6 +47,7 @@ pub struct Response {
 }
 
 #[derive(Debug, Default, Serialize, Deserialize, EnumStr, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
 #[cfg_attr(feature = "gui", derive(Gui))]
 pub enum RpcMessage {
     #[default]
@@ -36,16 +60,232 @@ pub enum RpcMessage {
     ConnectionStatus {
         connected: bool,
     },
+
+    /// A slice of a response that was too large to send in one frame
+    TransferChunk {
+        chunk: Chunk,
+    },
+
+    /// Confirms that the first `received` chunks of an upload have arrived
+    TransferAck {
+        transfer_id: Id,
+        received: u32,
+
+        /// Resumes the upload from another connection with `Command::ResumeTransfer`
+        token: Id,
+    },
+
+    /// The compression both sides will use for frames of at least `threshold` bytes
+    Negotiated {
+        compression: Compression,
+        threshold: u32,
+    },
+
+    /// The connection's credential was accepted
+    Authenticated {
+        subject: String,
+    },
+
+    /// A JSON Schema document describing the contract types
+    Schema {
+        schema: PayloadJson,
+    },
+
+    /// The serialized return value of a service method
+    Service {
+        service: String,
+        call_id: Id,
+        payload: PayloadJson,
+    },
+
+    /// Answers `Command::Heartbeat` with the same sequence number
+    Heartbeat {
+        sequence: u64,
+    },
+
+    /// The connection is now a member of `room`, along with `members - 1` others
+    RoomJoined {
+        room: String,
+        members: usize,
+    },
+
+    /// The connection is no longer a member of `room`
+    RoomLeft {
+        room: String,
+    },
+
+    /// Answers `Command::Broadcast` with how many other members the payload was sent to
+    Broadcasted {
+        room: String,
+        recipients: usize,
+    },
+
+    /// A payload broadcast to a room the connection is a member of, sent unrequested.
+    /// `sender` is the client id of the connection that broadcast it.
+    RoomMessage {
+        room: String,
+        sender: Id,
+        payload: PayloadJson,
+    },
+}
+
+impl RpcMessage {
+    /// The variant's name, which is all a metric label or log line needs
+    pub fn name(&self) -> &'static str {
+        match self {
+            Self::Empty => "Empty",
+            Self::ClientId { .. } => "ClientId",
+            Self::ConnectionStatus { .. } => "ConnectionStatus",
+            Self::TransferChunk { .. } => "TransferChunk",
+            Self::TransferAck { .. } => "TransferAck",
+            Self::Negotiated { .. } => "Negotiated",
+            Self::Authenticated { .. } => "Authenticated",
+            Self::Schema { .. } => "Schema",
+            Self::Service { .. } => "Service",
+            Self::Heartbeat { .. } => "Heartbeat",
+            Self::RoomJoined { .. } => "RoomJoined",
+            Self::RoomLeft { .. } => "RoomLeft",
+            Self::Broadcasted { .. } => "Broadcasted",
+            Self::RoomMessage { .. } => "RoomMessage",
+        }
+    }
 }
 
 #[derive(Debug, Default, Serialize, Deserialize, EnumStr, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
 #[cfg_attr(feature = "gui", derive(Gui))]
 pub enum Command {
     #[default]
     Example,
+
+    /// Cancels every command in flight that was sent with the message id `id`
+    Cancel { id: Id },
+
+    /// A slice of a message that was too large to send in one frame
+    TransferChunk { chunk: Chunk },
+
+    /// Takes over an interrupted upload with the token its acknowledgements carried,
+    /// answered with `RpcMessage::TransferAck` for how much of it has arrived
+    ResumeTransfer { transfer_id: Id, token: Id },
+
+    /// Proposes a compression algorithm for the connection, answered with `RpcMessage::Negotiated`
+    Negotiate {
+        compression: Compression,
+        threshold: u32,
+    },
+
+    /// Presents a credential, which servers that require authentication
+    /// need before they accept any other command
+    Authenticate { credential: Credential },
+
+    /// Asks for the JSON Schema of the contract, answered with `RpcMessage::Schema`
+    Schema,
+
+    /// Calls a method of a service registered with the executor, answered with `RpcMessage::Service`.
+    /// The payload is the JSON of the service's generated request enum.
+    Service {
+        service: String,
+        call_id: Id,
+        payload: PayloadJson,
+    },
+
+    /// Checks that the server is still there and measures the round trip,
+    /// answered with `RpcMessage::Heartbeat`
+    Heartbeat { sequence: u64 },
+
+    /// Joins a named room, creating it if needed, answered with `RpcMessage::RoomJoined`
+    JoinRoom { room: String },
+
+    /// Leaves a room, answered with `RpcMessage::RoomLeft`
+    LeaveRoom { room: String },
+
+    /// Sends a payload to every other member of a room the connection has joined,
+    /// who receive it as `RpcMessage::RoomMessage`
+    Broadcast { room: String, payload: PayloadJson },
+}
+
+impl Command {
+    /// The variant's name, which is all a metric label or log line needs
+    pub fn name(&self) -> &'static str {
+        match self {
+            Self::Example => "Example",
+            Self::Cancel { .. } => "Cancel",
+            Self::TransferChunk { .. } => "TransferChunk",
+            Self::ResumeTransfer { .. } => "ResumeTransfer",
+            Self::Negotiate { .. } => "Negotiate",
+            Self::Authenticate { .. } => "Authenticate",
+            Self::Schema => "Schema",
+            Self::Service { .. } => "Service",
+            Self::Heartbeat { .. } => "Heartbeat",
+            Self::JoinRoom { .. } => "JoinRoom",
+            Self::LeaveRoom { .. } => "LeaveRoom",
+            Self::Broadcast { .. } => "Broadcast",
+        }
+    }
+}
+
+#[derive(Debug, Default, Serialize, Deserialize, EnumStr, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
+#[cfg_attr(feature = "gui", derive(Gui))]
+pub enum Credential {
+    #[default]
+    None,
+
+    /// A pre-shared token from the server's configuration
+    Token { token: String },
+
+    /// An hmac-sha256 signature of `{subject}:{expires_at}` made with the server's signing key
+    Signed {
+        subject: String,
+        expires_at: u64,
+        signature: String,
+    },
+}
+
+impl Credential {
+    const SIGNED_PREFIX: &'static str = "signed:";
+
+    /// Parses the text a user pastes into a credential field.
+    /// Signed credentials are written as `signed:{subject}:{expires_at}:{signature}`,
+    /// anything else is treated as a token.
+    pub fn from_text(text: &str) -> Self {
+        let text = text.trim();
+        if text.is_empty() {
+            return Self::None;
+        }
+
+        let signed = text.strip_prefix(Self::SIGNED_PREFIX).and_then(|signed| {
+            let mut parts = signed.rsplitn(3, ':');
+            let signature = parts.next()?;
+            let expires_at = parts.next()?.parse().ok()?;
+            let subject = parts.next()?;
+            Some(Self::Signed {
+                subject: subject.to_string(),
+                expires_at,
+                signature: signature.to_string(),
+            })
+        });
+
+        signed.unwrap_or_else(|| Self::Token {
+            token: text.to_string(),
+        })
+    }
+
+    pub fn to_text(&self) -> String {
+        match self {
+            Self::None => String::new(),
+            Self::Token { token } => token.to_string(),
+            Self::Signed {
+                subject,
+                expires_at,
+                signature,
+            } => format!("{}{subject}:{expires_at}:{signature}", Self::SIGNED_PREFIX),
+        }
+    }
 }
 
 #[derive(Debug, Serialize, Deserialize, EnumStr, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
 #[cfg_attr(feature = "gui", derive(Gui))]
 pub enum RpcResult {
     Success(RpcMessage),
@@ -65,6 +305,7 @@ impl RpcResult {
 }
 
 #[derive(Default, Debug, Serialize, Deserialize, EnumStr, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
 #[cfg_attr(feature = "gui", derive(Gui))]
 pub enum Error {
     #[default]
@@ -73,6 +314,9 @@ pub enum Error {
     #[enum2str("The RPC operation timed out.")]
     Timeout,
 
+    #[enum2str("The RPC operation was cancelled.")]
+    Cancelled,
+
     #[enum2str("The client '{id}' has not been created yet.")]
     UnknownClientId { id: Id },
 
@@ -94,6 +338,73 @@ pub enum Error {
     #[enum2str("An unexpected message was received.")]
     UnrecognizedMessage,
 
+    #[enum2str("The server failed to run the command. Error: {error}")]
+    Internal { error: String },
+
+    #[enum2str(
+        "Transfer '{transfer_id}' received chunk {received} while expecting chunk {expected}."
+    )]
+    TransferSequence {
+        transfer_id: Id,
+        expected: u32,
+        received: u32,
+    },
+
+    #[enum2str("Transfer '{transfer_id}' failed its checksum.")]
+    TransferChecksum { transfer_id: Id },
+
+    #[enum2str("The transfer '{transfer_id}' is not in progress.")]
+    UnknownTransfer { transfer_id: Id },
+
+    #[enum2str("Failed to decompress a frame. Error: {error}")]
+    Decompression { error: String },
+
+    #[enum2str("The connection must authenticate before sending commands.")]
+    Unauthenticated,
+
+    #[enum2str("The credential was rejected.")]
+    InvalidCredential,
+
+    #[enum2str("The credential expired at {expires_at} (seconds since the unix epoch).")]
+    CredentialExpired { expires_at: u64 },
+
+    #[enum2str("The server does not provide a schema.")]
+    SchemaUnavailable,
+
+    #[enum2str("The service '{service}' is not registered (call '{call_id}').")]
+    UnknownService { service: String, call_id: Id },
+
+    #[enum2str("The service '{service}' failed call '{call_id}'. Error: {error}")]
+    Service {
+        service: String,
+        call_id: Id,
+        error: String,
+    },
+
+    #[enum2str("The command was not sent because {capacity} commands are already waiting for a connection.")]
+    QueueFull { capacity: usize },
+
+    #[enum2str("The command expired while waiting for a connection.")]
+    QueueExpired,
+
+    #[enum2str("A command with the idempotency key '{key}' is still running.")]
+    IdempotencyConflict { key: Id },
+
+    #[enum2str("The frame of {size} bytes is larger than the limit of {limit} bytes.")]
+    FrameTooLarge { size: u64, limit: u64 },
+
+    #[enum2str("The message of {size} bytes is larger than the limit of {limit} bytes.")]
+    PayloadTooLarge { size: u64, limit: u64 },
+
+    #[enum2str("Too many messages were sent. Retry in {retry_after_ms} ms.")]
+    RateLimited { retry_after_ms: u64 },
+
+    #[enum2str("Failed to deserialize a message. Error: {error}")]
+    MessageDeserialization { error: String },
+
+    #[enum2str("The connection is not a member of the room '{room}'.")]
+    NotInRoom { room: String },
+
     #[enum2str("Failed to serialize a command. Error: {error}")]
     CommandSerialization { error: String },
 
@@ -121,3 +432,46 @@ pub enum Error {
         error: String,
     },
 }
+
+impl Error {
+    /// The variant's name, which is all a metric label or log line needs
+    pub fn name(&self) -> &'static str {
+        match self {
+            Self::Empty => "Empty",
+            Self::Timeout => "Timeout",
+            Self::Cancelled => "Cancelled",
+            Self::UnknownClientId { .. } => "UnknownClientId",
+            Self::UnknownSpawnerId { .. } => "UnknownSpawnerId",
+            Self::UnknownSpawnerApp { .. } => "UnknownSpawnerApp",
+            Self::Connection => "Connection",
+            Self::Spawner { .. } => "Spawner",
+            Self::RpcResultDeserialization { .. } => "RpcResultDeserialization",
+            Self::UnrecognizedMessage => "UnrecognizedMessage",
+            Self::Internal { .. } => "Internal",
+            Self::TransferSequence { .. } => "TransferSequence",
+            Self::TransferChecksum { .. } => "TransferChecksum",
+            Self::UnknownTransfer { .. } => "UnknownTransfer",
+            Self::Decompression { .. } => "Decompression",
+            Self::Unauthenticated => "Unauthenticated",
+            Self::InvalidCredential => "InvalidCredential",
+            Self::CredentialExpired { .. } => "CredentialExpired",
+            Self::SchemaUnavailable => "SchemaUnavailable",
+            Self::UnknownService { .. } => "UnknownService",
+            Self::Service { .. } => "Service",
+            Self::QueueFull { .. } => "QueueFull",
+            Self::QueueExpired => "QueueExpired",
+            Self::IdempotencyConflict { .. } => "IdempotencyConflict",
+            Self::FrameTooLarge { .. } => "FrameTooLarge",
+            Self::PayloadTooLarge { .. } => "PayloadTooLarge",
+            Self::RateLimited { .. } => "RateLimited",
+            Self::MessageDeserialization { .. } => "MessageDeserialization",
+            Self::NotInRoom { .. } => "NotInRoom",
+            Self::CommandSerialization { .. } => "CommandSerialization",
+            Self::Subscription { .. } => "Subscription",
+            Self::Publish { .. } => "Publish",
+            Self::PublishJson { .. } => "PublishJson",
+            Self::RequestBridge { .. } => "RequestBridge",
+            Self::RemoveBridge { .. } => "RemoveBridge",
+        }
+    }
+}
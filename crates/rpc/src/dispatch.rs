@@ -0,0 +1,1223 @@
+use crate::{
+    AuthConfig, Authentication, CancellationToken, Chunk, Codec, Command, Compression, Credential,
+    Error, Id, Idempotency, IdempotencyCache, InFlight, JsonRpcRequest, Limits, Message,
+    OutgoingTransfer, PayloadJson, Received, Response, Rooms, RpcExecutor, RpcMessage, RpcResult,
+    Sessions, TokenBucket, Uploads, DEFAULT_CHUNK_SIZE, DEFAULT_UPLOAD_TTL,
+};
+use std::{
+    collections::HashMap,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Mutex, MutexGuard,
+    },
+    time::{Duration, Instant},
+};
+
+/// Frames smaller than this are never compressed, whatever the client proposes
+const MIN_COMPRESSION_THRESHOLD: u32 = 64;
+
+/// What a connection sends its client
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Outgoing {
+    /// A `Response` encoded with the connection's codec
+    Binary(Vec<u8>),
+
+    /// A JSON-RPC notification, for clients that send text frames
+    Text(String),
+}
+
+/// A command the executor ran, or one the backend answered itself
+pub struct Execution<'a> {
+    pub peer: &'a str,
+    pub client_id: &'a str,
+    pub command: &'static str,
+    pub duration: Duration,
+    pub result: &'a RpcResult,
+}
+
+/// Told what a backend does, for metrics and audit logs
+pub trait Observer: Send + Sync {
+    fn executed(&self, _execution: &Execution<'_>) {}
+
+    /// Called for commands answered without the executor: authentication, negotiation,
+    /// rooms and transfers, and messages refused before they could run. Heartbeats and
+    /// chunks are left out, as they only keep connections and uploads going.
+    /// Messages refused before they could be read are reported as `UNREAD`.
+    fn handled(&self, _execution: &Execution<'_>) {}
+
+    /// Called with every result a connection sends, errors included
+    fn answered(&self, _result: &RpcResult) {}
+}
+
+impl Observer for () {}
+
+/// The command name `Observer::handled` reports for messages that could not be read
+pub const UNREAD: &str = "Unread";
+
+/// Sees the messages connections read before they are dispatched
+type Interceptor = dyn Fn(&BackendConnection, Message) -> Option<Message> + Send + Sync;
+
+/// What every transport a server offers dispatches messages to, so clients get the same
+/// answers, limits and guarantees however they connect
+pub struct Backend {
+    // Commands only borrow the executor, and lock the session of the client that sent them
+    executor: RpcExecutor,
+    sessions: Mutex<Sessions>,
+
+    // Uploads outlive the connection that started them so they can be resumed
+    // from another one, by whoever holds their token
+    uploads: Mutex<Uploads>,
+    upload_ttl: Duration,
+
+    // Retried commands may arrive on a new connection, so results are kept backend-wide,
+    // keyed by owner and idempotency key so clients can't read each other's results
+    idempotency: Mutex<IdempotencyCache>,
+
+    // Every open connection, so broadcasts can reach the members of a room
+    connections: Mutex<HashMap<Id, BackendConnection>>,
+    rooms: Mutex<Rooms>,
+
+    auth: AuthConfig,
+    limits: Limits,
+    observer: Box<dyn Observer>,
+    interceptor: Option<Box<Interceptor>>,
+}
+
+impl Backend {
+    pub fn new(executor: RpcExecutor) -> Self {
+        Self {
+            executor,
+            sessions: Mutex::new(Sessions::new()),
+            uploads: Mutex::new(Uploads::new(
+                Limits::default().max_payload_size,
+                DEFAULT_UPLOAD_TTL,
+            )),
+            upload_ttl: DEFAULT_UPLOAD_TTL,
+            idempotency: Mutex::new(IdempotencyCache::default()),
+            connections: Mutex::new(HashMap::new()),
+            rooms: Mutex::new(Rooms::new()),
+            auth: AuthConfig::default(),
+            limits: Limits::default(),
+            observer: Box::new(()),
+            interceptor: None,
+        }
+    }
+
+    pub fn with_auth(mut self, auth: AuthConfig) -> Self {
+        self.auth = auth;
+        self
+    }
+
+    pub fn with_limits(mut self, limits: Limits) -> Self {
+        self.uploads = Mutex::new(Uploads::new(limits.max_payload_size, self.upload_ttl));
+        self.limits = limits;
+        self
+    }
+
+    /// Discards unfinished uploads that received nothing for `ttl`
+    pub fn with_upload_ttl(mut self, ttl: Duration) -> Self {
+        self.uploads = Mutex::new(Uploads::new(self.limits.max_payload_size, ttl));
+        self.upload_ttl = ttl;
+        self
+    }
+
+    pub fn with_observer(mut self, observer: impl Observer + 'static) -> Self {
+        self.observer = Box::new(observer);
+        self
+    }
+
+    /// Hands every binary message to `interceptor` before dispatching it, with chunked
+    /// messages reassembled. Returning `None` takes the message out of the dispatch,
+    /// so a test double can answer it itself through the connection.
+    pub fn with_interceptor(
+        mut self,
+        interceptor: impl Fn(&BackendConnection, Message) -> Option<Message> + Send + Sync + 'static,
+    ) -> Self {
+        self.interceptor = Some(Box::new(interceptor));
+        self
+    }
+
+    pub fn executor(&self) -> &RpcExecutor {
+        &self.executor
+    }
+
+    pub fn auth(&self) -> &AuthConfig {
+        &self.auth
+    }
+
+    pub fn limits(&self) -> &Limits {
+        &self.limits
+    }
+
+    /// The sessions of the connected clients
+    pub fn sessions(&self) -> MutexGuard<'_, Sessions> {
+        lock(&self.sessions)
+    }
+
+    /// The results of commands sent with an idempotency key, for clients that don't go through
+    /// a `BackendConnection`. Keys should be prefixed with their owner, as connections do.
+    pub fn idempotency(&self) -> MutexGuard<'_, IdempotencyCache> {
+        lock(&self.idempotency)
+    }
+
+    /// How many clients are connected
+    pub fn connections(&self) -> usize {
+        lock(&self.connections).len()
+    }
+
+    /// Opens a session for a client and sends it its client id.
+    /// Everything the connection sends goes to `outbox`, in order.
+    pub fn connect(
+        self: &Arc<Self>,
+        peer: &str,
+        outbox: impl Fn(Outgoing) + Send + Sync + 'static,
+    ) -> BackendConnection {
+        let connection = self.open(peer, None, outbox);
+        let client_id = connection.client_id().to_string();
+        connection.respond(Response {
+            id: client_id.to_string(),
+            result: RpcResult::value(RpcMessage::ClientId { id: client_id }),
+        });
+        connection
+    }
+
+    /// Opens a session for a single request, like a command POSTed over HTTP, whose credential
+    /// the transport verified already. Nothing is sent on the connection, the request is answered
+    /// with what `BackendConnection::handle` or `BackendConnection::execute` return. Without
+    /// authentication, idempotency keys only apply within the request.
+    pub fn connect_once(
+        self: &Arc<Self>,
+        peer: &str,
+        authentication: Option<Authentication>,
+    ) -> BackendConnection {
+        self.open(peer, authentication, |_| {})
+    }
+
+    fn open(
+        self: &Arc<Self>,
+        peer: &str,
+        authentication: Option<Authentication>,
+        outbox: impl Fn(Outgoing) + Send + Sync + 'static,
+    ) -> BackendConnection {
+        let client_id = lock(&self.sessions).open(peer);
+        log::info!("Opened session '{client_id}' for {peer}");
+
+        let connection = BackendConnection {
+            state: Arc::new(ConnectionState {
+                client_id: client_id.to_string(),
+                peer: peer.to_string(),
+                backend: self.clone(),
+                in_flight: Mutex::new(InFlight::new()),
+                codec: Mutex::new(Codec::default()),
+                authentication: Mutex::new(authentication),
+                rate_limiter: Mutex::new(
+                    self.limits
+                        .rate_limit
+                        .map(|limit| TokenBucket::new(limit, Instant::now())),
+                ),
+                json_rpc: AtomicBool::new(false),
+                outbox: Box::new(outbox),
+            }),
+        };
+        lock(&self.connections).insert(client_id, connection.clone());
+        connection
+    }
+
+    // Sends a payload to every member of a room except `sender`,
+    // returning how many connections it was sent to
+    fn broadcast(&self, room: &str, sender: &str, payload: &PayloadJson) -> usize {
+        let members = lock(&self.rooms)
+            .members(room)
+            .filter(|member| *member != sender)
+            .cloned()
+            .collect::<Vec<_>>();
+        let recipients = {
+            let connections = lock(&self.connections);
+            members
+                .iter()
+                .filter_map(|member| connections.get(member).cloned())
+                .collect::<Vec<_>>()
+        };
+
+        let message = RpcMessage::RoomMessage {
+            room: room.to_string(),
+            sender: sender.to_string(),
+            payload: payload.to_string(),
+        };
+        log::info!(
+            "[RPC <-]: Broadcasting to {} member(s) of room '{room}'",
+            recipients.len()
+        );
+        recipients
+            .iter()
+            .for_each(|connection| connection.push(room, message.clone()));
+        recipients.len()
+    }
+}
+
+/// What `BackendConnection::handle` did with a message
+pub enum Handled {
+    /// Answered without the executor
+    Answered(RpcResult),
+
+    /// Cancellations have nothing to answer
+    Unanswered,
+
+    /// A command for `BackendConnection::execute`, which the transport may
+    /// run in the background while it keeps reading cancellations
+    Queued(Job),
+}
+
+/// A command waiting for the executor
+#[derive(Debug)]
+pub struct Job {
+    id: Id,
+    command: Command,
+
+    // Prefixed with the owner of the connection that sent it
+    idempotency_key: Option<Id>,
+    cancellation: CancellationToken,
+}
+
+impl Job {
+    pub fn id(&self) -> &Id {
+        &self.id
+    }
+}
+
+/// One client of a `Backend`. Clones share the connection, so commands
+/// running in the background can answer on it.
+#[derive(Clone)]
+pub struct BackendConnection {
+    state: Arc<ConnectionState>,
+}
+
+struct ConnectionState {
+    client_id: Id,
+    peer: String,
+    backend: Arc<Backend>,
+    in_flight: Mutex<InFlight>,
+    codec: Mutex<Codec>,
+    authentication: Mutex<Option<Authentication>>,
+    rate_limiter: Mutex<Option<TokenBucket>>,
+
+    // Set once the client sends a text frame, so pushed messages are sent as JSON-RPC
+    json_rpc: AtomicBool,
+
+    outbox: Box<dyn Fn(Outgoing) + Send + Sync>,
+}
+
+impl BackendConnection {
+    pub fn client_id(&self) -> &Id {
+        &self.state.client_id
+    }
+
+    pub fn peer(&self) -> &str {
+        &self.state.peer
+    }
+
+    pub fn backend(&self) -> &Arc<Backend> {
+        &self.state.backend
+    }
+
+    /// Cancels the commands the client started and closes its session.
+    /// Nothing is sent on the connection afterwards, apart from the answers
+    /// of commands that were already running. Closing it again only cancels commands.
+    pub fn close(&self) {
+        // Nobody is left to receive the results of the commands this client started
+        lock(&self.state.in_flight).cancel_all();
+
+        let backend = self.backend();
+        let client_id = self.client_id();
+        if lock(&backend.connections).remove(client_id).is_none() {
+            return;
+        }
+        lock(&backend.rooms).leave_all(client_id);
+        lock(&backend.sessions).close(client_id);
+        log::info!("Closed session '{client_id}' for {}", self.peer());
+    }
+
+    /// Sends messages the client did not ask for as JSON-RPC notifications from now on
+    pub fn use_json_rpc(&self) {
+        self.state.json_rpc.store(true, Ordering::Relaxed);
+    }
+
+    /// Reads a binary frame and answers it, unless it completes a command for the executor
+    pub fn receive_frame(&self, frame: &[u8]) -> Option<Job> {
+        if let Err(error) = self.check_frame_size(frame.len()) {
+            self.reject(String::new(), error);
+            return None;
+        }
+        let max_payload_size = self.backend().limits.max_payload_size;
+        let message = Codec::decode_with_limit(frame, max_payload_size)
+            .and_then(|bytes| crate::deserialize_limited::<Message>(&bytes, max_payload_size));
+
+        match message {
+            Ok(message) => match self.check_rate_limit(&message.command) {
+                Ok(()) => self.receive_message(message),
+                Err(error) => {
+                    self.respond(Response {
+                        id: message.id,
+                        result: RpcResult::Error(error),
+                    });
+                    None
+                }
+            },
+            Err(error) => {
+                self.refused(UNREAD, &error);
+                self.reject(String::new(), error);
+                None
+            }
+        }
+    }
+
+    /// Refuses frames over the size limit, before they are read
+    pub fn check_frame_size(&self, size: usize) -> Result<(), Error> {
+        let limit = self.backend().limits.max_frame_size;
+        if size > limit {
+            let error = Error::FrameTooLarge {
+                size: size as u64,
+                limit: limit as u64,
+            };
+            self.refused(UNREAD, &error);
+            return Err(error);
+        }
+        Ok(())
+    }
+
+    /// Takes a message off the client's rate limit. Heartbeats are exempt,
+    /// so a throttled client is not mistaken for a dead one.
+    pub fn check_rate_limit(&self, command: &Command) -> Result<(), Error> {
+        if matches!(command, Command::Heartbeat { .. }) {
+            return Ok(());
+        }
+        let taken = match lock(&self.state.rate_limiter).as_mut() {
+            Some(bucket) => bucket.take(Instant::now()),
+            None => Ok(()),
+        };
+        if let Err(error) = &taken {
+            self.refused(command.name(), error);
+        }
+        taken
+    }
+
+    /// Dispatches a message that has been read and admitted. Authentication and heartbeats
+    /// are the only commands accepted before the connection authenticates, and commands
+    /// sent again with an idempotency key are answered with the first one's result.
+    /// Negotiation and chunks only apply to binary frames, which handle them before this.
+    pub fn handle(&self, message: Message) -> Handled {
+        let Message {
+            id,
+            command,
+            idempotency_key,
+        } = message;
+        let started = Instant::now();
+        let name = command.name();
+        let result = match command {
+            Command::Authenticate { credential } => self.authenticate(&credential),
+            Command::Heartbeat { sequence } => {
+                return Handled::Answered(RpcResult::value(RpcMessage::Heartbeat { sequence }))
+            }
+            command => match self.check_authentication() {
+                Ok(()) => match self.handle_command(id, command, idempotency_key) {
+                    Handled::Answered(result) => result,
+                    handled => return handled,
+                },
+                Err(error) => RpcResult::Error(error),
+            },
+        };
+        self.handled(name, started.elapsed(), &result);
+        Handled::Answered(result)
+    }
+
+    /// Runs a command on the executor, blocking until it finishes
+    pub fn execute(&self, job: Job) -> Response {
+        let Job {
+            id,
+            command,
+            idempotency_key,
+            cancellation,
+        } = job;
+        let running = Running {
+            connection: self,
+            id: &id,
+            cancellation: &cancellation,
+            idempotency_key: idempotency_key.as_ref(),
+        };
+
+        log::info!("[RPC ->]: {command:#?}");
+        let started = Instant::now();
+        let name = command.name();
+        let session = lock(&self.backend().sessions).get(self.client_id());
+        let result = match session {
+            Some(session) => {
+                let mut session = lock(&session);
+                if let Some(authentication) = lock(&self.state.authentication).as_ref() {
+                    session.authenticate(&authentication.subject);
+                }
+                let executor = &self.backend().executor;
+                executor.execute(&mut session, &id, command, &cancellation)
+            }
+            None => RpcResult::Error(Error::UnknownClientId {
+                id: self.client_id().to_string(),
+            }),
+        };
+
+        // A handler that finishes after being cancelled has its result discarded
+        let result = if cancellation.is_cancelled() {
+            RpcResult::Error(Error::Cancelled)
+        } else {
+            result
+        };
+        self.backend().observer.executed(&Execution {
+            peer: self.peer(),
+            client_id: self.client_id(),
+            command: name,
+            duration: started.elapsed(),
+            result: &result,
+        });
+        running.finish(&result);
+
+        Response {
+            id: id.to_string(),
+            result,
+        }
+    }
+
+    /// Sends a response, in chunks if it is too large for one frame.
+    /// A `RpcMessage::Negotiated` answer switches the codec for the frames after it,
+    /// since the client only switches codecs once it has read it.
+    pub fn respond(&self, response: Response) {
+        self.backend().observer.answered(&response.result);
+        let response_bytes = match bincode::serialize(&response) {
+            Ok(bytes) => bytes,
+            Err(error) => {
+                log::error!("Failed to serialize response: {error}");
+                return;
+            }
+        };
+
+        if response_bytes.len() <= DEFAULT_CHUNK_SIZE {
+            log::info!("[RPC <-]: {response:#?}");
+            self.send_frame(&response_bytes);
+            if let RpcResult::Success(RpcMessage::Negotiated {
+                compression,
+                threshold,
+            }) = response.result
+            {
+                *lock(&self.state.codec) = Codec::new(compression, threshold);
+            }
+            return;
+        }
+
+        let Response { id, .. } = response;
+        let mut transfer = OutgoingTransfer::new(response_bytes, DEFAULT_CHUNK_SIZE);
+        log::info!(
+            "[RPC <-]: Sending response for '{id}' in chunks as '{}'",
+            transfer.transfer_id()
+        );
+        for chunk in transfer.remaining_chunks() {
+            let chunk_response = Response {
+                id: id.to_string(),
+                result: RpcResult::value(RpcMessage::TransferChunk { chunk }),
+            };
+            match bincode::serialize(&chunk_response) {
+                Ok(bytes) => self.send_frame(&bytes),
+                Err(error) => log::error!("Failed to serialize response chunk: {error}"),
+            }
+        }
+    }
+
+    fn receive_message(&self, message: Message) -> Option<Job> {
+        let message = match &self.backend().interceptor {
+            Some(interceptor) => interceptor(self, message)?,
+            None => message,
+        };
+        let Message {
+            id,
+            command,
+            idempotency_key,
+        } = message;
+        let started = Instant::now();
+        let name = command.name();
+        let result = match command {
+            // Negotiation happens while setting up the connection, before authenticating
+            Command::Negotiate {
+                compression,
+                threshold,
+            } => {
+                let result = self.negotiate(compression, threshold);
+                self.handled(name, started.elapsed(), &result);
+                result
+            }
+            Command::TransferChunk { chunk } => match self.check_authentication() {
+                Ok(()) => return self.receive_chunk(id, chunk),
+                Err(error) => {
+                    self.refused(name, &error);
+                    RpcResult::Error(error)
+                }
+            },
+            Command::ResumeTransfer { transfer_id, token } => {
+                let result = match self.check_authentication() {
+                    Ok(()) => self.resume_transfer(transfer_id, &token),
+                    Err(error) => RpcResult::Error(error),
+                };
+                self.handled(name, started.elapsed(), &result);
+                result
+            }
+            command => {
+                let message = Message {
+                    id: id.to_string(),
+                    command,
+                    idempotency_key,
+                };
+                match self.handle(message) {
+                    Handled::Answered(result) => result,
+                    Handled::Unanswered => return None,
+                    Handled::Queued(job) => return Some(job),
+                }
+            }
+        };
+        self.respond(Response { id, result });
+        None
+    }
+
+    fn handle_command(&self, id: Id, command: Command, idempotency_key: Option<Id>) -> Handled {
+        let result = match command {
+            Command::Cancel { id } => {
+                let cancelled = lock(&self.state.in_flight).cancel(&id);
+                log::info!("[RPC ->]: Cancelled {cancelled} command(s) with id '{id}'");
+                return Handled::Unanswered;
+            }
+            command @ (Command::JoinRoom { .. }
+            | Command::LeaveRoom { .. }
+            | Command::Broadcast { .. }) => self.room_command(command),
+            Command::Negotiate { .. }
+            | Command::TransferChunk { .. }
+            | Command::ResumeTransfer { .. } => RpcResult::Error(Error::UnrecognizedMessage),
+            command => return self.queue(id, command, idempotency_key),
+        };
+        Handled::Answered(result)
+    }
+
+    fn queue(&self, id: Id, command: Command, idempotency_key: Option<Id>) -> Handled {
+        let idempotency_key = idempotency_key.map(|key| (format!("{} {key}", self.owner()), key));
+        if let Some((owned_key, key)) = idempotency_key.as_ref() {
+            match lock(&self.backend().idempotency).begin(owned_key) {
+                Idempotency::New => {}
+                Idempotency::InProgress => {
+                    return Handled::Answered(RpcResult::Error(Error::IdempotencyConflict {
+                        key: key.to_string(),
+                    }))
+                }
+                Idempotency::Completed(result) => {
+                    log::info!("[RPC ->]: Answering the retried command '{key}' from the cache");
+                    return Handled::Answered(result);
+                }
+            }
+        }
+
+        let cancellation = lock(&self.state.in_flight).register(&id);
+        Handled::Queued(Job {
+            id,
+            command,
+            idempotency_key: idempotency_key.map(|(owned_key, _)| owned_key),
+            cancellation,
+        })
+    }
+
+    fn room_command(&self, command: Command) -> RpcResult {
+        let backend = self.backend();
+        let client_id = self.client_id();
+        match command {
+            Command::JoinRoom { room } => {
+                let members = lock(&backend.rooms).join(&room, client_id);
+                log::info!("[RPC ->]: '{client_id}' joined room '{room}' of {members} member(s)");
+                RpcResult::value(RpcMessage::RoomJoined { room, members })
+            }
+            Command::LeaveRoom { room } => {
+                if lock(&backend.rooms).leave(&room, client_id) {
+                    log::info!("[RPC ->]: '{client_id}' left room '{room}'");
+                    RpcResult::value(RpcMessage::RoomLeft { room })
+                } else {
+                    RpcResult::Error(Error::NotInRoom { room })
+                }
+            }
+            Command::Broadcast { room, payload } => {
+                if !lock(&backend.rooms).is_member(&room, client_id) {
+                    return RpcResult::Error(Error::NotInRoom { room });
+                }
+                let recipients = backend.broadcast(&room, client_id, &payload);
+                RpcResult::value(RpcMessage::Broadcasted { room, recipients })
+            }
+            _ => RpcResult::Error(Error::UnrecognizedMessage),
+        }
+    }
+
+    // Backends without credentials to check accept any, so clients can always authenticate
+    fn authenticate(&self, credential: &Credential) -> RpcResult {
+        let auth = &self.backend().auth;
+        let result = if auth.is_enabled() {
+            auth.verify(credential)
+        } else {
+            Ok(Authentication {
+                subject: "anonymous".to_string(),
+                expires_at: None,
+            })
+        };
+
+        match result {
+            Ok(authentication) => {
+                log::info!("[RPC ->]: Authenticated as '{}'", authentication.subject);
+                let subject = authentication.subject.to_string();
+                *lock(&self.state.authentication) = Some(authentication);
+                RpcResult::value(RpcMessage::Authenticated { subject })
+            }
+            Err(error) => {
+                log::error!("[RPC ->]: Authentication failed: {error}");
+                *lock(&self.state.authentication) = None;
+                RpcResult::Error(error)
+            }
+        }
+    }
+
+    fn check_authentication(&self) -> Result<(), Error> {
+        if !self.backend().auth.is_enabled() {
+            return Ok(());
+        }
+        match lock(&self.state.authentication).as_ref() {
+            Some(authentication) => authentication.check_expiry(),
+            None => Err(Error::Unauthenticated),
+        }
+    }
+
+    fn receive_chunk(&self, id: Id, chunk: Chunk) -> Option<Job> {
+        let transfer_id = chunk.transfer_id.to_string();
+        let received =
+            lock(&self.backend().uploads).receive(self.client_id(), chunk, Instant::now());
+        match received {
+            Ok((
+                Received {
+                    received, payload, ..
+                },
+                token,
+            )) => {
+                self.respond(transfer_ack(&id, transfer_id, received, token));
+
+                // The reassembled payload is an ordinary serialized message,
+                // already counted against the rate limit through its chunks
+                let max_payload_size = self.backend().limits.max_payload_size;
+                match payload.map(|bytes| crate::deserialize_limited(&bytes, max_payload_size)) {
+                    Some(Ok(message)) => return self.receive_message(message),
+                    Some(Err(error)) => {
+                        self.refused(UNREAD, &error);
+                        self.reject(id, error);
+                    }
+                    None => {}
+                }
+            }
+            Err(error) => self.respond(Response {
+                id,
+                result: RpcResult::Error(error),
+            }),
+        }
+        None
+    }
+
+    fn resume_transfer(&self, transfer_id: Id, token: &str) -> RpcResult {
+        let received = lock(&self.backend().uploads).resume(
+            self.client_id(),
+            &transfer_id,
+            token,
+            Instant::now(),
+        );
+        log::info!("[RPC ->]: Resuming transfer '{transfer_id}' from chunk {received:?}");
+        match received {
+            Ok(received) => RpcResult::value(RpcMessage::TransferAck {
+                transfer_id,
+                received,
+                token: token.to_string(),
+            }),
+            Err(error) => RpcResult::Error(error),
+        }
+    }
+
+    // Who idempotent results belong to. An authenticated subject may retry its commands
+    // from a new connection, while without authentication nothing proves a reconnecting
+    // client is the one that sent them.
+    fn owner(&self) -> Id {
+        match lock(&self.state.authentication).as_ref() {
+            Some(authentication) if self.backend().auth.is_enabled() => {
+                format!("subject:{}", authentication.subject)
+            }
+            _ => client_owner(self.client_id()),
+        }
+    }
+
+    fn negotiate(&self, compression: Compression, threshold: u32) -> RpcResult {
+        let compression = if Compression::SUPPORTED.contains(&compression) {
+            compression
+        } else {
+            Compression::None
+        };
+        let threshold = threshold.max(MIN_COMPRESSION_THRESHOLD);
+        log::info!("[RPC ->]: Negotiated {compression} compression above {threshold} bytes");
+        RpcResult::value(RpcMessage::Negotiated {
+            compression,
+            threshold,
+        })
+    }
+
+    fn handled(&self, command: &'static str, duration: Duration, result: &RpcResult) {
+        self.backend().observer.handled(&Execution {
+            peer: self.peer(),
+            client_id: self.client_id(),
+            command,
+            duration,
+            result,
+        });
+    }
+
+    fn refused(&self, command: &'static str, error: &Error) {
+        self.handled(command, Duration::ZERO, &RpcResult::Error(error.clone()));
+    }
+
+    // A frame that could not be read has no known id, so it is answered with an empty one
+    fn reject(&self, id: Id, error: Error) {
+        log::error!("[RPC ->]: Rejected a message: {error}");
+        self.respond(Response {
+            id,
+            result: RpcResult::Error(error),
+        });
+    }
+
+    // Pushed messages answer no request, so they carry the room as their id
+    fn push(&self, room: &str, message: RpcMessage) {
+        if !self.state.json_rpc.load(Ordering::Relaxed) {
+            self.respond(Response {
+                id: room.to_string(),
+                result: RpcResult::value(message),
+            });
+            return;
+        }
+
+        let text = JsonRpcRequest::notification(&message)
+            .and_then(|notification| serde_json::to_string(&notification));
+        match text {
+            Ok(text) => {
+                log::info!("[JSON-RPC <-]: {text}");
+                (self.state.outbox)(Outgoing::Text(text));
+            }
+            Err(error) => log::error!("Failed to serialize JSON-RPC notification: {error}"),
+        }
+    }
+
+    fn send_frame(&self, bytes: &[u8]) {
+        let frame = lock(&self.state.codec).encode(bytes);
+        (self.state.outbox)(Outgoing::Binary(frame));
+    }
+}
+
+// Results that can't be read from another connection
+fn client_owner(client_id: &str) -> Id {
+    format!("client:{client_id}")
+}
+
+fn transfer_ack(id: &str, transfer_id: Id, received: u32, token: Id) -> Response {
+    Response {
+        id: id.to_string(),
+        result: RpcResult::value(RpcMessage::TransferAck {
+            transfer_id,
+            received,
+            token,
+        }),
+    }
+}
+
+// Releases a job's cancellation token and idempotency key once it finishes,
+// and also if its handler panics, so a retry isn't refused forever
+struct Running<'a> {
+    connection: &'a BackendConnection,
+    id: &'a Id,
+    cancellation: &'a CancellationToken,
+    idempotency_key: Option<&'a Id>,
+}
+
+impl Running<'_> {
+    fn finish(mut self, result: &RpcResult) {
+        if let Some(key) = self.idempotency_key.take() {
+            lock(&self.connection.backend().idempotency).complete(key, result);
+        }
+    }
+}
+
+impl Drop for Running<'_> {
+    fn drop(&mut self) {
+        lock(&self.connection.state.in_flight).complete(self.id, self.cancellation);
+        if let Some(key) = self.idempotency_key {
+            lock(&self.connection.backend().idempotency).abandon(key);
+        }
+    }
+}
+
+fn lock<T>(mutex: &Mutex<T>) -> MutexGuard<'_, T> {
+    mutex
+        .lock()
+        .unwrap_or_else(|poisoned| poisoned.into_inner())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Backend, BackendConnection, Execution, Handled, Observer, Outgoing, UNREAD};
+    use crate::{
+        sign_credential, unix_time, AuthConfig, Codec, Command, Credential, Error, Limits, Message,
+        OutgoingTransfer, RateLimit, Response, RpcExecutor, RpcMessage, RpcResult,
+    };
+    use std::sync::{mpsc, Arc, Mutex};
+
+    fn connect(backend: &Arc<Backend>) -> (BackendConnection, mpsc::Receiver<Outgoing>) {
+        let (outbox, outgoing) = mpsc::channel();
+        let connection = backend.connect("test", move |frame| {
+            let _ = outbox.send(frame);
+        });
+        assert!(matches!(
+            read(&outgoing).result,
+            RpcResult::Success(RpcMessage::ClientId { .. })
+        ));
+        (connection, outgoing)
+    }
+
+    fn read(outgoing: &mpsc::Receiver<Outgoing>) -> Response {
+        match outgoing.try_recv() {
+            Ok(Outgoing::Binary(frame)) => {
+                bincode::deserialize(&Codec::decode(&frame).unwrap()).unwrap()
+            }
+            other => panic!("Expected a binary frame, got {other:?}"),
+        }
+    }
+
+    fn frame(message: &Message) -> Vec<u8> {
+        Codec::default().encode(&bincode::serialize(message).unwrap())
+    }
+
+    fn answer(handled: Handled) -> RpcResult {
+        match handled {
+            Handled::Answered(result) => result,
+            Handled::Unanswered => panic!("The message was not answered"),
+            Handled::Queued(job) => panic!("The message was queued as {job:?}"),
+        }
+    }
+
+    #[test]
+    fn test_idempotent_retry() {
+        let backend = Arc::new(Backend::new(RpcExecutor::default()));
+        let (connection, _outgoing) = connect(&backend);
+        let message =
+            Message::new("1".to_string(), Command::Example).with_idempotency_key("key".into());
+
+        let Handled::Queued(job) = connection.handle(message.clone()) else {
+            panic!("The command was not queued");
+        };
+        assert_eq!(
+            answer(connection.handle(message.clone())),
+            RpcResult::Error(Error::IdempotencyConflict {
+                key: "key".to_string()
+            })
+        );
+        assert_eq!(connection.execute(job).result, RpcResult::default());
+        assert_eq!(
+            answer(connection.handle(message.clone())),
+            RpcResult::default()
+        );
+
+        // Keys are per client, so another one reusing the key runs its own command
+        let (other, _outgoing) = connect(&backend);
+        assert!(matches!(other.handle(message), Handled::Queued(_)));
+    }
+
+    #[test]
+    fn test_idempotent_retry_by_subject() {
+        let auth = AuthConfig {
+            tokens: Vec::new(),
+            signing_key: Some("key".to_string()),
+        };
+        let backend = Arc::new(Backend::new(RpcExecutor::default()).with_auth(auth));
+        let message =
+            Message::new("1".to_string(), Command::Example).with_idempotency_key("key".into());
+        let handle = |subject: &str| {
+            let (connection, _outgoing) = connect(&backend);
+            let credential = sign_credential("key", subject, unix_time() + 60);
+            let authenticate = Command::Authenticate { credential };
+            answer(connection.handle(Message::new("auth".to_string(), authenticate)));
+            (connection.clone(), connection.handle(message.clone()))
+        };
+
+        let (connection, Handled::Queued(job)) = handle("alice") else {
+            panic!("The command was not queued");
+        };
+        connection.execute(job);
+
+        // The same subject retrying from a new connection gets the first result
+        assert_eq!(answer(handle("alice").1), RpcResult::default());
+        assert!(matches!(handle("mallory").1, Handled::Queued(_)));
+    }
+
+    #[test]
+    fn test_idempotent_retry_by_token() {
+        let auth = AuthConfig {
+            tokens: vec!["first".to_string(), "second".to_string()],
+            signing_key: None,
+        };
+        let backend = Arc::new(Backend::new(RpcExecutor::default()).with_auth(auth));
+        let message =
+            Message::new("1".to_string(), Command::Example).with_idempotency_key("key".into());
+        let handle = |token: &str| {
+            let (connection, _outgoing) = connect(&backend);
+            let credential = Credential::Token {
+                token: token.to_string(),
+            };
+            let authenticate = Command::Authenticate { credential };
+            answer(connection.handle(Message::new("auth".to_string(), authenticate)));
+            (connection.clone(), connection.handle(message.clone()))
+        };
+
+        let (connection, Handled::Queued(job)) = handle("first") else {
+            panic!("The command was not queued");
+        };
+        connection.execute(job);
+
+        // Holders of different tokens don't share results
+        assert_eq!(answer(handle("first").1), RpcResult::default());
+        assert!(matches!(handle("second").1, Handled::Queued(_)));
+    }
+
+    #[test]
+    fn test_connect_once() {
+        let message =
+            Message::new("1".to_string(), Command::Example).with_idempotency_key("key".into());
+
+        // Whether the command ran, rather than being answered from the cache
+        let ran = |backend: &Arc<Backend>, authentication| {
+            let connection = backend.connect_once("test", authentication);
+            let ran = match connection.handle(message.clone()) {
+                Handled::Queued(job) => {
+                    assert_eq!(connection.execute(job).result, RpcResult::default());
+                    true
+                }
+                handled => {
+                    assert_eq!(answer(handled), RpcResult::default());
+                    false
+                }
+            };
+            connection.close();
+            ran
+        };
+
+        let auth = AuthConfig {
+            tokens: vec!["secret".to_string()],
+            signing_key: None,
+        };
+        let credential = Credential::Token {
+            token: "secret".to_string(),
+        };
+        let authentication = auth.verify(&credential).unwrap();
+        let backend = Arc::new(Backend::new(RpcExecutor::default()).with_auth(auth));
+        assert!(ran(&backend, Some(authentication.clone())));
+        assert!(!ran(&backend, Some(authentication)));
+        assert_eq!(backend.connections(), 0);
+
+        // Nothing tells anonymous clients apart, so each request has keys of its own
+        let backend = Arc::new(Backend::new(RpcExecutor::default()));
+        assert!(ran(&backend, None));
+        assert!(ran(&backend, None));
+    }
+
+    #[test]
+    fn test_limits() {
+        let limits = Limits {
+            max_frame_size: 64,
+            rate_limit: Some(RateLimit {
+                per_second: 0,
+                burst: 1,
+            }),
+            ..Default::default()
+        };
+        let backend = Arc::new(Backend::new(RpcExecutor::default()).with_limits(limits));
+        let (connection, outgoing) = connect(&backend);
+
+        let message = Message::new("1".to_string(), Command::Example);
+        assert!(connection.receive_frame(&frame(&message)).is_some());
+        assert!(connection.receive_frame(&frame(&message)).is_none());
+        assert!(matches!(
+            read(&outgoing).result,
+            RpcResult::Error(Error::RateLimited { .. })
+        ));
+
+        // Heartbeats are exempt from the rate limit
+        let heartbeat = Message::new("2".to_string(), Command::Heartbeat { sequence: 7 });
+        assert!(connection.receive_frame(&frame(&heartbeat)).is_none());
+        assert_eq!(
+            read(&outgoing).result,
+            RpcResult::value(RpcMessage::Heartbeat { sequence: 7 })
+        );
+
+        assert!(connection.receive_frame(&[0; 65]).is_none());
+        assert_eq!(
+            read(&outgoing).result,
+            RpcResult::Error(Error::FrameTooLarge {
+                size: 65,
+                limit: 64
+            })
+        );
+    }
+
+    /// Keeps the command and result names of everything the backend handled itself
+    #[derive(Clone, Default)]
+    struct Handlings(Arc<Mutex<Vec<(&'static str, &'static str)>>>);
+
+    impl Observer for Handlings {
+        fn handled(&self, execution: &Execution<'_>) {
+            let result = match execution.result {
+                RpcResult::Success(message) => message.name(),
+                RpcResult::Error(error) => error.name(),
+            };
+            self.0.lock().unwrap().push((execution.command, result));
+        }
+    }
+
+    #[test]
+    fn test_observe_handled() {
+        let auth = AuthConfig {
+            tokens: vec!["secret".to_string()],
+            signing_key: None,
+        };
+        let limits = Limits {
+            max_frame_size: 64,
+            rate_limit: Some(RateLimit {
+                per_second: 0,
+                burst: 3,
+            }),
+            ..Default::default()
+        };
+        let handlings = Handlings::default();
+        let backend = Backend::new(RpcExecutor::default())
+            .with_auth(auth)
+            .with_limits(limits)
+            .with_observer(handlings.clone());
+        let (connection, _outgoing) = connect(&Arc::new(backend));
+
+        let example = Message::new("1".to_string(), Command::Example);
+        let authenticate = Command::Authenticate {
+            credential: Credential::from_text("secret"),
+        };
+        let join = Command::JoinRoom {
+            room: "ops".to_string(),
+        };
+        assert!(connection.receive_frame(&frame(&example)).is_none());
+        connection.receive_frame(&frame(&Message::new("2".to_string(), authenticate)));
+        connection.receive_frame(&frame(&Message::new("3".to_string(), join)));
+        connection.receive_frame(&frame(&example));
+        connection.receive_frame(&[0; 65]);
+
+        // Commands run by the executor and heartbeats are not among them
+        let heartbeat = Command::Heartbeat { sequence: 1 };
+        connection.receive_frame(&frame(&Message::new("4".to_string(), heartbeat)));
+        assert_eq!(
+            *handlings.0.lock().unwrap(),
+            [
+                ("Example", "Unauthenticated"),
+                ("Authenticate", "Authenticated"),
+                ("JoinRoom", "RoomJoined"),
+                ("Example", "RateLimited"),
+                (UNREAD, "FrameTooLarge"),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_broadcast() {
+        let backend = Arc::new(Backend::new(RpcExecutor::default()));
+        let (sender, _sent) = connect(&backend);
+        let (member, received) = connect(&backend);
+        let (_outsider, ignored) = connect(&backend);
+
+        for connection in [&sender, &member] {
+            let join = Command::JoinRoom {
+                room: "ops".to_string(),
+            };
+            answer(connection.handle(Message::new("join".to_string(), join)));
+        }
+        let broadcast = Command::Broadcast {
+            room: "ops".to_string(),
+            payload: "hello".to_string(),
+        };
+        assert_eq!(
+            answer(sender.handle(Message::new("1".to_string(), broadcast))),
+            RpcResult::value(RpcMessage::Broadcasted {
+                room: "ops".to_string(),
+                recipients: 1
+            })
+        );
+        assert_eq!(
+            read(&received),
+            Response {
+                id: "ops".to_string(),
+                result: RpcResult::value(RpcMessage::RoomMessage {
+                    room: "ops".to_string(),
+                    sender: sender.client_id().to_string(),
+                    payload: "hello".to_string(),
+                }),
+            }
+        );
+        assert!(ignored.try_recv().is_err());
+
+        member.close();
+        assert_eq!(backend.connections(), 2);
+        assert_eq!(backend.sessions().len(), 2);
+    }
+
+    #[test]
+    fn test_upload_owners() {
+        let backend = Arc::new(Backend::new(RpcExecutor::default()));
+        let resume = |connection: &BackendConnection, outgoing, transfer_id: &str, token: &str| {
+            let command = Command::ResumeTransfer {
+                transfer_id: transfer_id.to_string(),
+                token: token.to_string(),
+            };
+            let message = Message::new("resume".to_string(), command);
+            assert!(connection.receive_frame(&frame(&message)).is_none());
+            read(outgoing).result
+        };
+
+        let mut transfer = OutgoingTransfer::new(vec![7; 100], 10);
+        let transfer_id = transfer.transfer_id().to_string();
+        let chunk = transfer.next_chunk().unwrap();
+        let (first, sent) = connect(&backend);
+        let message = Message::new("1".to_string(), Command::TransferChunk { chunk });
+        assert!(first.receive_frame(&frame(&message)).is_none());
+        let acknowledged = read(&sent).result;
+        let RpcResult::Success(RpcMessage::TransferAck {
+            received: 1,
+            ref token,
+            ..
+        }) = acknowledged
+        else {
+            panic!("Unexpected {acknowledged:?}");
+        };
+        first.close();
+
+        // The upload outlives the connection, for whoever holds its token
+        let (guesser, outgoing) = connect(&backend);
+        assert_eq!(
+            resume(&guesser, &outgoing, &transfer_id, "guess"),
+            RpcResult::Error(Error::UnknownTransfer {
+                transfer_id: transfer_id.to_string()
+            })
+        );
+        let (second, outgoing) = connect(&backend);
+        assert_eq!(
+            resume(&second, &outgoing, &transfer_id, token),
+            acknowledged
+        );
+    }
+}
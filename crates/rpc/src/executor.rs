@@ -1,13 +1,94 @@
-use crate::{Command, Id, RpcResult};
+use crate::{
+    CallContext, CancellationToken, Command, Error, Id, PayloadJson, RpcMessage, RpcResult,
+    RpcService, Session,
+};
+use std::{collections::HashMap, sync::Mutex};
 
+/// Runs commands for every connected client. Executing only borrows the executor,
+/// so clients run commands side by side and only calls to the same service wait on each other.
 #[derive(Default)]
-pub struct RpcExecutor;
+pub struct RpcExecutor {
+    schema: Option<PayloadJson>,
+    services: HashMap<String, Mutex<Box<dyn RpcService>>>,
+}
 
 impl RpcExecutor {
-    pub fn execute(&mut self, _id: &Id, command: Command) -> RpcResult {
+    /// The schema `Command::Schema` is answered with
+    pub fn with_schema(mut self, schema: PayloadJson) -> Self {
+        self.schema = Some(schema);
+        self
+    }
+
+    pub fn with_service(mut self, service: impl RpcService + 'static) -> Self {
+        self.register_service(service);
+        self
+    }
+
+    /// Routes `Command::Service` requests for `service.name()` to `service`,
+    /// replacing any service registered under the same name
+    pub fn register_service(&mut self, service: impl RpcService + 'static) {
+        self.services
+            .insert(service.name().to_string(), Mutex::new(Box::new(service)));
+    }
+
+    pub fn execute(
+        &self,
+        session: &mut Session,
+        _id: &Id,
+        command: Command,
+        cancellation: &CancellationToken,
+    ) -> RpcResult {
         log::info!("Executing an RPC command: {command:#?}");
+        if cancellation.is_cancelled() {
+            return RpcResult::Error(Error::Cancelled);
+        }
         match command {
             Command::Example => RpcResult::default(),
+            Command::Schema => match &self.schema {
+                Some(schema) => RpcResult::value(RpcMessage::Schema {
+                    schema: schema.to_string(),
+                }),
+                None => RpcResult::Error(Error::SchemaUnavailable),
+            },
+            Command::Service {
+                service,
+                call_id,
+                payload,
+            } => match self.services.get(&service) {
+                Some(handler) => match handler
+                    .lock()
+                    .unwrap_or_else(|poisoned| poisoned.into_inner())
+                    .call(
+                        &mut CallContext {
+                            session,
+                            cancellation,
+                        },
+                        &payload,
+                    ) {
+                    Ok(payload) => RpcResult::value(RpcMessage::Service {
+                        service,
+                        call_id,
+                        payload,
+                    }),
+                    Err(error) => RpcResult::Error(Error::Service {
+                        service,
+                        call_id,
+                        error,
+                    }),
+                },
+                None => RpcResult::Error(Error::UnknownService { service, call_id }),
+            },
+
+            // Control messages are handled by the connection before commands reach the executor
+            Command::Cancel { .. }
+            | Command::TransferChunk { .. }
+            | Command::ResumeTransfer { .. }
+            | Command::Negotiate { .. }
+            | Command::Authenticate { .. }
+            | Command::Heartbeat { .. }
+            | Command::JoinRoom { .. }
+            | Command::LeaveRoom { .. }
+            | Command::Broadcast { .. } => RpcResult::Error(Error::UnrecognizedMessage),
         }
     }
 }
@@ -0,0 +1,148 @@
+use std::time::Duration;
+use web_time::Instant;
+
+/// How often clients send `Command::Heartbeat` and servers send websocket pings
+pub const DEFAULT_HEARTBEAT_INTERVAL: Duration = Duration::from_secs(5);
+
+/// How long a connection may go without receiving anything before it is considered dead
+pub const DEFAULT_LIVENESS_TIMEOUT: Duration = Duration::from_secs(15);
+
+/// Decides when a client sends heartbeats, and measures the round trip of their answers.
+/// Any frame from the server counts as a sign of life, not just heartbeat answers.
+#[derive(Debug, Clone)]
+pub struct Heartbeat {
+    interval: Duration,
+    timeout: Duration,
+    sequence: u64,
+    pending: Option<(u64, Instant)>,
+    last_sent: Option<Instant>,
+    last_received: Option<Instant>,
+    latency: Option<Duration>,
+}
+
+impl Default for Heartbeat {
+    fn default() -> Self {
+        Self::new(DEFAULT_HEARTBEAT_INTERVAL, DEFAULT_LIVENESS_TIMEOUT)
+    }
+}
+
+impl Heartbeat {
+    pub fn new(interval: Duration, timeout: Duration) -> Self {
+        Self {
+            interval,
+            timeout,
+            sequence: 0,
+            pending: None,
+            last_sent: None,
+            last_received: None,
+            latency: None,
+        }
+    }
+
+    pub fn interval(&self) -> Duration {
+        self.interval
+    }
+
+    /// Called when the connection opens
+    pub fn start(&mut self, now: Instant) {
+        self.pending = None;
+        self.last_sent = None;
+        self.last_received = Some(now);
+        self.latency = None;
+    }
+
+    /// Called when the connection closes
+    pub fn stop(&mut self) {
+        self.pending = None;
+        self.last_received = None;
+    }
+
+    pub fn received(&mut self, now: Instant) {
+        if self.last_received.is_some() {
+            self.last_received = Some(now);
+        }
+    }
+
+    /// The sequence number of the heartbeat to send, if one is due
+    pub fn due(&mut self, now: Instant) -> Option<u64> {
+        self.last_received?;
+        let due = self.last_sent.map_or(true, |last_sent| {
+            now.duration_since(last_sent) >= self.interval
+        });
+        if !due {
+            return None;
+        }
+
+        self.sequence += 1;
+        self.last_sent = Some(now);
+
+        // Only the latest heartbeat is timed, so a late answer to an earlier one is ignored
+        self.pending = Some((self.sequence, now));
+        Some(self.sequence)
+    }
+
+    pub fn answered(&mut self, sequence: u64, now: Instant) {
+        if let Some((pending, sent_at)) = self.pending {
+            if pending == sequence {
+                self.latency = Some(now.duration_since(sent_at));
+                self.pending = None;
+            }
+        }
+    }
+
+    /// The round trip time of the last answered heartbeat
+    pub fn latency(&self) -> Option<Duration> {
+        self.latency
+    }
+
+    /// Whether the connection is open and has received something within the timeout
+    pub fn is_alive(&self, now: Instant) -> bool {
+        self.last_received.map_or(false, |last_received| {
+            now.duration_since(last_received) < self.timeout
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Heartbeat;
+    use std::time::Duration;
+    use web_time::Instant;
+
+    fn heartbeat() -> (Heartbeat, Instant) {
+        let mut heartbeat = Heartbeat::new(Duration::from_secs(5), Duration::from_secs(15));
+        let now = Instant::now();
+        heartbeat.start(now);
+        (heartbeat, now)
+    }
+
+    #[test]
+    fn test_interval() {
+        let (mut heartbeat, now) = heartbeat();
+        assert_eq!(heartbeat.due(now), Some(1));
+        assert_eq!(heartbeat.due(now + Duration::from_secs(1)), None);
+        assert_eq!(heartbeat.due(now + Duration::from_secs(5)), Some(2));
+    }
+
+    #[test]
+    fn test_latency() {
+        let (mut heartbeat, now) = heartbeat();
+        let sequence = heartbeat.due(now).unwrap();
+        heartbeat.answered(sequence + 1, now + Duration::from_millis(10));
+        assert_eq!(heartbeat.latency(), None);
+        heartbeat.answered(sequence, now + Duration::from_millis(20));
+        assert_eq!(heartbeat.latency(), Some(Duration::from_millis(20)));
+    }
+
+    #[test]
+    fn test_liveness() {
+        let (mut heartbeat, now) = heartbeat();
+        assert!(heartbeat.is_alive(now + Duration::from_secs(14)));
+        assert!(!heartbeat.is_alive(now + Duration::from_secs(15)));
+        heartbeat.received(now + Duration::from_secs(10));
+        assert!(heartbeat.is_alive(now + Duration::from_secs(20)));
+        heartbeat.stop();
+        assert!(!heartbeat.is_alive(now));
+        assert_eq!(heartbeat.due(now), None);
+    }
+}
@@ -0,0 +1,158 @@
+use crate::{Error, Id, RpcResult};
+use std::{
+    collections::{HashMap, VecDeque},
+    time::{Duration, Instant},
+};
+
+/// How many idempotency keys the server remembers
+pub const DEFAULT_IDEMPOTENCY_CAPACITY: usize = 1024;
+
+/// How long the result of a command with an idempotency key is kept for retries
+pub const DEFAULT_IDEMPOTENCY_TTL: Duration = Duration::from_secs(10 * 60);
+
+/// What the server knows about an idempotency key it was given
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Idempotency {
+    /// The key has not been seen, and is now claimed by the command about to run
+    New,
+
+    /// A command with the key is still running
+    InProgress,
+
+    /// A command with the key already finished, with this result
+    Completed(RpcResult),
+}
+
+enum Entry {
+    InProgress,
+    Completed { at: Instant, result: RpcResult },
+}
+
+/// Remembers the results of commands sent with an idempotency key,
+/// so a retried command is answered without running it again
+pub struct IdempotencyCache {
+    capacity: usize,
+    ttl: Duration,
+    entries: HashMap<Id, Entry>,
+    order: VecDeque<Id>,
+}
+
+impl Default for IdempotencyCache {
+    fn default() -> Self {
+        Self::new(DEFAULT_IDEMPOTENCY_CAPACITY, DEFAULT_IDEMPOTENCY_TTL)
+    }
+}
+
+impl IdempotencyCache {
+    pub fn new(capacity: usize, ttl: Duration) -> Self {
+        Self {
+            capacity,
+            ttl,
+            entries: HashMap::new(),
+            order: VecDeque::new(),
+        }
+    }
+
+    pub fn begin(&mut self, key: &str) -> Idempotency {
+        self.evict(Instant::now());
+        match self.entries.get(key) {
+            Some(Entry::InProgress) => Idempotency::InProgress,
+            Some(Entry::Completed { result, .. }) => Idempotency::Completed(result.clone()),
+            None => {
+                self.entries.insert(key.to_string(), Entry::InProgress);
+                self.order.push_back(key.to_string());
+                Idempotency::New
+            }
+        }
+    }
+
+    /// Records the result of the command that claimed `key`.
+    /// Cancelled commands are forgotten, so that retrying them runs them again.
+    pub fn complete(&mut self, key: &str, result: &RpcResult) {
+        if *result == RpcResult::Error(Error::Cancelled) {
+            self.abandon(key);
+            return;
+        }
+        if let Some(entry) = self.entries.get_mut(key) {
+            *entry = Entry::Completed {
+                at: Instant::now(),
+                result: result.clone(),
+            };
+        }
+    }
+
+    pub fn abandon(&mut self, key: &str) {
+        self.entries.remove(key);
+        self.order.retain(|candidate| candidate != key);
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    fn evict(&mut self, now: Instant) {
+        let ttl = self.ttl;
+        self.entries.retain(|_, entry| match entry {
+            Entry::InProgress => true,
+            Entry::Completed { at, .. } => now.duration_since(*at) < ttl,
+        });
+
+        // Keys are forgotten oldest first once the cache is full
+        while self.entries.len() >= self.capacity {
+            match self.order.pop_front() {
+                Some(key) => {
+                    self.entries.remove(&key);
+                }
+                None => break,
+            }
+        }
+        let entries = &self.entries;
+        self.order.retain(|key| entries.contains_key(key));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Idempotency, IdempotencyCache};
+    use crate::{Error, RpcResult};
+    use std::time::Duration;
+
+    #[test]
+    fn test_retry_returns_cached_result() {
+        let mut cache = IdempotencyCache::default();
+        assert_eq!(cache.begin("key"), Idempotency::New);
+        assert_eq!(cache.begin("key"), Idempotency::InProgress);
+        cache.complete("key", &RpcResult::default());
+        assert_eq!(
+            cache.begin("key"),
+            Idempotency::Completed(RpcResult::default())
+        );
+    }
+
+    #[test]
+    fn test_cancelled_commands_run_again() {
+        let mut cache = IdempotencyCache::default();
+        cache.begin("key");
+        cache.complete("key", &RpcResult::Error(Error::Cancelled));
+        assert_eq!(cache.begin("key"), Idempotency::New);
+    }
+
+    #[test]
+    fn test_capacity_and_ttl() {
+        let mut cache = IdempotencyCache::new(2, Duration::from_secs(60));
+        for key in ["a", "b", "c"] {
+            cache.begin(key);
+        }
+        assert_eq!(cache.len(), 2);
+        assert_eq!(cache.begin("a"), Idempotency::New);
+
+        let mut cache = IdempotencyCache::new(8, Duration::ZERO);
+        cache.begin("a");
+        cache.complete("a", &RpcResult::default());
+        assert_eq!(cache.begin("a"), Idempotency::New);
+    }
+}
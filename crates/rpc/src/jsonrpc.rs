@@ -0,0 +1,353 @@
+use crate::{Command, Error, RpcMessage, RpcResult};
+use serde::{Deserialize, Deserializer, Serialize};
+use serde_json::Value;
+
+pub const JSONRPC_VERSION: &str = "2.0";
+
+/// A JSON-RPC 2.0 request. The method is the name of a `Command` variant,
+/// and the params are that variant's fields as a JSON object.
+///
+/// `{"jsonrpc": "2.0", "method": "Cancel", "params": {"id": "1"}, "id": 2}`
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct JsonRpcRequest {
+    pub jsonrpc: String,
+    pub method: String,
+
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub params: Option<Value>,
+
+    /// Absent for notifications, which get no response
+    #[serde(
+        default,
+        deserialize_with = "deserialize_present",
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub id: Option<Value>,
+}
+
+impl JsonRpcRequest {
+    /// A request without an id, which servers use to push messages nobody asked for.
+    /// Like commands, the method is the name of the `RpcMessage` variant.
+    pub fn notification(message: &RpcMessage) -> Result<Self, serde_json::Error> {
+        let (method, params) = match serde_json::to_value(message)? {
+            Value::Object(tagged) => tagged
+                .into_iter()
+                .next()
+                .map(|(method, params)| (method, Some(params)))
+                .unwrap_or_default(),
+            Value::String(method) => (method, None),
+            _ => (String::new(), None),
+        };
+        Ok(Self {
+            jsonrpc: JSONRPC_VERSION.to_string(),
+            method,
+            params,
+            id: None,
+        })
+    }
+
+    pub fn is_notification(&self) -> bool {
+        self.id.is_none()
+    }
+
+    pub fn command(&self) -> Result<Command, JsonRpcError> {
+        if self.jsonrpc != JSONRPC_VERSION {
+            return Err(JsonRpcError::invalid_request());
+        }
+
+        // Commands are externally tagged, so unit variants are plain strings
+        // and struct variants are objects keyed by the variant name
+        let tagged = match &self.params {
+            None | Some(Value::Null) => Value::String(self.method.to_string()),
+            Some(Value::Object(params)) if params.is_empty() => {
+                Value::String(self.method.to_string())
+            }
+            Some(params @ Value::Object(_)) => {
+                let mut tagged = serde_json::Map::new();
+                tagged.insert(self.method.to_string(), params.clone());
+                Value::Object(tagged)
+            }
+            Some(_) => {
+                return Err(JsonRpcError::invalid_params(
+                    "Params must be an object of the command's fields",
+                ))
+            }
+        };
+
+        serde_json::from_value::<Command>(tagged).map_err(|error| {
+            let error = error.to_string();
+            if error.starts_with("unknown variant") {
+                JsonRpcError::method_not_found(&self.method)
+            } else {
+                JsonRpcError::invalid_params(&error)
+            }
+        })
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct JsonRpcResponse {
+    pub jsonrpc: String,
+
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub result: Option<Value>,
+
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub error: Option<JsonRpcError>,
+
+    pub id: Value,
+}
+
+impl JsonRpcResponse {
+    pub fn from_result(id: Value, result: RpcResult) -> Self {
+        match result {
+            RpcResult::Success(message) => match serde_json::to_value(message) {
+                Ok(value) => Self {
+                    jsonrpc: JSONRPC_VERSION.to_string(),
+                    result: Some(value),
+                    error: None,
+                    id,
+                },
+                Err(error) => Self::error(id, JsonRpcError::internal(&error.to_string())),
+            },
+            RpcResult::Error(error) => Self::error(id, JsonRpcError::from_rpc(&error)),
+        }
+    }
+
+    pub fn error(id: Value, error: JsonRpcError) -> Self {
+        Self {
+            jsonrpc: JSONRPC_VERSION.to_string(),
+            result: None,
+            error: Some(error),
+            id,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct JsonRpcError {
+    pub code: i64,
+    pub message: String,
+
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub data: Option<Value>,
+}
+
+impl JsonRpcError {
+    pub const PARSE_ERROR: i64 = -32700;
+    pub const INVALID_REQUEST: i64 = -32600;
+    pub const METHOD_NOT_FOUND: i64 = -32601;
+    pub const INVALID_PARAMS: i64 = -32602;
+    pub const INTERNAL_ERROR: i64 = -32603;
+
+    /// Errors returned by a command carry the `rpc::Error` as their data
+    pub const COMMAND_ERROR: i64 = -32000;
+
+    pub fn parse_error(error: &str) -> Self {
+        Self::new(Self::PARSE_ERROR, &format!("Parse error: {error}"))
+    }
+
+    pub fn invalid_request() -> Self {
+        Self::new(Self::INVALID_REQUEST, "Invalid request")
+    }
+
+    pub fn method_not_found(method: &str) -> Self {
+        Self::new(
+            Self::METHOD_NOT_FOUND,
+            &format!("Method '{method}' not found"),
+        )
+    }
+
+    pub fn invalid_params(error: &str) -> Self {
+        Self::new(Self::INVALID_PARAMS, &format!("Invalid params: {error}"))
+    }
+
+    pub fn internal(error: &str) -> Self {
+        Self::new(Self::INTERNAL_ERROR, &format!("Internal error: {error}"))
+    }
+
+    pub fn from_rpc(error: &Error) -> Self {
+        Self {
+            code: Self::COMMAND_ERROR,
+            message: error.to_string(),
+            data: serde_json::to_value(error).ok(),
+        }
+    }
+
+    fn new(code: i64, message: &str) -> Self {
+        Self {
+            code,
+            message: message.to_string(),
+            data: None,
+        }
+    }
+}
+
+/// A parsed JSON-RPC frame. Entries that could not be parsed
+/// are already turned into the error response they should get.
+#[derive(Debug, Clone, PartialEq)]
+pub enum JsonRpcMessage {
+    Single(Result<JsonRpcRequest, JsonRpcResponse>),
+    Batch(Vec<Result<JsonRpcRequest, JsonRpcResponse>>),
+}
+
+impl JsonRpcMessage {
+    pub fn parse(text: &str) -> Self {
+        let value = match serde_json::from_str::<Value>(text) {
+            Ok(value) => value,
+            Err(error) => {
+                return Self::Single(Err(JsonRpcResponse::error(
+                    Value::Null,
+                    JsonRpcError::parse_error(&error.to_string()),
+                )))
+            }
+        };
+
+        match value {
+            Value::Array(requests) if requests.is_empty() => Self::Single(Err(
+                JsonRpcResponse::error(Value::Null, JsonRpcError::invalid_request()),
+            )),
+            Value::Array(requests) => {
+                Self::Batch(requests.into_iter().map(parse_request).collect())
+            }
+            request => Self::Single(parse_request(request)),
+        }
+    }
+}
+
+fn parse_request(value: Value) -> Result<JsonRpcRequest, JsonRpcResponse> {
+    serde_json::from_value::<JsonRpcRequest>(value.clone()).map_err(|_| {
+        // Echo the id back if there is a usable one
+        let id = match value.get("id") {
+            Some(id @ (Value::String(_) | Value::Number(_))) => id.clone(),
+            _ => Value::Null,
+        };
+        JsonRpcResponse::error(id, JsonRpcError::invalid_request())
+    })
+}
+
+// A member that is present but null is still an id, unlike a missing member
+fn deserialize_present<'de, D>(deserializer: D) -> Result<Option<Value>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    Value::deserialize(deserializer).map(Some)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{JsonRpcError, JsonRpcMessage, JsonRpcRequest, JsonRpcResponse};
+    use crate::{Command, Error, RpcMessage, RpcResult};
+    use serde_json::{json, Value};
+
+    fn request(text: &str) -> JsonRpcRequest {
+        match JsonRpcMessage::parse(text) {
+            JsonRpcMessage::Single(Ok(request)) => request,
+            message => panic!("Expected a single request, got {message:?}"),
+        }
+    }
+
+    #[test]
+    fn test_unit_command() {
+        let request = request(r#"{"jsonrpc": "2.0", "method": "Example", "id": 1}"#);
+        assert_eq!(request.command(), Ok(Command::Example));
+        assert!(!request.is_notification());
+    }
+
+    #[test]
+    fn test_struct_command() {
+        let request = request(r#"{"jsonrpc": "2.0", "method": "Cancel", "params": {"id": "a"}}"#);
+        assert_eq!(
+            request.command(),
+            Ok(Command::Cancel {
+                id: "a".to_string()
+            })
+        );
+        assert!(request.is_notification());
+    }
+
+    #[test]
+    fn test_null_id_is_not_a_notification() {
+        let request = request(r#"{"jsonrpc": "2.0", "method": "Example", "id": null}"#);
+        assert_eq!(request.id, Some(Value::Null));
+    }
+
+    #[test]
+    fn test_errors() {
+        let code = |text: &str| request(text).command().unwrap_err().code;
+        assert_eq!(
+            code(r#"{"jsonrpc": "2.0", "method": "Missing", "id": 1}"#),
+            JsonRpcError::METHOD_NOT_FOUND
+        );
+        assert_eq!(
+            code(r#"{"jsonrpc": "2.0", "method": "Cancel", "params": {"nope": 1}, "id": 1}"#),
+            JsonRpcError::INVALID_PARAMS
+        );
+        assert_eq!(
+            code(r#"{"jsonrpc": "1.0", "method": "Example", "id": 1}"#),
+            JsonRpcError::INVALID_REQUEST
+        );
+    }
+
+    #[test]
+    fn test_batch() {
+        let message = JsonRpcMessage::parse(
+            r#"[{"jsonrpc": "2.0", "method": "Example", "id": 1}, {"id": 2}]"#,
+        );
+        let JsonRpcMessage::Batch(requests) = message else {
+            panic!("Expected a batch");
+        };
+        assert!(requests[0].is_ok());
+        assert_eq!(requests[1].as_ref().unwrap_err().id, json!(2));
+    }
+
+    #[test]
+    fn test_parse_error() {
+        let JsonRpcMessage::Single(Err(response)) = JsonRpcMessage::parse("{") else {
+            panic!("Expected a parse error");
+        };
+        assert_eq!(response.error.unwrap().code, JsonRpcError::PARSE_ERROR);
+        assert_eq!(
+            JsonRpcMessage::parse("[]"),
+            JsonRpcMessage::Single(Err(JsonRpcResponse::error(
+                Value::Null,
+                JsonRpcError::invalid_request()
+            )))
+        );
+    }
+
+    #[test]
+    fn test_notification() {
+        let message = RpcMessage::RoomMessage {
+            room: "ops".to_string(),
+            sender: "a".to_string(),
+            payload: "{}".to_string(),
+        };
+        let notification = JsonRpcRequest::notification(&message).unwrap();
+        assert!(notification.is_notification());
+        assert_eq!(
+            serde_json::to_value(&notification).unwrap(),
+            json!({
+                "jsonrpc": "2.0",
+                "method": "RoomMessage",
+                "params": {"room": "ops", "sender": "a", "payload": "{}"}
+            })
+        );
+    }
+
+    #[test]
+    fn test_results() {
+        let success = JsonRpcResponse::from_result(
+            json!(1),
+            RpcResult::value(RpcMessage::ClientId {
+                id: "a".to_string(),
+            }),
+        );
+        assert_eq!(success.result, Some(json!({"ClientId": {"id": "a"}})));
+
+        let error = JsonRpcResponse::from_result(json!(1), RpcResult::Error(Error::Cancelled));
+        let error = error.error.unwrap();
+        assert_eq!(error.code, JsonRpcError::COMMAND_ERROR);
+        assert_eq!(error.data, Some(json!("Cancelled")));
+    }
+}
@@ -1,12 +1,69 @@
 mod client;
+mod codec;
+mod heartbeat;
+mod limits;
+mod queue;
+mod service;
+mod transfer;
+mod transport;
 
 #[cfg(feature = "contract")]
 mod contract;
 
-pub use self::{client::*, contract::*};
+#[cfg(feature = "contract")]
+mod jsonrpc;
+
+#[cfg(feature = "schema")]
+mod schema;
+
+pub use self::{
+    client::*, codec::*, contract::*, heartbeat::*, jsonrpc::*, limits::*, queue::*, service::*,
+    transfer::*, transport::*,
+};
+pub use rpc_macros::rpc_service;
+
+/// Used by the code `rpc_service` generates
+#[doc(hidden)]
+pub mod __private {
+    pub use serde;
+    pub use serde_json;
+}
+
+#[cfg(feature = "schema")]
+pub use self::schema::*;
+
+#[cfg(not(target_arch = "wasm32"))]
+mod auth;
+
+#[cfg(not(target_arch = "wasm32"))]
+mod cancellation;
+
+#[cfg(not(target_arch = "wasm32"))]
+mod dispatch;
 
 #[cfg(not(target_arch = "wasm32"))]
 mod executor;
 
 #[cfg(not(target_arch = "wasm32"))]
-pub use self::executor::*;
+mod idempotency;
+
+#[cfg(not(target_arch = "wasm32"))]
+mod loopback;
+
+#[cfg(not(target_arch = "wasm32"))]
+mod rooms;
+
+#[cfg(not(target_arch = "wasm32"))]
+mod session;
+
+#[cfg(not(target_arch = "wasm32"))]
+mod stream;
+
+#[cfg(not(target_arch = "wasm32"))]
+mod uploads;
+
+#[cfg(not(target_arch = "wasm32"))]
+pub use self::{
+    auth::*, cancellation::*, dispatch::*, executor::*, idempotency::*, loopback::*, rooms::*,
+    session::*, stream::*, uploads::*,
+};
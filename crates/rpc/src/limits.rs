@@ -0,0 +1,163 @@
+use crate::Error;
+use bincode::Options;
+use serde::de::DeserializeOwned;
+use std::time::Duration;
+use web_time::Instant;
+
+/// The largest websocket frame a server accepts. Clients chunk anything
+/// bigger than `DEFAULT_CHUNK_SIZE`, so well-behaved frames stay far below this.
+pub const DEFAULT_MAX_FRAME_SIZE: usize = 1024 * 1024;
+
+/// The largest message a server accepts once decompressed or reassembled from chunks
+pub const DEFAULT_MAX_PAYLOAD_SIZE: u64 = 64 * 1024 * 1024;
+
+pub const DEFAULT_RATE_LIMIT: RateLimit = RateLimit {
+    per_second: 100,
+    burst: 200,
+};
+
+/// How many messages a connection may send, on average and in a burst
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct RateLimit {
+    pub per_second: u32,
+    pub burst: u32,
+}
+
+/// How much a single client may send
+#[derive(Debug, Clone, Copy)]
+pub struct Limits {
+    /// Larger frames are answered with `Error::FrameTooLarge`
+    pub max_frame_size: usize,
+
+    /// The largest message accepted once decompressed or reassembled from chunks.
+    /// Nothing larger is ever allocated, whatever sizes a frame claims.
+    pub max_payload_size: u64,
+
+    /// How many messages each connection may send, or unlimited when `None`
+    pub rate_limit: Option<RateLimit>,
+}
+
+impl Default for Limits {
+    fn default() -> Self {
+        Self {
+            max_frame_size: DEFAULT_MAX_FRAME_SIZE,
+            max_payload_size: DEFAULT_MAX_PAYLOAD_SIZE,
+            rate_limit: Some(DEFAULT_RATE_LIMIT),
+        }
+    }
+}
+
+/// Deserializes a message the way `bincode::deserialize` does, but never reads or allocates
+/// more than `limit` bytes, however large the lengths written into the message claim to be.
+pub fn deserialize_limited<T: DeserializeOwned>(bytes: &[u8], limit: u64) -> Result<T, Error> {
+    let size = bytes.len() as u64;
+    if size > limit {
+        return Err(Error::PayloadTooLarge { size, limit });
+    }
+
+    bincode::DefaultOptions::new()
+        .with_fixint_encoding()
+        .allow_trailing_bytes()
+        .with_limit(limit)
+        .deserialize(bytes)
+        .map_err(|error| Error::MessageDeserialization {
+            error: error.to_string(),
+        })
+}
+
+/// Lets through `burst` messages at once, then `per_second` on average
+#[derive(Debug, Clone)]
+pub struct TokenBucket {
+    limit: RateLimit,
+    tokens: f64,
+    refilled_at: Instant,
+}
+
+impl TokenBucket {
+    pub fn new(limit: RateLimit, now: Instant) -> Self {
+        Self {
+            limit,
+            tokens: limit.burst as f64,
+            refilled_at: now,
+        }
+    }
+
+    /// Takes a token for one message, or says how long until the next one is available
+    pub fn take(&mut self, now: Instant) -> Result<(), Error> {
+        let elapsed = now
+            .saturating_duration_since(self.refilled_at)
+            .as_secs_f64();
+        self.tokens =
+            (self.tokens + elapsed * self.limit.per_second as f64).min(self.limit.burst as f64);
+        self.refilled_at = now;
+
+        if self.tokens >= 1.0 {
+            self.tokens -= 1.0;
+            return Ok(());
+        }
+
+        let retry_after = match self.limit.per_second {
+            0 => Duration::MAX,
+            per_second => Duration::from_secs_f64((1.0 - self.tokens) / per_second as f64),
+        };
+        Err(Error::RateLimited {
+            retry_after_ms: retry_after.as_millis().min(u64::MAX as u128) as u64,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{deserialize_limited, RateLimit, TokenBucket};
+    use crate::{Command, Error, Message};
+    use std::time::Duration;
+    use web_time::Instant;
+
+    #[test]
+    fn test_matches_bincode() {
+        let message = Message::new("1".to_string(), Command::Example);
+        let bytes = bincode::serialize(&message).unwrap();
+        let decoded = deserialize_limited::<Message>(&bytes, 1024).unwrap();
+        assert_eq!(decoded.id, "1");
+        assert_eq!(decoded.command, Command::Example);
+    }
+
+    #[test]
+    fn test_lying_length_is_rejected() {
+        // A string claiming to be 1 TiB long, followed by nothing
+        let bytes = (1u64 << 40).to_le_bytes();
+        assert!(matches!(
+            deserialize_limited::<String>(&bytes, 1024),
+            Err(Error::MessageDeserialization { .. })
+        ));
+        assert_eq!(
+            deserialize_limited::<String>(&[0; 16], 8),
+            Err(Error::PayloadTooLarge { size: 16, limit: 8 })
+        );
+    }
+
+    #[test]
+    fn test_token_bucket() {
+        let now = Instant::now();
+        let limit = RateLimit {
+            per_second: 10,
+            burst: 2,
+        };
+        let mut bucket = TokenBucket::new(limit, now);
+        assert!(bucket.take(now).is_ok());
+        assert!(bucket.take(now).is_ok());
+        assert_eq!(
+            bucket.take(now),
+            Err(Error::RateLimited {
+                retry_after_ms: 100
+            })
+        );
+        assert!(bucket.take(now + Duration::from_millis(100)).is_ok());
+
+        // Idle time never saves up more than the burst
+        let later = now + Duration::from_secs(60);
+        assert!(bucket.take(later).is_ok());
+        assert!(bucket.take(later).is_ok());
+        assert!(bucket.take(later).is_err());
+    }
+}
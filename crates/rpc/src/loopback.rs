@@ -0,0 +1,301 @@
+use crate::{Backend, Outgoing, RpcExecutor, Transport, TransportEvent};
+use std::{
+    sync::{mpsc, Arc},
+    thread,
+};
+
+/// The peer address loopback sessions are opened with
+const LOOPBACK_PEER: &str = "loopback";
+
+/// Runs an executor in-process behind the same encoding, dispatch and decoding
+/// a remote server uses, so the wire format is exercised without a network.
+/// Commands execute on background threads and never block the caller.
+pub struct LoopbackTransport {
+    frames: mpsc::Sender<Vec<u8>>,
+    events: mpsc::Receiver<TransportEvent>,
+}
+
+impl LoopbackTransport {
+    /// Starts a backend running `executor`, which calls `wake_up` whenever it has something to read
+    pub fn new(executor: RpcExecutor, wake_up: impl Fn() + Send + Sync + 'static) -> Self {
+        Self::connect(&Arc::new(Backend::new(executor)), wake_up)
+    }
+
+    /// Connects to a backend that other connections may share, like clients of a server
+    pub fn connect(backend: &Arc<Backend>, wake_up: impl Fn() + Send + Sync + 'static) -> Self {
+        let (frames, incoming) = mpsc::channel();
+        let (events, received) = mpsc::channel();
+        let backend = backend.clone();
+        thread::spawn(move || serve(&backend, incoming, events, Arc::new(wake_up)));
+        Self {
+            frames,
+            events: received,
+        }
+    }
+}
+
+impl Transport for LoopbackTransport {
+    fn send(&mut self, frame: Vec<u8>) {
+        if self.frames.send(frame).is_err() {
+            log::error!("The loopback backend has stopped");
+        }
+    }
+
+    fn try_recv(&mut self) -> Option<TransportEvent> {
+        self.events.try_recv().ok()
+    }
+}
+
+/// Reads frames in order and answers control messages right away, like a server connection.
+/// Each command runs on a thread of its own, so cancellations can be read while it runs.
+fn serve(
+    backend: &Arc<Backend>,
+    incoming: mpsc::Receiver<Vec<u8>>,
+    events: mpsc::Sender<TransportEvent>,
+    wake_up: Arc<dyn Fn() + Send + Sync>,
+) {
+    if events.send(TransportEvent::Opened).is_ok() {
+        wake_up();
+    }
+    let connection = backend.connect(LOOPBACK_PEER, move |outgoing| match outgoing {
+        Outgoing::Binary(frame) => {
+            if events.send(TransportEvent::Frame(frame)).is_ok() {
+                wake_up();
+            }
+        }
+
+        // Only clients that send text frames are answered with them
+        Outgoing::Text(_) => {}
+    });
+
+    for frame in incoming {
+        if let Some(job) = connection.receive_frame(&frame) {
+            let executing = connection.clone();
+            thread::spawn(move || {
+                let response = executing.execute(job);
+                executing.respond(response);
+            });
+        }
+    }
+
+    // The transport was dropped, so nobody is left to receive results
+    connection.close();
+}
+
+#[cfg(test)]
+mod tests {
+    use super::LoopbackTransport;
+    use crate::{
+        CallContext, Command, Compression, Error, Message, PayloadJson, Response, RpcClient,
+        RpcExecutor, RpcMessage, RpcResult, RpcService, DEFAULT_CHUNK_SIZE,
+    };
+    use std::time::{Duration, Instant};
+
+    struct Echo;
+
+    impl RpcService for Echo {
+        fn name(&self) -> &'static str {
+            "echo"
+        }
+
+        fn call(
+            &mut self,
+            _context: &mut CallContext<'_>,
+            payload: &str,
+        ) -> Result<PayloadJson, String> {
+            Ok(payload.to_string())
+        }
+    }
+
+    // Answers with how many times it has been called
+    #[derive(Default)]
+    struct Counter(usize);
+
+    impl RpcService for Counter {
+        fn name(&self) -> &'static str {
+            "counter"
+        }
+
+        fn call(
+            &mut self,
+            _context: &mut CallContext<'_>,
+            _payload: &str,
+        ) -> Result<PayloadJson, String> {
+            self.0 += 1;
+            Ok(self.0.to_string())
+        }
+    }
+
+    // Runs until it is cancelled
+    struct Waiter;
+
+    impl RpcService for Waiter {
+        fn name(&self) -> &'static str {
+            "waiter"
+        }
+
+        fn call(
+            &mut self,
+            context: &mut CallContext<'_>,
+            _payload: &str,
+        ) -> Result<PayloadJson, String> {
+            let deadline = Instant::now() + Duration::from_secs(5);
+            while !context.cancellation.is_cancelled() {
+                if Instant::now() > deadline {
+                    return Err("Never cancelled".to_string());
+                }
+                std::thread::sleep(Duration::from_millis(10));
+            }
+            Ok("cancelled".to_string())
+        }
+    }
+
+    fn client() -> RpcClient {
+        let executor = RpcExecutor::default()
+            .with_service(Echo)
+            .with_service(Counter::default())
+            .with_service(Waiter);
+        RpcClient::new(LoopbackTransport::new(executor, || {}))
+    }
+
+    fn wait_for(client: &mut RpcClient, id: &str) -> RpcResult {
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while Instant::now() < deadline {
+            match client.receive() {
+                Some(Response {
+                    id: received,
+                    result,
+                }) if received == id => return result,
+                Some(_) => {}
+                None => std::thread::sleep(Duration::from_millis(1)),
+            }
+        }
+        panic!("No response for '{id}'");
+    }
+
+    #[test]
+    fn test_round_trip() {
+        let mut client = client();
+        client.send("1".to_string(), Command::Example);
+        assert_eq!(wait_for(&mut client, "1"), RpcResult::default());
+        assert!(client.is_open());
+
+        client.send(
+            "2".to_string(),
+            Command::LeaveRoom {
+                room: "ops".to_string(),
+            },
+        );
+        assert_eq!(
+            wait_for(&mut client, "2"),
+            RpcResult::Error(Error::NotInRoom {
+                room: "ops".to_string()
+            })
+        );
+    }
+
+    #[test]
+    fn test_chunked_and_compressed() {
+        let mut client = client()
+            .with_chunk_size(256)
+            .with_compression(Compression::Lz4, 64);
+        let payload = "x".repeat(4096);
+        client.send(
+            "1".to_string(),
+            Command::Service {
+                service: "echo".to_string(),
+                call_id: "call".to_string(),
+                payload: payload.to_string(),
+            },
+        );
+        assert_eq!(
+            wait_for(&mut client, "1"),
+            RpcResult::value(RpcMessage::Service {
+                service: "echo".to_string(),
+                call_id: "call".to_string(),
+                payload,
+            })
+        );
+
+        // The answer to the proposal may arrive after the command's
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while client.compression() != Compression::Lz4 {
+            assert!(Instant::now() < deadline, "Compression was not negotiated");
+            client.receive();
+            std::thread::sleep(Duration::from_millis(1));
+        }
+    }
+
+    #[test]
+    fn test_large_response() {
+        let mut client = client();
+        let payload = "x".repeat(DEFAULT_CHUNK_SIZE + 1);
+        client.send(
+            "1".to_string(),
+            Command::Service {
+                service: "echo".to_string(),
+                call_id: "call".to_string(),
+                payload: payload.to_string(),
+            },
+        );
+        assert_eq!(
+            wait_for(&mut client, "1"),
+            RpcResult::value(RpcMessage::Service {
+                service: "echo".to_string(),
+                call_id: "call".to_string(),
+                payload,
+            })
+        );
+    }
+
+    #[test]
+    fn test_idempotent_retry() {
+        let mut client = client();
+        let count = Command::Service {
+            service: "counter".to_string(),
+            call_id: "call".to_string(),
+            payload: String::new(),
+        };
+        let counted = |payload: &str| {
+            RpcResult::value(RpcMessage::Service {
+                service: "counter".to_string(),
+                call_id: "call".to_string(),
+                payload: payload.to_string(),
+            })
+        };
+
+        // The retry is answered with the first result instead of running again
+        for id in ["1", "2"] {
+            let message =
+                Message::new(id.to_string(), count.clone()).with_idempotency_key("key".into());
+            client.send_message(message);
+            assert_eq!(wait_for(&mut client, id), counted("1"));
+        }
+        client.send("3".to_string(), count);
+        assert_eq!(wait_for(&mut client, "3"), counted("2"));
+    }
+
+    #[test]
+    fn test_cancel_running_command() {
+        let mut client = client();
+        client.send(
+            "1".to_string(),
+            Command::Service {
+                service: "waiter".to_string(),
+                call_id: "call".to_string(),
+                payload: String::new(),
+            },
+        );
+        std::thread::sleep(Duration::from_millis(50));
+        client.send(
+            "2".to_string(),
+            Command::Cancel {
+                id: "1".to_string(),
+            },
+        );
+        assert_eq!(
+            wait_for(&mut client, "1"),
+            RpcResult::Error(Error::Cancelled)
+        );
+    }
+}
@@ -0,0 +1,112 @@
+use crate::Message;
+use std::{collections::VecDeque, time::Duration};
+use web_time::Instant;
+
+/// How many commands are held while disconnected before new ones are refused
+pub const DEFAULT_QUEUE_CAPACITY: usize = 256;
+
+/// How long a command waits for a connection before it is given up on
+pub const DEFAULT_QUEUE_EXPIRY: Duration = Duration::from_secs(60);
+
+/// Holds the messages issued while there is no open connection, in the order they were issued
+#[derive(Debug, Clone)]
+pub struct OfflineQueue {
+    capacity: usize,
+    expiry: Duration,
+    messages: VecDeque<(Instant, Message)>,
+}
+
+impl Default for OfflineQueue {
+    fn default() -> Self {
+        Self::new(DEFAULT_QUEUE_CAPACITY, DEFAULT_QUEUE_EXPIRY)
+    }
+}
+
+impl OfflineQueue {
+    pub fn new(capacity: usize, expiry: Duration) -> Self {
+        Self {
+            capacity,
+            expiry,
+            messages: VecDeque::new(),
+        }
+    }
+
+    pub fn capacity(&self) -> usize {
+        self.capacity
+    }
+
+    /// Queues `message`, or hands it back if the queue is full
+    pub fn push(&mut self, message: Message, now: Instant) -> Result<(), Message> {
+        if self.messages.len() >= self.capacity {
+            return Err(message);
+        }
+        self.messages.push_back((now, message));
+        Ok(())
+    }
+
+    /// Removes and returns the messages that have waited longer than the expiry
+    pub fn expire(&mut self, now: Instant) -> Vec<Message> {
+        let mut expired = Vec::new();
+        while let Some((queued_at, _)) = self.messages.front() {
+            if now.duration_since(*queued_at) < self.expiry {
+                break;
+            }
+            if let Some((_, message)) = self.messages.pop_front() {
+                expired.push(message);
+            }
+        }
+        expired
+    }
+
+    /// Takes every queued message, oldest first
+    pub fn drain(&mut self) -> impl Iterator<Item = Message> + '_ {
+        self.messages.drain(..).map(|(_, message)| message)
+    }
+
+    pub fn len(&self) -> usize {
+        self.messages.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.messages.is_empty()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::OfflineQueue;
+    use crate::{Command, Message};
+    use std::time::Duration;
+    use web_time::Instant;
+
+    fn message(id: &str) -> Message {
+        Message::new(id.to_string(), Command::Example)
+    }
+
+    #[test]
+    fn test_capacity() {
+        let mut queue = OfflineQueue::new(1, Duration::from_secs(1));
+        let now = Instant::now();
+        assert!(queue.push(message("a"), now).is_ok());
+        assert_eq!(queue.push(message("b"), now), Err(message("b")));
+    }
+
+    #[test]
+    fn test_expiry_keeps_order() {
+        let mut queue = OfflineQueue::new(8, Duration::from_secs(10));
+        let now = Instant::now();
+        queue.push(message("a"), now).unwrap();
+        queue
+            .push(message("b"), now + Duration::from_secs(5))
+            .unwrap();
+        queue
+            .push(message("c"), now + Duration::from_secs(6))
+            .unwrap();
+
+        let expired = queue.expire(now + Duration::from_secs(10));
+        assert_eq!(expired, vec![message("a")]);
+        let ids = queue.drain().map(|message| message.id).collect::<Vec<_>>();
+        assert_eq!(ids, vec!["b", "c"]);
+        assert!(queue.is_empty());
+    }
+}
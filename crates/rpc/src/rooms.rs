@@ -0,0 +1,106 @@
+use crate::Id;
+use std::collections::{BTreeSet, HashMap};
+
+/// Named groups of connections that broadcasts are delivered to.
+/// A room exists for as long as it has members.
+#[derive(Default)]
+pub struct Rooms {
+    rooms: HashMap<String, BTreeSet<Id>>,
+}
+
+impl Rooms {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Adds a client to a room, returning how many members the room has now
+    pub fn join(&mut self, room: &str, client_id: &str) -> usize {
+        let members = self.rooms.entry(room.to_string()).or_default();
+        members.insert(client_id.to_string());
+        members.len()
+    }
+
+    /// Removes a client from a room, returning whether it was a member
+    pub fn leave(&mut self, room: &str, client_id: &str) -> bool {
+        let Some(members) = self.rooms.get_mut(room) else {
+            return false;
+        };
+        let removed = members.remove(client_id);
+        if members.is_empty() {
+            self.rooms.remove(room);
+        }
+        removed
+    }
+
+    /// Removes a client from every room, when its connection closes
+    pub fn leave_all(&mut self, client_id: &str) {
+        self.rooms.retain(|_, members| {
+            members.remove(client_id);
+            !members.is_empty()
+        });
+    }
+
+    pub fn is_member(&self, room: &str, client_id: &str) -> bool {
+        self.rooms
+            .get(room)
+            .map_or(false, |members| members.contains(client_id))
+    }
+
+    pub fn members(&self, room: &str) -> impl Iterator<Item = &Id> {
+        self.rooms.get(room).into_iter().flatten()
+    }
+
+    /// The rooms a client is a member of
+    pub fn joined<'a>(&'a self, client_id: &'a str) -> impl Iterator<Item = &'a String> {
+        self.rooms
+            .iter()
+            .filter(move |(_, members)| members.contains(client_id))
+            .map(|(room, _)| room)
+    }
+
+    pub fn len(&self) -> usize {
+        self.rooms.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.rooms.is_empty()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Rooms;
+
+    #[test]
+    fn test_join_and_leave() {
+        let mut rooms = Rooms::new();
+        assert_eq!(rooms.join("ops", "a"), 1);
+        assert_eq!(rooms.join("ops", "b"), 2);
+        assert_eq!(rooms.join("ops", "b"), 2);
+        assert!(rooms.is_member("ops", "a"));
+        assert_eq!(rooms.members("ops").collect::<Vec<_>>(), ["a", "b"]);
+
+        assert!(rooms.leave("ops", "a"));
+        assert!(!rooms.leave("ops", "a"));
+        assert!(!rooms.is_member("ops", "a"));
+
+        // The last member leaving removes the room
+        assert!(rooms.leave("ops", "b"));
+        assert!(rooms.is_empty());
+        assert_eq!(rooms.members("ops").count(), 0);
+    }
+
+    #[test]
+    fn test_leave_all() {
+        let mut rooms = Rooms::new();
+        rooms.join("ops", "a");
+        rooms.join("ops", "b");
+        rooms.join("alerts", "a");
+        assert_eq!(rooms.joined("a").count(), 2);
+
+        rooms.leave_all("a");
+        assert_eq!(rooms.joined("a").count(), 0);
+        assert_eq!(rooms.len(), 1);
+        assert!(rooms.is_member("ops", "b"));
+    }
+}
@@ -0,0 +1,93 @@
+use crate::{Command, Error, Message, PayloadJson, Response, RpcMessage, RpcResult};
+use schemars::{
+    gen::{SchemaGenerator, SchemaSettings},
+    schema::{Metadata, RootSchema, Schema, SchemaObject, SubschemaValidation},
+    JsonSchema,
+};
+
+/// Collects the JSON Schemas of contract types into a single document.
+/// Every type is listed under `definitions`, and the document itself
+/// validates a value of any of the types that were added.
+pub struct ContractSchema {
+    generator: SchemaGenerator,
+    roots: Vec<Schema>,
+}
+
+impl Default for ContractSchema {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl ContractSchema {
+    /// Starts with the types of the RPC contract
+    pub fn new() -> Self {
+        Self {
+            generator: SchemaSettings::draft07().into_generator(),
+            roots: Vec::new(),
+        }
+        .with::<Message>()
+        .with::<Response>()
+        .with::<Command>()
+        .with::<RpcResult>()
+        .with::<RpcMessage>()
+        .with::<Error>()
+    }
+
+    pub fn with<T: JsonSchema>(mut self) -> Self {
+        let schema = self.generator.subschema_for::<T>();
+        if !self.roots.contains(&schema) {
+            self.roots.push(schema);
+        }
+        self
+    }
+
+    pub fn root(mut self) -> RootSchema {
+        RootSchema {
+            meta_schema: self.generator.settings().meta_schema.clone(),
+            schema: SchemaObject {
+                metadata: Some(Box::new(Metadata {
+                    title: Some("Contract".to_string()),
+                    ..Default::default()
+                })),
+                subschemas: Some(Box::new(SubschemaValidation {
+                    any_of: Some(self.roots),
+                    ..Default::default()
+                })),
+                ..Default::default()
+            },
+            definitions: self.generator.take_definitions(),
+        }
+    }
+
+    pub fn to_json(self) -> PayloadJson {
+        serde_json::to_string_pretty(&self.root()).expect("Schemas are always serializable")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::ContractSchema;
+
+    #[test]
+    fn test_contract_definitions() {
+        let root = ContractSchema::new().root();
+        for name in [
+            "Message",
+            "Command",
+            "RpcResult",
+            "Error",
+            "Chunk",
+            "Credential",
+        ] {
+            assert!(root.definitions.contains_key(name), "Missing {name}");
+        }
+    }
+
+    #[test]
+    fn test_roots_are_not_repeated() {
+        let root = ContractSchema::new().with::<crate::Command>().root();
+        let roots = root.schema.subschemas.unwrap().any_of.unwrap();
+        assert_eq!(roots.len(), 6);
+    }
+}
@@ -0,0 +1,304 @@
+#[cfg(not(target_arch = "wasm32"))]
+use crate::{CancellationToken, Session};
+use crate::{Error, Id, PayloadJson};
+use serde::{de::DeserializeOwned, Serialize};
+use std::marker::PhantomData;
+
+/// Sends `Command::Service` requests for the client stubs generated by `rpc_service`
+pub trait ServiceClient {
+    /// Whatever the client needs to publish a command, such as the broker
+    type Context;
+
+    /// Sends `payload` to `service` and returns the call id its response will carry
+    fn call_service(
+        &mut self,
+        context: &mut Self::Context,
+        service: &str,
+        payload: PayloadJson,
+    ) -> Id;
+}
+
+/// A service call in flight, typed with the return type of the method that was called
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ServiceCall<R> {
+    call_id: Id,
+    response: PhantomData<fn() -> R>,
+}
+
+impl<R: DeserializeOwned> ServiceCall<R> {
+    pub fn send<C, Q>(
+        client: &mut C,
+        context: &mut C::Context,
+        service: &str,
+        request: &Q,
+    ) -> Result<Self, Error>
+    where
+        C: ServiceClient + ?Sized,
+        Q: Serialize,
+    {
+        let payload =
+            serde_json::to_string(request).map_err(|error| Error::CommandSerialization {
+                error: error.to_string(),
+            })?;
+        Ok(Self {
+            call_id: client.call_service(context, service, payload),
+            response: PhantomData,
+        })
+    }
+
+    /// Matches the `call_id` of `RpcMessage::Service` and `Error::Service` results
+    pub fn call_id(&self) -> &Id {
+        &self.call_id
+    }
+
+    pub fn decode(&self, payload: &str) -> Result<R, Error> {
+        serde_json::from_str(payload).map_err(|error| Error::RpcResultDeserialization {
+            error: error.to_string(),
+        })
+    }
+}
+
+/// What a service call runs with besides its request
+#[cfg(not(target_arch = "wasm32"))]
+pub struct CallContext<'a> {
+    /// The session of the client that sent the call, which keeps its values between calls
+    pub session: &'a mut Session,
+
+    /// Set once the client cancels the call. Long running services should check it
+    /// and return early, as their result is discarded anyway.
+    pub cancellation: &'a CancellationToken,
+}
+
+/// A service the executor dispatches `Command::Service` requests to,
+/// usually generated by `rpc_service` as `{Trait}Service`
+#[cfg(not(target_arch = "wasm32"))]
+pub trait RpcService: Send + Sync {
+    fn name(&self) -> &'static str;
+
+    /// Answers a serialized request with a serialized response
+    fn call(&mut self, context: &mut CallContext<'_>, payload: &str)
+        -> Result<PayloadJson, String>;
+}
+
+#[cfg(all(test, not(target_arch = "wasm32")))]
+mod tests {
+    use super::{CallContext, RpcService};
+    use crate::{
+        rpc_service, CancellationToken, Command, Error, Id, PayloadJson, RpcExecutor, RpcMessage,
+        RpcResult, Session,
+    };
+    use std::time::{Duration, Instant};
+
+    #[rpc_service(crate = "crate")]
+    trait Counter {
+        fn add(&mut self, amount: u32) -> u32;
+        fn total(&self) -> u32;
+    }
+
+    #[rpc_service(crate = "crate")]
+    trait Visits {
+        fn visit(&mut self, context: &mut CallContext<'_>, page: String) -> u32;
+    }
+
+    // Counts the visits of each client in its session
+    struct Visitors;
+
+    impl Visits for Visitors {
+        fn visit(&mut self, context: &mut CallContext<'_>, page: String) -> u32 {
+            let visits = context
+                .session
+                .value(&page)
+                .and_then(|visits| visits.parse::<u32>().ok())
+                .unwrap_or_default()
+                + 1;
+            context.session.set_value(&page, visits.to_string());
+            visits
+        }
+    }
+
+    struct Tally(u32);
+
+    impl Counter for Tally {
+        fn add(&mut self, amount: u32) -> u32 {
+            self.0 += amount;
+            self.0
+        }
+
+        fn total(&self) -> u32 {
+            self.0
+        }
+    }
+
+    #[derive(Default)]
+    struct Recorder {
+        commands: Vec<Command>,
+    }
+
+    impl super::ServiceClient for Recorder {
+        type Context = ();
+
+        fn call_service(&mut self, _context: &mut (), service: &str, payload: PayloadJson) -> Id {
+            let call_id = self.commands.len().to_string();
+            self.commands.push(Command::Service {
+                service: service.to_string(),
+                call_id: call_id.to_string(),
+                payload,
+            });
+            call_id
+        }
+    }
+
+    // Remembers the last payload it was given in the caller's session
+    struct Notes;
+
+    impl RpcService for Notes {
+        fn name(&self) -> &'static str {
+            "notes"
+        }
+
+        fn call(
+            &mut self,
+            context: &mut CallContext<'_>,
+            payload: &str,
+        ) -> Result<PayloadJson, String> {
+            let previous = context.session.set_value("note", payload.to_string());
+            Ok(previous.unwrap_or_default())
+        }
+    }
+
+    // Runs until it is cancelled, saying whether it was
+    struct Waiter;
+
+    impl RpcService for Waiter {
+        fn name(&self) -> &'static str {
+            "waiter"
+        }
+
+        fn call(
+            &mut self,
+            context: &mut CallContext<'_>,
+            _payload: &str,
+        ) -> Result<PayloadJson, String> {
+            let deadline = Instant::now() + Duration::from_secs(5);
+            while !context.cancellation.is_cancelled() {
+                if Instant::now() > deadline {
+                    return Err("Never cancelled".to_string());
+                }
+                std::thread::sleep(Duration::from_millis(10));
+            }
+            Ok("cancelled".to_string())
+        }
+    }
+
+    fn execute(executor: &RpcExecutor, command: Command) -> RpcResult {
+        execute_in(executor, &mut Session::new("test"), command)
+    }
+
+    fn execute_in(executor: &RpcExecutor, session: &mut Session, command: Command) -> RpcResult {
+        executor.execute(
+            session,
+            &"id".to_string(),
+            command,
+            &CancellationToken::new(),
+        )
+    }
+
+    fn note(payload: &str) -> Command {
+        Command::Service {
+            service: "notes".to_string(),
+            call_id: "call".to_string(),
+            payload: payload.to_string(),
+        }
+    }
+
+    fn noted(result: RpcResult) -> PayloadJson {
+        match result {
+            RpcResult::Success(RpcMessage::Service { payload, .. }) => payload,
+            result => panic!("Expected a service response, got {result:?}"),
+        }
+    }
+
+    #[test]
+    fn test_session_values() {
+        let executor = RpcExecutor::default().with_service(Notes);
+        let mut session = Session::new("test");
+        assert_eq!(
+            noted(execute_in(&executor, &mut session, note("first"))),
+            ""
+        );
+        assert_eq!(
+            noted(execute_in(&executor, &mut session, note("second"))),
+            "first"
+        );
+        assert_eq!(session.value("note").map(String::as_str), Some("second"));
+
+        // Another client's session starts empty
+        assert_eq!(noted(execute(&executor, note("other"))), "");
+    }
+
+    #[test]
+    fn test_service_round_trip() {
+        let mut client = Recorder::default();
+        let call = client.add(&mut (), 2).unwrap();
+        let executor = RpcExecutor::default().with_service(CounterService(Tally(40)));
+
+        let result = execute(&executor, client.commands.remove(0));
+        let RpcResult::Success(RpcMessage::Service {
+            call_id, payload, ..
+        }) = result
+        else {
+            panic!("Expected a service response");
+        };
+        assert_eq!(&call_id, call.call_id());
+        assert_eq!(call.decode(&payload), Ok(42));
+    }
+
+    #[test]
+    fn test_unknown_service() {
+        let mut client = Recorder::default();
+        client.total(&mut ()).unwrap();
+        let result = execute(&RpcExecutor::default(), client.commands.remove(0));
+        assert_eq!(
+            result,
+            RpcResult::Error(Error::UnknownService {
+                service: "Counter".to_string(),
+                call_id: "0".to_string(),
+            })
+        );
+    }
+
+    #[test]
+    fn test_cancel_running_call() {
+        let executor = RpcExecutor::default().with_service(Waiter);
+        let cancellation = CancellationToken::new();
+        let command = Command::Service {
+            service: "waiter".to_string(),
+            call_id: "call".to_string(),
+            payload: String::new(),
+        };
+
+        let result = std::thread::scope(|scope| {
+            let running = scope.spawn(|| {
+                let mut session = Session::new("test");
+                executor.execute(&mut session, &"id".to_string(), command, &cancellation)
+            });
+            std::thread::sleep(Duration::from_millis(50));
+            cancellation.cancel();
+            running.join().unwrap()
+        });
+        assert_eq!(noted(result), "cancelled");
+    }
+
+    #[test]
+    fn test_service_context() {
+        let mut client = Recorder::default();
+        let call = client.visit(&mut (), "home".to_string()).unwrap();
+        let executor = RpcExecutor::default().with_service(VisitsService(Visitors));
+
+        let mut session = Session::new("test");
+        let command = client.commands.remove(0);
+        execute_in(&executor, &mut session, command.clone());
+        let payload = noted(execute_in(&executor, &mut session, command));
+        assert_eq!(call.decode(&payload), Ok(2));
+    }
+}
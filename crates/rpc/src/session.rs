@@ -0,0 +1,104 @@
+use crate::{Id, PayloadJson};
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+use uuid::Uuid;
+
+/// The state the server keeps for a single connected client
+pub struct Session {
+    id: Id,
+    peer_address: String,
+    connected_at: Instant,
+    subject: Option<String>,
+    values: HashMap<String, PayloadJson>,
+}
+
+impl Session {
+    pub fn new(peer_address: &str) -> Self {
+        Self {
+            id: Uuid::new_v4().to_string(),
+            peer_address: peer_address.to_string(),
+            connected_at: Instant::now(),
+            subject: None,
+            values: HashMap::new(),
+        }
+    }
+
+    pub fn id(&self) -> &Id {
+        &self.id
+    }
+
+    pub fn peer_address(&self) -> &str {
+        &self.peer_address
+    }
+
+    pub fn connected_for(&self) -> Duration {
+        self.connected_at.elapsed()
+    }
+
+    /// The identity the client authenticated as, if the server requires authentication
+    pub fn subject(&self) -> Option<&str> {
+        self.subject.as_deref()
+    }
+
+    pub fn authenticate(&mut self, subject: &str) {
+        self.subject = Some(subject.to_string());
+    }
+
+    pub fn value(&self, key: &str) -> Option<&PayloadJson> {
+        self.values.get(key)
+    }
+
+    pub fn set_value(&mut self, key: &str, value: PayloadJson) -> Option<PayloadJson> {
+        self.values.insert(key.to_string(), value)
+    }
+
+    pub fn remove_value(&mut self, key: &str) -> Option<PayloadJson> {
+        self.values.remove(key)
+    }
+}
+
+/// Every session that is currently connected to the server, keyed by client id.
+/// Each session is locked on its own, so a command running for one client
+/// never holds up the commands of another.
+#[derive(Default)]
+pub struct Sessions {
+    sessions: HashMap<Id, Arc<Mutex<Session>>>,
+}
+
+impl Sessions {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn open(&mut self, peer_address: &str) -> Id {
+        let session = Session::new(peer_address);
+        let id = session.id().to_string();
+        self.sessions
+            .insert(id.to_string(), Arc::new(Mutex::new(session)));
+        id
+    }
+
+    pub fn close(&mut self, id: &str) -> Option<Arc<Mutex<Session>>> {
+        self.sessions.remove(id)
+    }
+
+    /// The session to lock while running a command, which stays usable after it is closed
+    pub fn get(&self, id: &str) -> Option<Arc<Mutex<Session>>> {
+        self.sessions.get(id).cloned()
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &Arc<Mutex<Session>>> {
+        self.sessions.values()
+    }
+
+    pub fn len(&self) -> usize {
+        self.sessions.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.sessions.is_empty()
+    }
+}
@@ -0,0 +1,215 @@
+use crate::{Transport, TransportEvent, DEFAULT_MAX_FRAME_SIZE};
+use std::{
+    io::{self, Read, Write},
+    net::{Shutdown, TcpStream, ToSocketAddrs},
+    sync::mpsc,
+    thread,
+};
+
+#[cfg(unix)]
+use std::{os::unix::net::UnixStream, path::Path};
+
+/// Writes a frame prefixed with its length as a big endian u32,
+/// which is how frames are delimited on TCP and Unix domain sockets
+pub fn write_frame(writer: &mut impl Write, frame: &[u8]) -> io::Result<()> {
+    let length = u32::try_from(frame.len()).map_err(|_| {
+        io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "The frame is too large for its length prefix",
+        )
+    })?;
+    writer.write_all(&length.to_be_bytes())?;
+    writer.write_all(frame)?;
+    writer.flush()
+}
+
+/// Reads a length prefixed frame, or `None` once the stream has ended.
+/// Lengths above `max_frame_size` are refused before anything is read.
+pub fn read_frame(reader: &mut impl Read, max_frame_size: usize) -> io::Result<Option<Vec<u8>>> {
+    let mut length = [0; 4];
+    match reader.read_exact(&mut length) {
+        Ok(()) => {}
+        Err(error) if error.kind() == io::ErrorKind::UnexpectedEof => return Ok(None),
+        Err(error) => return Err(error),
+    }
+
+    let length = u32::from_be_bytes(length) as usize;
+    if length > max_frame_size {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("A frame of {length} bytes is larger than the limit of {max_frame_size} bytes"),
+        ));
+    }
+
+    // The buffer grows as bytes arrive, so a prefix alone can't claim a large allocation
+    let mut frame = Vec::new();
+    reader
+        .by_ref()
+        .take(length as u64)
+        .read_to_end(&mut frame)?;
+    if frame.len() < length {
+        return Err(io::ErrorKind::UnexpectedEof.into());
+    }
+    Ok(Some(frame))
+}
+
+/// Sends length prefixed frames over a byte stream, for local tools and tests
+/// that talk to the server without a websocket stack.
+/// Incoming frames are read on a background thread, which calls `wake_up` after each event.
+pub struct StreamTransport {
+    writer: Box<dyn Write + Send>,
+    events: mpsc::Receiver<TransportEvent>,
+
+    /// Closes the underlying stream, which the reader thread holds a handle to as well
+    shutdown: Option<Box<dyn FnOnce() + Send>>,
+}
+
+impl StreamTransport {
+    pub fn connect_tcp(
+        address: impl ToSocketAddrs,
+        wake_up: impl Fn() + Send + Sync + 'static,
+    ) -> io::Result<Self> {
+        let stream = TcpStream::connect(address)?;
+        stream.set_nodelay(true)?;
+        let reader = stream.try_clone()?;
+        let closer = stream.try_clone()?;
+        Ok(Self::new(reader, stream, wake_up).with_shutdown(move || {
+            let _ = closer.shutdown(Shutdown::Both);
+        }))
+    }
+
+    #[cfg(unix)]
+    pub fn connect_unix(
+        path: impl AsRef<Path>,
+        wake_up: impl Fn() + Send + Sync + 'static,
+    ) -> io::Result<Self> {
+        let stream = UnixStream::connect(path)?;
+        let reader = stream.try_clone()?;
+        let closer = stream.try_clone()?;
+        Ok(Self::new(reader, stream, wake_up).with_shutdown(move || {
+            let _ = closer.shutdown(Shutdown::Both);
+        }))
+    }
+
+    /// Wraps the two halves of a stream that is already connected
+    pub fn new(
+        mut reader: impl Read + Send + 'static,
+        writer: impl Write + Send + 'static,
+        wake_up: impl Fn() + Send + Sync + 'static,
+    ) -> Self {
+        let (sender, events) = mpsc::channel();
+        let _ = sender.send(TransportEvent::Opened);
+        wake_up();
+
+        thread::spawn(move || loop {
+            let event = match read_frame(&mut reader, DEFAULT_MAX_FRAME_SIZE) {
+                Ok(Some(frame)) => TransportEvent::Frame(frame),
+                Ok(None) => TransportEvent::Closed,
+                Err(error) => TransportEvent::Error(error.to_string()),
+            };
+            let finished = !matches!(event, TransportEvent::Frame(_));
+            if sender.send(event).is_err() {
+                break;
+            }
+            wake_up();
+            if finished {
+                break;
+            }
+        });
+
+        Self {
+            writer: Box::new(writer),
+            events,
+            shutdown: None,
+        }
+    }
+
+    /// Called when the transport is dropped, to close a stream that `new` was given halves of
+    pub fn with_shutdown(mut self, shutdown: impl FnOnce() + Send + 'static) -> Self {
+        self.shutdown = Some(Box::new(shutdown));
+        self
+    }
+}
+
+impl Drop for StreamTransport {
+    fn drop(&mut self) {
+        if let Some(shutdown) = self.shutdown.take() {
+            shutdown();
+        }
+    }
+}
+
+impl Transport for StreamTransport {
+    fn send(&mut self, frame: Vec<u8>) {
+        if let Err(error) = write_frame(&mut self.writer, &frame) {
+            log::error!("Failed to send frame: {error}");
+        }
+    }
+
+    fn try_recv(&mut self) -> Option<TransportEvent> {
+        self.events.try_recv().ok()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{read_frame, write_frame, StreamTransport};
+    use crate::{Transport, TransportEvent};
+    use std::{io::Cursor, net::TcpListener, time::Duration};
+
+    #[test]
+    fn test_framing() {
+        let mut bytes = Vec::new();
+        write_frame(&mut bytes, b"hello").unwrap();
+        write_frame(&mut bytes, b"").unwrap();
+        assert_eq!(&bytes[..4], &[0, 0, 0, 5]);
+
+        let mut reader = Cursor::new(bytes);
+        assert_eq!(
+            read_frame(&mut reader, 16).unwrap(),
+            Some(b"hello".to_vec())
+        );
+        assert_eq!(read_frame(&mut reader, 16).unwrap(), Some(Vec::new()));
+        assert_eq!(read_frame(&mut reader, 16).unwrap(), None);
+    }
+
+    #[test]
+    fn test_frame_limit() {
+        let mut bytes = Vec::new();
+        write_frame(&mut bytes, &[0; 32]).unwrap();
+        assert!(read_frame(&mut Cursor::new(bytes), 16).is_err());
+
+        // A stream that ends before the length it promised
+        let bytes = [0, 0, 0, 16, 1, 2, 3];
+        assert!(read_frame(&mut Cursor::new(bytes), 16).is_err());
+    }
+
+    #[test]
+    fn test_tcp_transport() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let mut client =
+            StreamTransport::connect_tcp(listener.local_addr().unwrap(), || {}).unwrap();
+        let (mut server, _) = listener.accept().unwrap();
+
+        client.send(b"ping".to_vec());
+        assert_eq!(read_frame(&mut server, 16).unwrap(), Some(b"ping".to_vec()));
+        write_frame(&mut server, b"pong").unwrap();
+        drop(server);
+
+        let mut events = Vec::new();
+        while !matches!(events.last(), Some(TransportEvent::Closed)) {
+            match client.try_recv() {
+                Some(event) => events.push(event),
+                None => std::thread::sleep(Duration::from_millis(1)),
+            }
+        }
+        assert_eq!(
+            events,
+            [
+                TransportEvent::Opened,
+                TransportEvent::Frame(b"pong".to_vec()),
+                TransportEvent::Closed
+            ]
+        );
+    }
+}
@@ -0,0 +1,490 @@
+use crate::{Error, Id, PayloadBytes};
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, VecDeque};
+use uuid::Uuid;
+
+#[cfg(feature = "gui")]
+use enum2egui::{egui, GuiInspect};
+
+/// Serialized messages larger than this are split into chunks
+pub const DEFAULT_CHUNK_SIZE: usize = 256 * 1024;
+
+/// How many upload chunks may be sent before the server acknowledges them
+pub const UPLOAD_WINDOW: u32 = 4;
+
+/// How many partially received transfers are kept before the oldest is discarded
+pub const MAX_INCOMING_TRANSFERS: usize = 64;
+
+/// One slice of a serialized `Message` or `Response` that was too large for a single frame
+#[derive(Default, Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
+pub struct Chunk {
+    pub transfer_id: Id,
+    pub sequence: u32,
+    pub total_chunks: u32,
+    pub total_size: u64,
+
+    /// The crc32 of the complete transfer, repeated in every chunk
+    pub checksum: u32,
+
+    pub bytes: PayloadBytes,
+}
+
+#[cfg(feature = "gui")]
+impl GuiInspect for Chunk {
+    fn ui(&self, ui: &mut egui::Ui) {
+        ui.label(format!(
+            "Chunk {}/{} of transfer '{}'",
+            self.sequence + 1,
+            self.total_chunks,
+            self.transfer_id
+        ));
+    }
+
+    fn ui_mut(&mut self, ui: &mut egui::Ui) {
+        self.ui(ui);
+    }
+}
+
+#[derive(Default, Debug, Copy, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
+pub enum TransferDirection {
+    #[default]
+    Upload,
+    Download,
+}
+
+/// Published on the broker as chunks are sent and received
+#[derive(Default, Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
+pub struct TransferProgress {
+    pub transfer_id: Id,
+    pub direction: TransferDirection,
+    pub transferred: u64,
+    pub total: u64,
+}
+
+impl TransferProgress {
+    pub fn fraction(&self) -> f32 {
+        if self.total == 0 {
+            return 1.0;
+        }
+        self.transferred as f32 / self.total as f32
+    }
+
+    pub fn is_complete(&self) -> bool {
+        self.transferred >= self.total
+    }
+}
+
+#[cfg(feature = "gui")]
+impl GuiInspect for TransferProgress {
+    fn ui(&self, ui: &mut egui::Ui) {
+        ui.add(egui::ProgressBar::new(self.fraction()).show_percentage());
+    }
+
+    fn ui_mut(&mut self, ui: &mut egui::Ui) {
+        self.ui(ui);
+    }
+}
+
+/// A payload being sent one chunk at a time
+pub struct OutgoingTransfer {
+    transfer_id: Id,
+    bytes: PayloadBytes,
+    chunk_size: usize,
+    checksum: u32,
+    total_chunks: u32,
+
+    /// The next chunk that will be sent
+    next_sequence: u32,
+
+    /// How many chunks the receiver has confirmed, in order
+    acknowledged: u32,
+}
+
+impl OutgoingTransfer {
+    pub fn new(bytes: PayloadBytes, chunk_size: usize) -> Self {
+        let chunk_size = chunk_size.max(1);
+        let total_chunks = ((bytes.len() + chunk_size - 1) / chunk_size).max(1) as u32;
+        Self {
+            transfer_id: Uuid::new_v4().to_string(),
+            checksum: crc32fast::hash(&bytes),
+            bytes,
+            chunk_size,
+            total_chunks,
+            next_sequence: 0,
+            acknowledged: 0,
+        }
+    }
+
+    pub fn transfer_id(&self) -> &Id {
+        &self.transfer_id
+    }
+
+    /// Returns the next chunk to send, if any remain
+    pub fn next_chunk(&mut self) -> Option<Chunk> {
+        if self.next_sequence >= self.total_chunks {
+            return None;
+        }
+        let chunk = self.chunk(self.next_sequence);
+        self.next_sequence += 1;
+        Some(chunk)
+    }
+
+    /// Returns the chunks that may be sent without overrunning the upload window
+    pub fn next_window(&mut self) -> Vec<Chunk> {
+        let mut chunks = Vec::new();
+        while self.next_sequence < self.acknowledged + UPLOAD_WINDOW {
+            match self.next_chunk() {
+                Some(chunk) => chunks.push(chunk),
+                None => break,
+            }
+        }
+        chunks
+    }
+
+    /// Returns every remaining chunk, for transfers that are not flow controlled
+    pub fn remaining_chunks(&mut self) -> Vec<Chunk> {
+        std::iter::from_fn(|| self.next_chunk()).collect()
+    }
+
+    pub fn acknowledge(&mut self, received: u32) {
+        self.acknowledged = received.min(self.total_chunks);
+    }
+
+    /// Rewinds to the first chunk the receiver is missing, so a transfer can resume
+    pub fn resume_from(&mut self, received: u32) {
+        self.acknowledge(received);
+        self.next_sequence = self.acknowledged;
+    }
+
+    /// Starts over from the first chunk as a new transfer, for receivers that can't resume it
+    pub fn restart(&mut self) {
+        self.transfer_id = Uuid::new_v4().to_string();
+        self.next_sequence = 0;
+        self.acknowledged = 0;
+    }
+
+    pub fn is_complete(&self) -> bool {
+        self.acknowledged >= self.total_chunks
+    }
+
+    pub fn progress(&self) -> TransferProgress {
+        TransferProgress {
+            transfer_id: self.transfer_id.to_string(),
+            direction: TransferDirection::Upload,
+            transferred: self.byte_offset(self.acknowledged) as u64,
+            total: self.bytes.len() as u64,
+        }
+    }
+
+    fn chunk(&self, sequence: u32) -> Chunk {
+        let start = self.byte_offset(sequence);
+        let end = self.byte_offset(sequence + 1);
+        Chunk {
+            transfer_id: self.transfer_id.to_string(),
+            sequence,
+            total_chunks: self.total_chunks,
+            total_size: self.bytes.len() as u64,
+            checksum: self.checksum,
+            bytes: self.bytes[start..end].to_vec(),
+        }
+    }
+
+    fn byte_offset(&self, sequence: u32) -> usize {
+        (sequence as usize * self.chunk_size).min(self.bytes.len())
+    }
+}
+
+/// A payload being reassembled from chunks
+pub struct IncomingTransfer {
+    transfer_id: Id,
+    total_chunks: u32,
+    total_size: u64,
+    checksum: u32,
+    bytes: PayloadBytes,
+    next_sequence: u32,
+}
+
+impl IncomingTransfer {
+    fn new(chunk: &Chunk) -> Self {
+        Self {
+            transfer_id: chunk.transfer_id.to_string(),
+            total_chunks: chunk.total_chunks,
+            total_size: chunk.total_size,
+            checksum: chunk.checksum,
+            bytes: Vec::new(),
+            next_sequence: 0,
+        }
+    }
+
+    /// Appends a chunk. Chunks that were already received are ignored,
+    /// which happens when a sender resumes from its last acknowledgement.
+    fn receive(&mut self, chunk: Chunk) -> Result<(), Error> {
+        if chunk.sequence < self.next_sequence {
+            return Ok(());
+        }
+
+        let expected_bytes = self.bytes.len() as u64 + chunk.bytes.len() as u64;
+        if chunk.sequence != self.next_sequence
+            || chunk.total_chunks != self.total_chunks
+            || chunk.checksum != self.checksum
+            || expected_bytes > self.total_size
+        {
+            return Err(Error::TransferSequence {
+                transfer_id: self.transfer_id.to_string(),
+                expected: self.next_sequence,
+                received: chunk.sequence,
+            });
+        }
+
+        self.bytes.extend_from_slice(&chunk.bytes);
+        self.next_sequence += 1;
+        Ok(())
+    }
+
+    pub fn received(&self) -> u32 {
+        self.next_sequence
+    }
+
+    pub fn is_complete(&self) -> bool {
+        self.next_sequence >= self.total_chunks
+    }
+
+    pub fn progress(&self, direction: TransferDirection) -> TransferProgress {
+        TransferProgress {
+            transfer_id: self.transfer_id.to_string(),
+            direction,
+            transferred: self.bytes.len() as u64,
+            total: self.total_size,
+        }
+    }
+
+    fn finish(self) -> Result<PayloadBytes, Error> {
+        if crc32fast::hash(&self.bytes) != self.checksum {
+            return Err(Error::TransferChecksum {
+                transfer_id: self.transfer_id,
+            });
+        }
+        Ok(self.bytes)
+    }
+}
+
+/// What happened when a chunk was handed to `Transfers::receive`
+pub struct Received {
+    pub progress: TransferProgress,
+
+    /// How many chunks of the transfer have now arrived in order
+    pub received: u32,
+
+    /// The reassembled payload, once the final chunk arrives
+    pub payload: Option<PayloadBytes>,
+}
+
+/// Every transfer that is currently being received, keyed by transfer id
+pub struct Transfers {
+    direction: TransferDirection,
+    transfers: HashMap<Id, IncomingTransfer>,
+    order: VecDeque<Id>,
+    max_size: u64,
+}
+
+impl Transfers {
+    pub fn new(direction: TransferDirection) -> Self {
+        Self {
+            direction,
+            transfers: HashMap::new(),
+            order: VecDeque::new(),
+            max_size: u64::MAX,
+        }
+    }
+
+    /// Refuses transfers whose reassembled payload would be larger than `max_size` bytes
+    pub fn with_max_size(mut self, max_size: u64) -> Self {
+        self.max_size = max_size;
+        self
+    }
+
+    pub fn receive(&mut self, chunk: Chunk) -> Result<Received, Error> {
+        let transfer_id = chunk.transfer_id.to_string();
+        if !self.transfers.contains_key(&transfer_id) {
+            if chunk.total_size > self.max_size {
+                return Err(Error::PayloadTooLarge {
+                    size: chunk.total_size,
+                    limit: self.max_size,
+                });
+            }
+            self.start(&chunk);
+        }
+
+        let transfer =
+            self.transfers
+                .get_mut(&transfer_id)
+                .ok_or_else(|| Error::UnknownTransfer {
+                    transfer_id: transfer_id.to_string(),
+                })?;
+
+        if let Err(error) = transfer.receive(chunk) {
+            self.remove(&transfer_id);
+            return Err(error);
+        }
+
+        let progress = transfer.progress(self.direction);
+        let received = transfer.received();
+        if !transfer.is_complete() {
+            return Ok(Received {
+                progress,
+                received,
+                payload: None,
+            });
+        }
+
+        let payload = self
+            .remove(&transfer_id)
+            .map(IncomingTransfer::finish)
+            .transpose()?;
+        Ok(Received {
+            progress,
+            received,
+            payload,
+        })
+    }
+
+    /// How many chunks of a transfer have arrived, used to resume an interrupted upload
+    pub fn received(&self, transfer_id: &str) -> Option<u32> {
+        self.transfers
+            .get(transfer_id)
+            .map(IncomingTransfer::received)
+    }
+
+    /// Drops a partially received transfer, returning whether there was one
+    pub fn discard(&mut self, transfer_id: &str) -> bool {
+        self.remove(transfer_id).is_some()
+    }
+
+    pub fn len(&self) -> usize {
+        self.transfers.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.transfers.is_empty()
+    }
+
+    fn start(&mut self, chunk: &Chunk) {
+        if self.order.len() >= MAX_INCOMING_TRANSFERS {
+            if let Some(oldest) = self.order.pop_front() {
+                log::warn!("Discarding incomplete transfer '{oldest}'");
+                self.transfers.remove(&oldest);
+            }
+        }
+        self.transfers
+            .insert(chunk.transfer_id.to_string(), IncomingTransfer::new(chunk));
+        self.order.push_back(chunk.transfer_id.to_string());
+    }
+
+    fn remove(&mut self, transfer_id: &str) -> Option<IncomingTransfer> {
+        self.order.retain(|id| id != transfer_id);
+        self.transfers.remove(transfer_id)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{OutgoingTransfer, TransferDirection, Transfers};
+    use crate::Error;
+
+    #[test]
+    fn test_round_trip() {
+        let bytes = (0..10_000)
+            .map(|value| (value % 251) as u8)
+            .collect::<Vec<_>>();
+        let mut outgoing = OutgoingTransfer::new(bytes.clone(), 1_024);
+        let mut incoming = Transfers::new(TransferDirection::Upload);
+
+        let mut payload = None;
+        for chunk in outgoing.remaining_chunks() {
+            payload = incoming.receive(chunk).unwrap().payload;
+        }
+
+        assert_eq!(payload, Some(bytes));
+        assert!(incoming.is_empty());
+    }
+
+    #[test]
+    fn test_empty_payload() {
+        let mut outgoing = OutgoingTransfer::new(Vec::new(), 1_024);
+        let mut incoming = Transfers::new(TransferDirection::Download);
+        let chunks = outgoing.remaining_chunks();
+        assert_eq!(chunks.len(), 1);
+        let received = incoming.receive(chunks[0].clone()).unwrap();
+        assert_eq!(received.payload, Some(Vec::new()));
+    }
+
+    #[test]
+    fn test_upload_window() {
+        let mut outgoing = OutgoingTransfer::new(vec![0; 10_000], 1_000);
+        assert_eq!(outgoing.next_window().len(), super::UPLOAD_WINDOW as usize);
+        assert!(outgoing.next_window().is_empty());
+        outgoing.acknowledge(2);
+        assert_eq!(outgoing.next_window().len(), 2);
+        assert_eq!(outgoing.progress().transferred, 2_000);
+    }
+
+    #[test]
+    fn test_resume() {
+        let bytes = vec![7; 5_000];
+        let mut outgoing = OutgoingTransfer::new(bytes.clone(), 1_000);
+        let mut incoming = Transfers::new(TransferDirection::Upload);
+
+        // The connection drops after the first two chunks arrive
+        let chunks = outgoing.next_window();
+        for chunk in chunks.into_iter().take(2) {
+            incoming.receive(chunk).unwrap();
+        }
+
+        let received = incoming.received(outgoing.transfer_id()).unwrap();
+        assert_eq!(received, 2);
+        outgoing.resume_from(received);
+
+        let mut payload = None;
+        for chunk in outgoing.remaining_chunks() {
+            payload = incoming.receive(chunk).unwrap().payload;
+        }
+        assert_eq!(payload, Some(bytes));
+    }
+
+    #[test]
+    fn test_checksum_mismatch() {
+        let mut outgoing = OutgoingTransfer::new(vec![1; 2_000], 1_000);
+        let mut incoming = Transfers::new(TransferDirection::Upload);
+        let mut chunks = outgoing.remaining_chunks();
+        chunks[1].bytes[0] = 2;
+
+        incoming.receive(chunks[0].clone()).unwrap();
+        let result = incoming.receive(chunks[1].clone());
+        assert!(matches!(result, Err(Error::TransferChecksum { .. })));
+    }
+
+    #[test]
+    fn test_max_size() {
+        let mut outgoing = OutgoingTransfer::new(vec![1; 3_000], 1_000);
+        let mut incoming = Transfers::new(TransferDirection::Upload).with_max_size(2_000);
+        let chunks = outgoing.remaining_chunks();
+
+        let result = incoming.receive(chunks[0].clone());
+        assert!(matches!(result, Err(Error::PayloadTooLarge { .. })));
+        assert!(incoming.is_empty());
+    }
+
+    #[test]
+    fn test_out_of_order_chunk() {
+        let mut outgoing = OutgoingTransfer::new(vec![1; 3_000], 1_000);
+        let mut incoming = Transfers::new(TransferDirection::Upload);
+        let chunks = outgoing.remaining_chunks();
+
+        let result = incoming.receive(chunks[2].clone());
+        assert!(matches!(result, Err(Error::TransferSequence { .. })));
+        assert!(incoming.is_empty());
+    }
+}
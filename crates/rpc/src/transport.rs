@@ -0,0 +1,59 @@
+use ewebsock::{WsEvent, WsMessage, WsReceiver, WsSender};
+
+/// What happened on a transport since it was last polled
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum TransportEvent {
+    Opened,
+
+    /// A frame encoded with `Codec`
+    Frame(Vec<u8>),
+
+    /// Traffic without a frame, like a websocket ping, which still shows the peer is there
+    KeepAlive,
+
+    Closed,
+    Error(String),
+}
+
+/// Carries encoded frames between a client and a server
+pub trait Transport {
+    fn send(&mut self, frame: Vec<u8>);
+
+    /// Returns the next event without blocking
+    fn try_recv(&mut self) -> Option<TransportEvent>;
+}
+
+/// Sends frames as binary websocket messages, in browsers and natively
+pub struct WebsocketTransport {
+    sender: WsSender,
+    receiver: WsReceiver,
+}
+
+impl WebsocketTransport {
+    pub fn new(sender: WsSender, receiver: WsReceiver) -> Self {
+        Self { sender, receiver }
+    }
+
+    /// Connects to a `ws://` or `wss://` url, calling `wake_up` whenever an event arrives
+    pub fn connect(url: &str, wake_up: impl Fn() + Send + Sync + 'static) -> Result<Self, String> {
+        let (sender, receiver) = ewebsock::connect_with_wakeup(url, wake_up)?;
+        Ok(Self::new(sender, receiver))
+    }
+}
+
+impl Transport for WebsocketTransport {
+    fn send(&mut self, frame: Vec<u8>) {
+        self.sender.send(WsMessage::Binary(frame));
+    }
+
+    fn try_recv(&mut self) -> Option<TransportEvent> {
+        let event = match self.receiver.try_recv()? {
+            WsEvent::Opened => TransportEvent::Opened,
+            WsEvent::Message(WsMessage::Binary(frame)) => TransportEvent::Frame(frame),
+            WsEvent::Message(_) => TransportEvent::KeepAlive,
+            WsEvent::Error(error) => TransportEvent::Error(error),
+            WsEvent::Closed => TransportEvent::Closed,
+        };
+        Some(event)
+    }
+}
@@ -0,0 +1,189 @@
+use crate::{Chunk, Error, Id, Received, TransferDirection, Transfers};
+use std::{
+    collections::HashMap,
+    time::{Duration, Instant},
+};
+use uuid::Uuid;
+
+/// How long an unfinished upload is kept after its last chunk, for its client to resume it
+pub const DEFAULT_UPLOAD_TTL: Duration = Duration::from_secs(10 * 60);
+
+/// The uploads a backend is receiving. They outlive the connection that started them,
+/// and another connection takes one over by resuming it with the token its acknowledgements carry.
+pub struct Uploads {
+    transfers: Transfers,
+    uploads: HashMap<Id, Upload>,
+    ttl: Duration,
+}
+
+// Who may send the next chunks of an upload
+struct Upload {
+    token: Id,
+
+    // The connection the chunks come from, until another one resumes the upload
+    client_id: Id,
+
+    // When the upload last received a chunk or was resumed
+    touched: Instant,
+}
+
+impl Uploads {
+    pub fn new(max_size: u64, ttl: Duration) -> Self {
+        Self {
+            transfers: Transfers::new(TransferDirection::Upload).with_max_size(max_size),
+            uploads: HashMap::new(),
+            ttl,
+        }
+    }
+
+    /// Adds a chunk sent by `client_id`, returning the token that resumes its upload
+    pub fn receive(
+        &mut self,
+        client_id: &str,
+        chunk: Chunk,
+        now: Instant,
+    ) -> Result<(Received, Id), Error> {
+        self.expire(now);
+        let transfer_id = chunk.transfer_id.to_string();
+        let token = match self.uploads.get_mut(&transfer_id) {
+            Some(upload) if upload.client_id == client_id => {
+                upload.touched = now;
+                upload.token.to_string()
+            }
+            // Another connection has to resume the upload before sending to it
+            Some(_) => return Err(Error::UnknownTransfer { transfer_id }),
+            None => {
+                let token = Uuid::new_v4().to_string();
+                let upload = Upload {
+                    token: token.to_string(),
+                    client_id: client_id.to_string(),
+                    touched: now,
+                };
+                self.uploads.insert(transfer_id, upload);
+                token
+            }
+        };
+
+        let received = self.transfers.receive(chunk);
+
+        // Finished, failed and evicted transfers can't be resumed
+        let transfers = &self.transfers;
+        self.uploads
+            .retain(|transfer_id, _| transfers.received(transfer_id).is_some());
+        received.map(|received| (received, token))
+    }
+
+    /// Hands an upload over to `client_id`, returning how many of its chunks have arrived
+    pub fn resume(
+        &mut self,
+        client_id: &str,
+        transfer_id: &str,
+        token: &str,
+        now: Instant,
+    ) -> Result<u32, Error> {
+        self.expire(now);
+        let unknown = || Error::UnknownTransfer {
+            transfer_id: transfer_id.to_string(),
+        };
+        let upload = self
+            .uploads
+            .get_mut(transfer_id)
+            .filter(|upload| upload.token == token)
+            .ok_or_else(unknown)?;
+        upload.client_id = client_id.to_string();
+        upload.touched = now;
+        self.transfers.received(transfer_id).ok_or_else(unknown)
+    }
+
+    pub fn len(&self) -> usize {
+        self.uploads.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.uploads.is_empty()
+    }
+
+    fn expire(&mut self, now: Instant) {
+        let ttl = self.ttl;
+        let expired = self
+            .uploads
+            .iter()
+            .filter(|(_, upload)| now.saturating_duration_since(upload.touched) >= ttl)
+            .map(|(transfer_id, _)| transfer_id.to_string())
+            .collect::<Vec<_>>();
+        for transfer_id in expired {
+            log::info!("Discarding upload '{transfer_id}', unfinished for {ttl:?}");
+            self.uploads.remove(&transfer_id);
+            self.transfers.discard(&transfer_id);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Uploads;
+    use crate::{Error, OutgoingTransfer};
+    use std::time::{Duration, Instant};
+
+    #[test]
+    fn test_resume() {
+        let mut uploads = Uploads::new(u64::MAX, Duration::from_secs(60));
+        let mut transfer = OutgoingTransfer::new(vec![7; 30], 10);
+        let transfer_id = transfer.transfer_id().to_string();
+        let now = Instant::now();
+
+        let (received, token) = uploads
+            .receive("first", transfer.next_chunk().unwrap(), now)
+            .unwrap();
+        assert_eq!(received.received, 1);
+
+        // Only the connection holding the upload may add to it
+        let chunk = transfer.next_chunk().unwrap();
+        let unknown = Error::UnknownTransfer {
+            transfer_id: transfer_id.to_string(),
+        };
+        assert_eq!(
+            uploads.receive("second", chunk.clone(), now).err(),
+            Some(unknown.clone())
+        );
+        assert_eq!(
+            uploads.resume("second", &transfer_id, "guess", now),
+            Err(unknown.clone())
+        );
+        assert_eq!(uploads.resume("second", &transfer_id, &token, now), Ok(1));
+        assert_eq!(
+            uploads.receive("first", chunk.clone(), now).err(),
+            Some(unknown)
+        );
+
+        let (received, _) = uploads.receive("second", chunk, now).unwrap();
+        assert_eq!(received.received, 2);
+        let (received, _) = uploads
+            .receive("second", transfer.next_chunk().unwrap(), now)
+            .unwrap();
+        assert_eq!(received.payload, Some(vec![7; 30]));
+        assert!(uploads.is_empty());
+    }
+
+    #[test]
+    fn test_expiry() {
+        let ttl = Duration::from_secs(60);
+        let mut uploads = Uploads::new(u64::MAX, ttl);
+        let mut transfer = OutgoingTransfer::new(vec![7; 30], 10);
+        let transfer_id = transfer.transfer_id().to_string();
+        let now = Instant::now();
+
+        let (_, token) = uploads
+            .receive("first", transfer.next_chunk().unwrap(), now)
+            .unwrap();
+        assert_eq!(
+            uploads.resume("first", &transfer_id, &token, now + ttl / 2),
+            Ok(1)
+        );
+        assert_eq!(
+            uploads.resume("first", &transfer_id, &token, now + ttl * 2),
+            Err(Error::UnknownTransfer { transfer_id })
+        );
+        assert!(uploads.is_empty());
+    }
+}
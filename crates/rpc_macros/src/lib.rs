@@ -0,0 +1,271 @@
+//! Generates the plumbing for typed RPC services.
+//!
+//! ```ignore
+//! #[rpc::rpc_service]
+//! pub trait Greeter {
+//!     fn greet(&mut self, name: String) -> String;
+//! }
+//! ```
+//!
+//! generates, next to the trait:
+//!
+//! - `GreeterRequest`, an enum with a variant holding the arguments of each method
+//! - `GreeterService<T>`, which registers any `T: Greeter` with an `RpcExecutor`
+//! - `GreeterClient`, a stub implemented for every `rpc::ServiceClient`, whose methods
+//!   send the request and return an `rpc::ServiceCall` typed with the method's return type
+//!
+//! A method whose first argument is a `&mut rpc::CallContext<'_>` is handed the session of
+//! the calling client and the call's cancellation token, and its stub leaves that argument out.
+//!
+//! Arguments and return values travel as JSON, so they must be owned and implement
+//! `Serialize` and `Deserialize`. Crates that only reach the rpc crate through a re-export
+//! name it with `#[rpc_service(crate = "widget::rpc")]`.
+
+use proc_macro::TokenStream;
+use proc_macro2::TokenStream as TokenStream2;
+use quote::{format_ident, quote};
+use syn::{
+    parse_macro_input, parse_quote, spanned::Spanned, Error, FnArg, Ident, ItemTrait, LitStr, Pat,
+    Path, ReturnType, TraitItem, TraitItemFn, Type,
+};
+
+#[proc_macro_attribute]
+pub fn rpc_service(attribute: TokenStream, item: TokenStream) -> TokenStream {
+    let mut krate: Path = parse_quote!(::rpc);
+    let parser = syn::meta::parser(|meta| {
+        if meta.path.is_ident("crate") {
+            krate = meta.value()?.parse::<LitStr>()?.parse()?;
+            Ok(())
+        } else {
+            Err(meta.error("Expected `crate = \"path::to::rpc\"`"))
+        }
+    });
+    parse_macro_input!(attribute with parser);
+
+    let service = parse_macro_input!(item as ItemTrait);
+    expand(&service, &krate)
+        .unwrap_or_else(Error::into_compile_error)
+        .into()
+}
+
+struct Method {
+    ident: Ident,
+    variant: Ident,
+
+    // Whether the method takes the `CallContext` before its arguments
+    context: bool,
+    arguments: Vec<(Ident, Type)>,
+    output: Type,
+}
+
+fn expand(service: &ItemTrait, krate: &Path) -> syn::Result<TokenStream2> {
+    if !service.generics.params.is_empty() {
+        return Err(Error::new(
+            service.generics.span(),
+            "RPC services can't be generic",
+        ));
+    }
+
+    let methods = service
+        .items
+        .iter()
+        .filter_map(|item| match item {
+            TraitItem::Fn(method) => Some(parse_method(method)),
+            _ => None,
+        })
+        .collect::<syn::Result<Vec<_>>>()?;
+
+    let visibility = &service.vis;
+    let name = &service.ident;
+    let service_name = name.to_string();
+    let request = format_ident!("{}Request", name);
+    let adapter = format_ident!("{}Service", name);
+    let client = format_ident!("{}Client", name);
+    let serde_crate = format!("{}::__private::serde", quote!(#krate));
+
+    let variants = methods.iter().map(|method| {
+        let variant = &method.variant;
+        let (arguments, types): (Vec<_>, Vec<_>) = method.arguments.iter().cloned().unzip();
+        quote! { #variant { #(#arguments: #types),* } }
+    });
+
+    let dispatch = methods.iter().map(|method| {
+        let Method { ident, variant, .. } = method;
+        let arguments = method.arguments.iter().map(|(argument, _)| argument);
+        let call_arguments = arguments.clone();
+        let context = method.context.then(|| quote!(context,));
+        quote! {
+            #request::#variant { #(#arguments),* } => {
+                #krate::__private::serde_json::to_string(
+                    &self.0.#ident(#context #(#call_arguments),*)
+                )
+            }
+        }
+    });
+    let context = if methods.iter().any(|method| method.context) {
+        quote!(context)
+    } else {
+        quote!(_context)
+    };
+
+    let stubs = methods.iter().map(|method| {
+        let Method {
+            ident,
+            variant,
+            output,
+            ..
+        } = method;
+        let (arguments, types): (Vec<_>, Vec<_>) = method.arguments.iter().cloned().unzip();
+        quote! {
+            fn #ident(
+                &mut self,
+                context: &mut Self::Context,
+                #(#arguments: #types),*
+            ) -> ::core::result::Result<#krate::ServiceCall<#output>, #krate::Error> {
+                let request = #request::#variant { #(#arguments),* };
+                #krate::ServiceCall::send(self, context, #service_name, &request)
+            }
+        }
+    });
+
+    let request_doc = format!("The arguments of a call to each method of `{name}`");
+    let adapter_doc = format!("Registers a `{name}` implementation with an `RpcExecutor`");
+    let client_doc = format!("Calls the methods of a remote `{name}`");
+
+    Ok(quote! {
+        #service
+
+        #[doc = #request_doc]
+        #[derive(
+            ::core::fmt::Debug,
+            ::core::clone::Clone,
+            #krate::__private::serde::Serialize,
+            #krate::__private::serde::Deserialize,
+        )]
+        #[serde(crate = #serde_crate)]
+        #visibility enum #request {
+            #(#variants),*
+        }
+
+        #[doc = #adapter_doc]
+        #visibility struct #adapter<T>(pub T);
+
+        #[cfg(not(target_arch = "wasm32"))]
+        impl<T: #name + ::core::marker::Send + ::core::marker::Sync> #krate::RpcService for #adapter<T> {
+            fn name(&self) -> &'static str {
+                #service_name
+            }
+
+            fn call(
+                &mut self,
+                #context: &mut #krate::CallContext<'_>,
+                payload: &str,
+            ) -> ::core::result::Result<#krate::PayloadJson, String> {
+                let request = #krate::__private::serde_json::from_str::<#request>(payload)
+                    .map_err(|error| error.to_string())?;
+                let response = match request {
+                    #(#dispatch)*
+                };
+                response.map_err(|error| error.to_string())
+            }
+        }
+
+        #[doc = #client_doc]
+        #visibility trait #client: #krate::ServiceClient {
+            #(#stubs)*
+        }
+
+        impl<C: #krate::ServiceClient + ?Sized> #client for C {}
+    })
+}
+
+fn parse_method(method: &TraitItemFn) -> syn::Result<Method> {
+    let signature = &method.sig;
+    if signature.asyncness.is_some() {
+        return Err(Error::new(
+            signature.span(),
+            "RPC service methods can't be async",
+        ));
+    }
+    if !signature.generics.params.is_empty() {
+        return Err(Error::new(
+            signature.generics.span(),
+            "RPC service methods can't be generic",
+        ));
+    }
+
+    let mut inputs = signature.inputs.iter();
+    match inputs.next() {
+        Some(FnArg::Receiver(receiver)) if receiver.reference.is_some() => {}
+        _ => {
+            return Err(Error::new(
+                signature.span(),
+                "RPC service methods must take `&self` or `&mut self`",
+            ))
+        }
+    }
+
+    let mut inputs = inputs.peekable();
+    let context = inputs.peek().is_some_and(|input| is_context(input));
+    if context {
+        inputs.next();
+    }
+
+    let arguments = inputs
+        .map(|input| match input {
+            FnArg::Typed(argument) => match &*argument.pat {
+                Pat::Ident(pattern) => Ok((pattern.ident.clone(), (*argument.ty).clone())),
+                pattern => Err(Error::new(
+                    pattern.span(),
+                    "RPC service arguments must be plain identifiers",
+                )),
+            },
+            FnArg::Receiver(receiver) => Err(Error::new(receiver.span(), "Unexpected receiver")),
+        })
+        .collect::<syn::Result<Vec<_>>>()?;
+
+    let output = match &signature.output {
+        ReturnType::Default => parse_quote!(()),
+        ReturnType::Type(_, output) => (**output).clone(),
+    };
+
+    Ok(Method {
+        ident: signature.ident.clone(),
+        variant: format_ident!("{}", pascal_case(&signature.ident.to_string())),
+        context,
+        arguments,
+        output,
+    })
+}
+
+// Matches `&mut CallContext` however its path is written
+fn is_context(input: &FnArg) -> bool {
+    let FnArg::Typed(argument) = input else {
+        return false;
+    };
+    let Type::Reference(reference) = &*argument.ty else {
+        return false;
+    };
+    let Type::Path(path) = &*reference.elem else {
+        return false;
+    };
+    reference.mutability.is_some()
+        && path
+            .path
+            .segments
+            .last()
+            .is_some_and(|segment| segment.ident == "CallContext")
+}
+
+fn pascal_case(name: &str) -> String {
+    name.split('_')
+        .filter(|word| !word.is_empty())
+        .map(|word| {
+            let mut characters = word.chars();
+            characters
+                .next()
+                .map(|first| first.to_uppercase().chain(characters).collect::<String>())
+                .unwrap_or_default()
+        })
+        .collect()
+}
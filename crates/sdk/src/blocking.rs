@@ -0,0 +1,162 @@
+//! A client whose calls block the current thread until the backend answers
+
+use crate::{
+    driver::{Handle, Reply},
+    Event, Options,
+};
+use rpc::{Command, Error, Id, Message, PayloadJson, RpcMessage, ServiceCall, ServiceClient};
+use serde::de::DeserializeOwned;
+use std::{collections::HashMap, sync::mpsc};
+use uuid::Uuid;
+
+type Outcome = mpsc::Receiver<Result<RpcMessage, Error>>;
+
+pub struct Client {
+    handle: Handle,
+    client_id: Id,
+
+    /// Service calls sent through the generated client stubs, waiting for `wait`
+    services: HashMap<Id, Outcome>,
+}
+
+impl Client {
+    /// Connects and waits until the backend has assigned a client id,
+    /// and accepted the credential if one was given
+    pub fn connect(options: Options) -> Result<Self, Error> {
+        let (sender, receiver) = mpsc::channel();
+        let handle = Handle::connect(options, reply(sender));
+        let client_id = receiver.recv().unwrap_or(Err(Error::Connection))?;
+        Ok(Self {
+            handle,
+            client_id,
+            services: HashMap::new(),
+        })
+    }
+
+    /// The id the backend assigned to this connection
+    pub fn client_id(&self) -> &Id {
+        &self.client_id
+    }
+
+    /// Sends a command and waits for its result
+    pub fn call(&self, command: Command) -> Result<RpcMessage, Error> {
+        self.call_message(Message::new(Uuid::new_v4().to_string(), command))
+    }
+
+    /// Sends a message, such as one with an idempotency key, and waits for its result.
+    /// The message id must not be used by another call that is still waiting.
+    pub fn call_message(&self, message: Message) -> Result<RpcMessage, Error> {
+        self.send(message).recv().unwrap_or(Err(Error::Connection))
+    }
+
+    /// Waits for the result of a call made through a client stub generated by `rpc_service`
+    pub fn wait<R: DeserializeOwned>(&mut self, call: &ServiceCall<R>) -> Result<R, Error> {
+        let outcome = self
+            .services
+            .remove(call.call_id())
+            .ok_or(Error::UnrecognizedMessage)?;
+        match outcome.recv().unwrap_or(Err(Error::Connection))? {
+            RpcMessage::Service { payload, .. } => call.decode(&payload),
+            _ => Err(Error::UnrecognizedMessage),
+        }
+    }
+
+    /// Receives every event the backend pushes from now on
+    pub fn subscribe(&self) -> mpsc::Receiver<Event> {
+        let (sender, events) = mpsc::channel();
+        self.handle
+            .subscribe(Box::new(move |event| sender.send(event.clone()).is_ok()));
+        events
+    }
+
+    fn send(&self, message: Message) -> Outcome {
+        let (sender, outcome) = mpsc::channel();
+        self.handle.call(message, reply(sender));
+        outcome
+    }
+}
+
+impl ServiceClient for Client {
+    type Context = ();
+
+    fn call_service(&mut self, _context: &mut (), service: &str, payload: PayloadJson) -> Id {
+        let call_id = Uuid::new_v4().to_string();
+        let command = Command::Service {
+            service: service.to_string(),
+            call_id: call_id.to_string(),
+            payload,
+        };
+        let outcome = self.send(Message::new(call_id.to_string(), command));
+        self.services.insert(call_id.to_string(), outcome);
+        call_id
+    }
+}
+
+fn reply<T: Send + 'static>(sender: mpsc::Sender<Result<T, Error>>) -> Reply<T> {
+    Box::new(move |result| {
+        let _ = sender.send(result);
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Client;
+    use crate::{Endpoint, Event, Options};
+    use mock_server::{MockServer, Rule};
+    use rpc::{rpc_service, Command, Error, RpcExecutor, RpcMessage};
+    use std::time::Duration;
+
+    #[rpc_service]
+    trait Greeter {
+        fn greet(&self, name: String) -> String;
+    }
+
+    struct English;
+
+    impl Greeter for English {
+        fn greet(&self, name: String) -> String {
+            format!("Hello, {name}!")
+        }
+    }
+
+    fn connect(server: &MockServer) -> Client {
+        Client::connect(Options::new(Endpoint::Websocket(server.url()))).unwrap()
+    }
+
+    #[test]
+    fn test_call() {
+        let server = MockServer::start().unwrap();
+        let client = connect(&server);
+        assert!(!client.client_id().is_empty());
+        assert_eq!(client.call(Command::Example), Ok(RpcMessage::Empty));
+        assert_eq!(client.call(Command::Schema), Err(Error::SchemaUnavailable));
+    }
+
+    #[test]
+    fn test_service() {
+        let executor = RpcExecutor::default().with_service(GreeterService(English));
+        let server = MockServer::start_with(executor).unwrap();
+        let mut client = connect(&server);
+        let call = client.greet(&mut (), "backend".to_string()).unwrap();
+        assert_eq!(client.wait(&call), Ok("Hello, backend!".to_string()));
+    }
+
+    #[test]
+    fn test_disconnect() {
+        let server = MockServer::start().unwrap();
+        server.expect(
+            Rule::command(Command::Example)
+                .respond(RpcMessage::Empty)
+                .times(1),
+        );
+        server.expect(Rule::command(Command::Example).disconnect());
+        let client = connect(&server);
+        let events = client.subscribe();
+        assert_eq!(client.call(Command::Example), Ok(RpcMessage::Empty));
+        assert_eq!(client.call(Command::Example), Err(Error::Connection));
+        assert_eq!(
+            events.recv_timeout(Duration::from_secs(5)),
+            Ok(Event::Disconnected)
+        );
+    }
+}
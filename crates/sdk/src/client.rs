@@ -0,0 +1,133 @@
+use crate::{
+    driver::{Handle, Reply},
+    Event, Options,
+};
+use rpc::{Command, Error, Id, Message, PayloadJson, RpcMessage, ServiceCall, ServiceClient};
+use serde::de::DeserializeOwned;
+use std::collections::HashMap;
+use tokio::sync::{mpsc, oneshot};
+use uuid::Uuid;
+
+type Outcome = oneshot::Receiver<Result<RpcMessage, Error>>;
+
+/// A client whose calls are awaited. The connection runs on its own thread,
+/// so any tokio runtime works, including a current thread one.
+pub struct Client {
+    handle: Handle,
+    client_id: Id,
+
+    /// Service calls sent through the generated client stubs, waiting for `wait`
+    services: HashMap<Id, Outcome>,
+}
+
+impl Client {
+    /// Connects and waits until the backend has assigned a client id,
+    /// and accepted the credential if one was given
+    pub async fn connect(options: Options) -> Result<Self, Error> {
+        let (sender, receiver) = oneshot::channel();
+        let handle = Handle::connect(options, reply(sender));
+        let client_id = receiver.await.unwrap_or(Err(Error::Connection))?;
+        Ok(Self {
+            handle,
+            client_id,
+            services: HashMap::new(),
+        })
+    }
+
+    /// The id the backend assigned to this connection
+    pub fn client_id(&self) -> &Id {
+        &self.client_id
+    }
+
+    /// Sends a command and waits for its result
+    pub async fn call(&self, command: Command) -> Result<RpcMessage, Error> {
+        self.call_message(Message::new(Uuid::new_v4().to_string(), command))
+            .await
+    }
+
+    /// Sends a message, such as one with an idempotency key, and waits for its result.
+    /// The message id must not be used by another call that is still waiting.
+    pub async fn call_message(&self, message: Message) -> Result<RpcMessage, Error> {
+        self.send(message).await.unwrap_or(Err(Error::Connection))
+    }
+
+    /// Waits for the result of a call made through a client stub generated by `rpc_service`
+    pub async fn wait<R: DeserializeOwned>(&mut self, call: &ServiceCall<R>) -> Result<R, Error> {
+        let outcome = self
+            .services
+            .remove(call.call_id())
+            .ok_or(Error::UnrecognizedMessage)?;
+        match outcome.await.unwrap_or(Err(Error::Connection))? {
+            RpcMessage::Service { payload, .. } => call.decode(&payload),
+            _ => Err(Error::UnrecognizedMessage),
+        }
+    }
+
+    /// Receives every event the backend pushes from now on
+    pub fn subscribe(&self) -> mpsc::UnboundedReceiver<Event> {
+        let (sender, events) = mpsc::unbounded_channel();
+        self.handle
+            .subscribe(Box::new(move |event| sender.send(event.clone()).is_ok()));
+        events
+    }
+
+    fn send(&self, message: Message) -> Outcome {
+        let (sender, outcome) = oneshot::channel();
+        self.handle.call(message, reply(sender));
+        outcome
+    }
+}
+
+impl ServiceClient for Client {
+    type Context = ();
+
+    fn call_service(&mut self, _context: &mut (), service: &str, payload: PayloadJson) -> Id {
+        let call_id = Uuid::new_v4().to_string();
+        let command = Command::Service {
+            service: service.to_string(),
+            call_id: call_id.to_string(),
+            payload,
+        };
+        let outcome = self.send(Message::new(call_id.to_string(), command));
+        self.services.insert(call_id.to_string(), outcome);
+        call_id
+    }
+}
+
+fn reply<T: Send + 'static>(sender: oneshot::Sender<Result<T, Error>>) -> Reply<T> {
+    Box::new(move |result| {
+        let _ = sender.send(result);
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Client;
+    use crate::{Endpoint, Options};
+    use mock_server::MockServer;
+    use rpc::{Command, Error, RpcMessage};
+
+    #[tokio::test]
+    async fn test_call() {
+        let server = MockServer::start().unwrap();
+        let client = Client::connect(Options::new(Endpoint::Websocket(server.url())))
+            .await
+            .unwrap();
+        assert_eq!(client.call(Command::Example).await, Ok(RpcMessage::Empty));
+        assert_eq!(
+            client.call(Command::Schema).await,
+            Err(Error::SchemaUnavailable)
+        );
+    }
+
+    #[tokio::test]
+    async fn test_connection_refused() {
+        // Nothing listens on a port once its listener is dropped
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let refused = listener.local_addr().unwrap().to_string();
+        drop(listener);
+
+        let result = Client::connect(Options::new(Endpoint::Tcp(refused))).await;
+        assert_eq!(result.err(), Some(Error::Connection));
+    }
+}
@@ -0,0 +1,253 @@
+use crate::Options;
+use rpc::{Command, Error, Id, Message, PayloadJson, Response, RpcClient, RpcMessage, RpcResult};
+use std::{
+    collections::HashMap,
+    sync::{
+        mpsc::{self, RecvTimeoutError},
+        Mutex, MutexGuard,
+    },
+    thread,
+    time::{Duration, Instant},
+};
+
+/// How often the connection is polled for heartbeats and timeouts while nothing wakes it up
+const POLL_INTERVAL: Duration = Duration::from_millis(50);
+
+/// Something the backend sent without being asked
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Event {
+    /// A payload another member broadcast to a room this client joined
+    RoomMessage {
+        room: String,
+        sender: Id,
+        payload: PayloadJson,
+    },
+
+    /// The connection closed or stopped answering heartbeats. No events follow it.
+    Disconnected,
+}
+
+/// Called once with the outcome. A reply that is dropped instead means the connection is gone.
+pub(crate) type Reply<T> = Box<dyn FnOnce(Result<T, Error>) + Send>;
+
+/// Called with every event until it returns false
+pub(crate) type Subscriber = Box<dyn FnMut(&Event) -> bool + Send>;
+
+enum Request {
+    Call {
+        message: Message,
+        reply: Reply<RpcMessage>,
+    },
+    Subscribe(Subscriber),
+    WakeUp,
+    Close,
+}
+
+/// Talks to the thread that owns the connection, which closes it when the handle is dropped
+pub(crate) struct Handle {
+    requests: Mutex<mpsc::Sender<Request>>,
+}
+
+impl Handle {
+    /// Connects on a new thread. `ready` is called with the client id the backend
+    /// assigned once the connection is open and authenticated.
+    pub(crate) fn connect(options: Options, ready: Reply<Id>) -> Self {
+        let (requests, receiver) = mpsc::channel();
+        let wake_up = Mutex::new(requests.clone());
+        thread::spawn(move || {
+            let wake_up = move || {
+                let _ = lock(&wake_up).send(Request::WakeUp);
+            };
+            match options.connect(wake_up) {
+                Ok(client) => Driver::new(client, receiver, &options).run(&options, ready),
+                Err(error) => {
+                    log::error!("Failed to connect to {}: {error}", options.endpoint());
+                    ready(Err(Error::Connection));
+                }
+            }
+        });
+        Self {
+            requests: Mutex::new(requests),
+        }
+    }
+
+    pub(crate) fn call(&self, message: Message, reply: Reply<RpcMessage>) {
+        self.request(Request::Call { message, reply });
+    }
+
+    pub(crate) fn subscribe(&self, subscriber: Subscriber) {
+        self.request(Request::Subscribe(subscriber));
+    }
+
+    // If the thread has stopped, the request and its reply are dropped,
+    // which the caller sees as a lost connection
+    fn request(&self, request: Request) {
+        let _ = lock(&self.requests).send(request);
+    }
+}
+
+impl Drop for Handle {
+    fn drop(&mut self) {
+        self.request(Request::Close);
+    }
+}
+
+struct Pending {
+    reply: Reply<RpcMessage>,
+    deadline: Instant,
+}
+
+struct Driver {
+    client: RpcClient,
+    requests: mpsc::Receiver<Request>,
+    request_timeout: Duration,
+    pending: HashMap<Id, Pending>,
+    subscribers: Vec<Subscriber>,
+    client_id: Option<Id>,
+}
+
+impl Driver {
+    fn new(client: RpcClient, requests: mpsc::Receiver<Request>, options: &Options) -> Self {
+        Self {
+            client,
+            requests,
+            request_timeout: options.request_timeout,
+            pending: HashMap::new(),
+            subscribers: Vec::new(),
+            client_id: None,
+        }
+    }
+
+    fn run(mut self, options: &Options, ready: Reply<Id>) {
+        let deadline = Instant::now() + options.connect_timeout;
+        match self.handshake(deadline, options.authenticates()) {
+            Ok(client_id) => ready(Ok(client_id)),
+            Err(error) => return ready(Err(error)),
+        }
+
+        loop {
+            match self.requests.recv_timeout(POLL_INTERVAL) {
+                Ok(Request::Close) | Err(RecvTimeoutError::Disconnected) => return,
+                Ok(request) => self.handle(request),
+                Err(RecvTimeoutError::Timeout) => {}
+            }
+            self.poll();
+            self.expire(Instant::now());
+
+            if !self.client.is_open() || !self.client.is_alive() {
+                log::warn!("The connection to {} was lost", options.endpoint());
+                break;
+            }
+        }
+
+        // Dropping the replies tells every waiting call that the connection is gone
+        self.pending.clear();
+        self.publish(&Event::Disconnected);
+    }
+
+    // Waits for the backend to assign a client id, and to accept the credential if one was given
+    fn handshake(&mut self, deadline: Instant, authenticates: bool) -> Result<Id, Error> {
+        loop {
+            self.poll();
+            if self.client.is_open() {
+                match (&self.client_id, self.client.authentication()) {
+                    (_, Some(Err(error))) => return Err(error.clone()),
+                    (Some(client_id), Some(Ok(_))) => return Ok(client_id.to_string()),
+                    (Some(client_id), None) if !authenticates => return Ok(client_id.to_string()),
+                    _ => {}
+                }
+            }
+
+            let now = Instant::now();
+            if now >= deadline {
+                return Err(Error::Timeout);
+            }
+            match self
+                .requests
+                .recv_timeout(POLL_INTERVAL.min(deadline - now))
+            {
+                Ok(Request::Close) | Err(RecvTimeoutError::Disconnected) => {
+                    return Err(Error::Connection)
+                }
+                Ok(request) => self.handle(request),
+                Err(RecvTimeoutError::Timeout) => {}
+            }
+        }
+    }
+
+    fn handle(&mut self, request: Request) {
+        match request {
+            Request::Call { message, reply } => {
+                let pending = Pending {
+                    reply,
+                    deadline: Instant::now() + self.request_timeout,
+                };
+                self.pending.insert(message.id.to_string(), pending);
+                self.client.send_message(message);
+            }
+            Request::Subscribe(subscriber) => self.subscribers.push(subscriber),
+            Request::WakeUp | Request::Close => {}
+        }
+    }
+
+    fn poll(&mut self) {
+        while let Some(response) = self.client.receive() {
+            self.receive(response);
+        }
+    }
+
+    fn receive(&mut self, Response { id, result }: Response) {
+        match result {
+            RpcResult::Success(RpcMessage::ClientId { id }) => {
+                log::info!("Assigned client id '{id}' by the backend");
+                self.client_id = Some(id);
+            }
+
+            // Broadcasts answer no call, so they go to the subscribers
+            RpcResult::Success(RpcMessage::RoomMessage {
+                room,
+                sender,
+                payload,
+            }) => self.publish(&Event::RoomMessage {
+                room,
+                sender,
+                payload,
+            }),
+
+            result => match self.pending.remove(&id) {
+                Some(Pending { reply, .. }) => reply(match result {
+                    RpcResult::Success(message) => Ok(message),
+                    RpcResult::Error(error) => Err(error),
+                }),
+                None => log::debug!("Dropping a response to '{id}', which nothing is waiting for"),
+            },
+        }
+    }
+
+    // Expired calls are cancelled, so the backend stops working on results nobody will read
+    fn expire(&mut self, now: Instant) {
+        let expired = self
+            .pending
+            .iter()
+            .filter(|(_, pending)| pending.deadline <= now)
+            .map(|(id, _)| id.to_string())
+            .collect::<Vec<_>>();
+        for id in expired {
+            if let Some(Pending { reply, .. }) = self.pending.remove(&id) {
+                reply(Err(Error::Timeout));
+                self.client
+                    .send(id.to_string(), Command::Cancel { id: id.to_string() });
+            }
+        }
+    }
+
+    fn publish(&mut self, event: &Event) {
+        self.subscribers.retain_mut(|subscriber| subscriber(event));
+    }
+}
+
+fn lock<T>(mutex: &Mutex<T>) -> MutexGuard<'_, T> {
+    mutex
+        .lock()
+        .unwrap_or_else(|poisoned| poisoned.into_inner())
+}
@@ -0,0 +1,28 @@
+//! A headless client for the backend's `server` subcommand, for scripts and batch jobs
+//! that drive the same backend the editor uses.
+//!
+//! The connection is owned by a background thread, which sends heartbeats, matches
+//! responses to the calls that are waiting for them, and forwards pushed events.
+//! `blocking::Client` waits on that thread directly, and `Client` (with the `tokio` feature)
+//! awaits it without blocking the runtime.
+//!
+//! ```no_run
+//! use sdk::{blocking::Client, Endpoint, Options};
+//!
+//! let client = Client::connect(Options::new(Endpoint::Tcp("127.0.0.1:9001".to_string())))?;
+//! let schema = client.call(rpc::Command::Schema)?;
+//! # Ok::<(), rpc::Error>(())
+//! ```
+
+pub mod blocking;
+
+mod driver;
+mod options;
+
+#[cfg(feature = "tokio")]
+mod client;
+
+pub use self::{driver::Event, options::*};
+
+#[cfg(feature = "tokio")]
+pub use self::client::*;
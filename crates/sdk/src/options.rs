@@ -0,0 +1,123 @@
+use rpc::{
+    Compression, Credential, RpcClient, StreamTransport, WebsocketTransport,
+    DEFAULT_COMPRESSION_THRESHOLD, DEFAULT_HEARTBEAT_INTERVAL, DEFAULT_LIVENESS_TIMEOUT,
+};
+use std::{fmt, time::Duration};
+
+#[cfg(unix)]
+use std::path::PathBuf;
+
+/// How long connecting may take, including authentication, before it is given up on
+pub const DEFAULT_CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// How long a call waits for its response before it fails with `Error::Timeout`
+pub const DEFAULT_REQUEST_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Where the backend listens
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Endpoint {
+    /// A `ws://` or `wss://` url, the server's `--port`
+    Websocket(String),
+
+    /// A `host:port` address, the server's `--tcp-port`
+    Tcp(String),
+
+    /// A socket path, the server's `--unix-socket`
+    #[cfg(unix)]
+    Unix(PathBuf),
+}
+
+impl fmt::Display for Endpoint {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Websocket(url) => write!(f, "{url}"),
+            Self::Tcp(address) => write!(f, "tcp://{address}"),
+            #[cfg(unix)]
+            Self::Unix(path) => write!(f, "unix://{}", path.display()),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Options {
+    endpoint: Endpoint,
+    credential: Credential,
+    compression: Compression,
+    heartbeat_interval: Duration,
+    liveness_timeout: Duration,
+    pub(crate) connect_timeout: Duration,
+    pub(crate) request_timeout: Duration,
+}
+
+impl Options {
+    pub fn new(endpoint: Endpoint) -> Self {
+        Self {
+            endpoint,
+            credential: Credential::None,
+            compression: Compression::None,
+            heartbeat_interval: DEFAULT_HEARTBEAT_INTERVAL,
+            liveness_timeout: DEFAULT_LIVENESS_TIMEOUT,
+            connect_timeout: DEFAULT_CONNECT_TIMEOUT,
+            request_timeout: DEFAULT_REQUEST_TIMEOUT,
+        }
+    }
+
+    /// Presented when the connection opens, for servers that require authentication
+    pub fn with_credential(mut self, credential: Credential) -> Self {
+        self.credential = credential;
+        self
+    }
+
+    /// Compresses large frames, if the server agrees to it
+    pub fn with_compression(mut self, compression: Compression) -> Self {
+        self.compression = compression;
+        self
+    }
+
+    /// Sends a heartbeat every `interval`, and disconnects
+    /// when nothing has been received from the server for `timeout`
+    pub fn with_heartbeat(mut self, interval: Duration, timeout: Duration) -> Self {
+        self.heartbeat_interval = interval;
+        self.liveness_timeout = timeout;
+        self
+    }
+
+    pub fn with_connect_timeout(mut self, timeout: Duration) -> Self {
+        self.connect_timeout = timeout;
+        self
+    }
+
+    pub fn with_request_timeout(mut self, timeout: Duration) -> Self {
+        self.request_timeout = timeout;
+        self
+    }
+
+    pub fn endpoint(&self) -> &Endpoint {
+        &self.endpoint
+    }
+
+    pub(crate) fn authenticates(&self) -> bool {
+        self.credential != Credential::None
+    }
+
+    pub(crate) fn connect(
+        &self,
+        wake_up: impl Fn() + Send + Sync + 'static,
+    ) -> Result<RpcClient, String> {
+        let client = match &self.endpoint {
+            Endpoint::Websocket(url) => RpcClient::new(WebsocketTransport::connect(url, wake_up)?),
+            Endpoint::Tcp(address) => RpcClient::new(
+                StreamTransport::connect_tcp(address.as_str(), wake_up)
+                    .map_err(|error| error.to_string())?,
+            ),
+            #[cfg(unix)]
+            Endpoint::Unix(path) => RpcClient::new(
+                StreamTransport::connect_unix(path, wake_up).map_err(|error| error.to_string())?,
+            ),
+        };
+        Ok(client
+            .with_compression(self.compression, DEFAULT_COMPRESSION_THRESHOLD)
+            .with_credential(self.credential.clone())
+            .with_heartbeat(self.heartbeat_interval, self.liveness_timeout))
+    }
+}
@@ -1,7 +1,7 @@
 use crate::filesystem::{FileSystemCommand, FileSystemId, FileSystemResult};
 use enum2contract::EnumContract;
 use enum2str::EnumStr;
-use rpc::{Command, Id as RpcId, RpcResult};
+use rpc::{Command, Id as RpcId, PayloadJson, RpcResult, TransferProgress};
 use serde::{Deserialize, Serialize};
 
 pub type ClientHandle = crate::broker::ClientHandle<Message>;
@@ -11,6 +11,7 @@ pub type Broker = crate::broker::Broker<Message>;
 use enum2egui::{egui, Gui, GuiInspect};
 
 #[derive(Default, Debug, EnumContract, Clone, EnumStr, PartialEq, Eq, Serialize, Deserialize)]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
 #[cfg_attr(feature = "gui", derive(Gui))]
 pub enum Message {
     #[default]
@@ -18,11 +19,31 @@ pub enum Message {
     Empty,
 
     #[topic("rpc/command")]
-    RpcCommand { id: RpcId, command: Command },
+    RpcCommand {
+        id: RpcId,
+        command: Command,
+        idempotency_key: Option<RpcId>,
+    },
 
     #[topic("rpc/{id}/result")]
     RpcResult { result: RpcResult },
 
+    #[topic("rpc/{id}/progress")]
+    RpcTransferProgress { progress: TransferProgress },
+
+    #[topic("rpc/session")]
+    RpcSession { client_id: RpcId },
+
+    #[topic("rpc/session/request")]
+    RpcSessionRequest,
+
+    #[topic("rpc/room/{room}")]
+    RpcRoomMessage {
+        room: String,
+        sender: RpcId,
+        payload: PayloadJson,
+    },
+
     #[topic("file/command")]
     FileSystemCommand {
         id: FileSystemId,
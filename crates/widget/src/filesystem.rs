@@ -5,6 +5,7 @@ use serde::{Deserialize, Serialize};
 use enum2egui::{egui, Gui, GuiInspect};
 
 #[derive(Debug, Default, Serialize, Deserialize, EnumStr, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
 pub enum FileSystemCommand {
     #[default]
     None,
@@ -32,6 +33,7 @@ impl GuiInspect for FileSystemCommand {
 }
 
 #[derive(Debug, Serialize, Deserialize, EnumStr, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
 #[cfg_attr(feature = "gui", derive(Gui))]
 pub enum FileSystemResult {
     Success(FileSystemMessage),
@@ -45,6 +47,7 @@ impl Default for FileSystemResult {
 }
 
 #[derive(Debug, Default, Serialize, Deserialize, EnumStr, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
 pub enum FileSystemMessage {
     #[default]
     Empty,
@@ -69,6 +72,7 @@ impl GuiInspect for FileSystemMessage {
 }
 
 #[derive(Default, Debug, Serialize, Deserialize, EnumStr, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
 #[cfg_attr(feature = "gui", derive(Gui))]
 pub enum FilesystemError {
     #[default]
@@ -1,10 +1,14 @@
+use std::collections::HashMap;
 use uuid::Uuid;
 use widget::{
     broker::{self, Client},
     filesystem::{FileSystemCommand, FileSystemMessage, FileSystemResult},
     log,
-    rpc::{Command, Id, RpcMessage, RpcResult},
-    serde::{Deserialize, Serialize},
+    rpc::{
+        Command, Error, Id, PayloadJson, RpcMessage, RpcResult, ServiceCall, ServiceClient,
+        TransferProgress,
+    },
+    serde::{de::DeserializeOwned, Deserialize, Serialize},
     ClientHandle, Message,
 };
 
@@ -16,6 +20,10 @@ pub struct WidgetClient {
     subscribed: bool,
     #[serde(skip)]
     handle: ClientHandle,
+    #[serde(skip)]
+    transfers: HashMap<Id, TransferProgress>,
+    #[serde(skip)]
+    service_results: HashMap<Id, Result<PayloadJson, Error>>,
 }
 
 impl Default for WidgetClient {
@@ -25,6 +33,8 @@ impl Default for WidgetClient {
             frontend_id: Uuid::new_v4().to_string(),
             client_id: None,
             subscribed: false,
+            transfers: HashMap::new(),
+            service_results: HashMap::new(),
         }
     }
 }
@@ -35,21 +45,86 @@ impl WidgetClient {
             self.create_subscriptions(broker);
         }
 
-        if let Some(Message::RpcResult {
-            result: RpcResult::Success(RpcMessage::ClientId { id: client_id }),
-        }) = self.peek_message()
-        {
-            self.client_id = Some(client_id);
+        match self.peek_message() {
+            Some(Message::RpcResult {
+                result: RpcResult::Success(RpcMessage::ClientId { id: client_id }),
+            }) => {
+                self.client_id = Some(client_id);
+            }
+            Some(Message::RpcSession { client_id }) => {
+                self.client_id = Some(client_id);
+                self.next_message(); // Dequeue the message we peeked
+            }
+            Some(Message::RpcTransferProgress { progress }) => {
+                self.update_transfer(progress);
+                self.next_message(); // Dequeue the message we peeked
+            }
+            Some(Message::RpcResult {
+                result:
+                    RpcResult::Success(RpcMessage::Service {
+                        call_id, payload, ..
+                    }),
+            }) => {
+                self.service_results.insert(call_id, Ok(payload));
+                self.next_message(); // Dequeue the message we peeked
+            }
+            Some(Message::RpcResult {
+                result: RpcResult::Error(error),
+            }) => {
+                // Other errors are left for the widget to handle
+                if let Error::Service { call_id, .. } | Error::UnknownService { call_id, .. } =
+                    &error
+                {
+                    self.service_results
+                        .insert(call_id.to_string(), Err(error.clone()));
+                    self.next_message(); // Dequeue the message we peeked
+                }
+            }
+            _ => {}
+        }
+    }
+
+    fn update_transfer(&mut self, progress: TransferProgress) {
+        if progress.is_complete() {
+            self.transfers.remove(&progress.transfer_id);
+        } else {
+            self.transfers
+                .insert(progress.transfer_id.to_string(), progress);
         }
     }
 
+    /// Takes the result of a call made through a generated service stub, once it has arrived
+    pub fn service_response<R: DeserializeOwned>(
+        &mut self,
+        call: &ServiceCall<R>,
+    ) -> Option<Result<R, Error>> {
+        let result = self.service_results.remove(call.call_id())?;
+        Some(result.and_then(|payload| call.decode(&payload)))
+    }
+
+    /// The chunked uploads and downloads this client has in progress
+    pub fn transfers(&self) -> impl Iterator<Item = &TransferProgress> {
+        self.transfers.values()
+    }
+
     fn create_subscriptions(&mut self, broker: &mut broker::Broker<Message>) {
         self.subscribe_to_topic(&Message::rpc_result_topic(&self.frontend_id), broker);
         self.subscribe_to_topic(
             &Message::file_system_result_topic(&self.frontend_id),
             broker,
         );
+        self.subscribe_to_topic(
+            &Message::rpc_transfer_progress_topic(&self.frontend_id),
+            broker,
+        );
+        self.subscribe_to_topic(&Message::rpc_session_topic(), broker);
         self.subscribed = true;
+
+        // Ask the rpc service for the client id in case it was announced before we subscribed
+        broker.publish(
+            &Message::rpc_session_request_topic(),
+            Message::RpcSessionRequest,
+        );
     }
 
     fn subscribe_to_topic(&mut self, topic: &str, broker: &mut broker::Broker<Message>) {
@@ -138,14 +213,80 @@ impl WidgetClient {
     }
 
     pub fn publish_rpc_command(&self, broker: &mut broker::Broker<Message>, command: Command) {
+        self.publish_command(broker, command, None);
+    }
+
+    /// Publishes a command the backend runs at most once, however often it is
+    /// published with the same key, answering repeats with the first result
+    pub fn publish_idempotent_rpc_command(
+        &self,
+        broker: &mut broker::Broker<Message>,
+        command: Command,
+        idempotency_key: &str,
+    ) {
+        self.publish_command(broker, command, Some(idempotency_key.to_string()));
+    }
+
+    fn publish_command(
+        &self,
+        broker: &mut broker::Broker<Message>,
+        command: Command,
+        idempotency_key: Option<Id>,
+    ) {
         log::info!("Publishing command: {command:#?}");
         let message = Message::RpcCommand {
             id: self.frontend_id.to_string(),
             command,
+            idempotency_key,
         };
         broker.publish(&Message::rpc_command_topic(), message);
     }
 
+    /// Asks the backend to stop every rpc command this client has in flight.
+    /// Each cancelled command still produces a result, carrying `Error::Cancelled`.
+    pub fn cancel_rpc_commands(&self, broker: &mut broker::Broker<Message>) {
+        log::info!("Cancelling rpc commands for {}", self.frontend_id);
+        let command = Command::Cancel {
+            id: self.frontend_id.to_string(),
+        };
+        self.publish_command(broker, command, None);
+    }
+
+    /// Joins a room on the backend and starts receiving what is broadcast to it
+    /// as `Message::RpcRoomMessage`
+    pub fn join_room(&mut self, broker: &mut broker::Broker<Message>, room: &str) {
+        self.subscribe_to_topic(&Message::rpc_room_message_topic(room), broker);
+        let command = Command::JoinRoom {
+            room: room.to_string(),
+        };
+        self.publish_command(broker, command, None);
+    }
+
+    pub fn leave_room(&mut self, broker: &mut broker::Broker<Message>, room: &str) {
+        let topic = Message::rpc_room_message_topic(room);
+        if let Err(error) = broker.unsubscribe(&topic, self.handle.borrow().id()) {
+            log::warn!("Failed to unsubscribe from {topic}: {error}");
+        }
+        let command = Command::LeaveRoom {
+            room: room.to_string(),
+        };
+        self.publish_command(broker, command, None);
+    }
+
+    /// Sends a payload to the other members of a room this client has joined
+    pub fn broadcast(
+        &self,
+        broker: &mut broker::Broker<Message>,
+        room: &str,
+        payload: PayloadJson,
+    ) {
+        let command = Command::Broadcast {
+            room: room.to_string(),
+            payload,
+        };
+        self.publish_command(broker, command, None);
+    }
+
     pub fn publish_file_command(
         &self,
         broker: &mut broker::Broker<Message>,
@@ -158,3 +299,25 @@ impl WidgetClient {
         broker.publish(&Message::file_system_command_topic(), message);
     }
 }
+
+impl ServiceClient for WidgetClient {
+    type Context = broker::Broker<Message>;
+
+    fn call_service(
+        &mut self,
+        broker: &mut broker::Broker<Message>,
+        service: &str,
+        payload: PayloadJson,
+    ) -> Id {
+        let call_id = Uuid::new_v4().to_string();
+        let command = Command::Service {
+            service: service.to_string(),
+            call_id: call_id.to_string(),
+            payload,
+        };
+
+        // Each call has its own id, so it doubles as the key for retries
+        self.publish_idempotent_rpc_command(broker, command, &call_id);
+        call_id
+    }
+}
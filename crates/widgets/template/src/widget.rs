@@ -33,10 +33,20 @@ impl Template {
     fn widget_ui(&mut self, ui: &mut egui::Ui, broker: &mut Broker) {
         let client = self.connection.client_mut();
 
-        if ui.button("Publish RPC command").clicked() {
-            client.publish_rpc_command(broker, widget::rpc::Command::Example);
-            client.notify(broker, "Published RPC command!");
-        }
+        ui.horizontal(|ui| {
+            if ui.button("Publish RPC command").clicked() {
+                client.publish_rpc_command(broker, widget::rpc::Command::Example);
+                client.notify(broker, "Published RPC command!");
+            }
+
+            if ui.button("Stop").clicked() {
+                client.cancel_rpc_commands(broker);
+            }
+        });
+
+        client.transfers().for_each(|progress| {
+            ui.add(egui::ProgressBar::new(progress.fraction()).show_percentage());
+        });
     }
 
     fn receive_messages(&mut self) {